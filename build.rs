@@ -2,6 +2,9 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     tonic_build::configure()
         .build_client(false)
         .build_server(true)
-        .compile(&["proto/dbrunner.proto"], &["proto"])?;
+        .compile(
+            &["proto/dbrunner.proto", "proto/dbrunner/v2/dbrunner.proto"],
+            &["proto"],
+        )?;
     Ok(())
 }
@@ -33,30 +33,28 @@ impl<'a, C: AsyncCommands> RedisCacher<'a, C> {
             kind = Kind::Input,
             uid = query_uid
         );
-        let Some(output_uid): Option<String> = self
-            .conn
-            .get_ex(&input_key, redis::Expiry::EX(EXPIRE_SECONDS))
-            .await?
-        else {
-            return Ok(Miss);
-        };
-
-        let output_key = format!(
+        let response_key = format!(
             "{key}:{kind}:{uid}",
             key = DBRUNNER_CACHER_KEY,
-            kind = Kind::Output,
-            uid = output_uid,
+            kind = Kind::Response,
+            uid = query_uid,
         );
-        let Some(output): Option<String> = self
-            .conn
-            .get_ex(&output_key, redis::Expiry::EX(EXPIRE_SECONDS))
-            .await?
-        else {
+
+        // Both are refreshed, so the output UID expires with the response.
+        let (output_uid, response) = redis::pipe()
+            .get_ex(&input_key, redis::Expiry::EX(EXPIRE_SECONDS))
+            .get_ex(&response_key, redis::Expiry::EX(EXPIRE_SECONDS))
+            .query_async::<(Option<String>, Option<String>)>(self.conn)
+            .await?;
+        let (Some(_), Some(response)) = (output_uid, response) else {
             return Ok(Miss);
         };
 
-        let output = serde_json::from_str(&output).unwrap();
-        Ok(Hit(output))
+        // The response may be stored by an older version in another format.
+        let Ok(response) = serde_json::from_str(&response) else {
+            return Ok(Miss);
+        };
+        Ok(Hit(response))
     }
 
     /// Check if the two query_uid has the same output UID.
@@ -88,6 +86,10 @@ impl<'a, C: AsyncCommands> RedisCacher<'a, C> {
     }
 
     /// Store the data in the cache.
+    ///
+    /// The data is stored under the query UID, as the queries having the
+    /// same output UID may differ in what is not compared, such as the
+    /// origin of the columns.
     pub async fn set(
         &mut self,
         query_uid: &str,
@@ -99,16 +101,16 @@ impl<'a, C: AsyncCommands> RedisCacher<'a, C> {
             kind = Kind::Input,
             uid = query_uid,
         );
-        let output_key = format!(
+        let response_key = format!(
             "{key}:{kind}:{uid}",
             key = DBRUNNER_CACHER_KEY,
-            kind = Kind::Output,
-            uid = output.get_uid(),
+            kind = Kind::Response,
+            uid = query_uid,
         );
         let output_json = serde_json::to_string(&output).unwrap();
 
         redis::pipe()
-            .set_ex(output_key, output_json, EXPIRE_SECONDS)
+            .set_ex(response_key, output_json, EXPIRE_SECONDS)
             .ignore()
             .set_ex(
                 input_key,
@@ -121,18 +123,72 @@ impl<'a, C: AsyncCommands> RedisCacher<'a, C> {
 
         Ok(())
     }
+
+    /// Get the data stored under the query UID in the given kind.
+    pub async fn get_entry<T: DeserializeOwned>(
+        &mut self,
+        kind: Kind,
+        query_uid: &str,
+    ) -> Result<CacheState<T>, Error> {
+        use CacheState::*;
+
+        let key = format!(
+            "{key}:{kind}:{uid}",
+            key = DBRUNNER_CACHER_KEY,
+            kind = kind,
+            uid = query_uid,
+        );
+        let Some(value): Option<String> = self
+            .conn
+            .get_ex(&key, redis::Expiry::EX(EXPIRE_SECONDS))
+            .await?
+        else {
+            return Ok(Miss);
+        };
+
+        let Ok(value) = serde_json::from_str(&value) else {
+            return Ok(Miss);
+        };
+        Ok(Hit(value))
+    }
+
+    /// Store the data under the query UID in the given kind.
+    pub async fn set_entry(
+        &mut self,
+        kind: Kind,
+        query_uid: &str,
+        value: impl Serialize,
+    ) -> Result<(), Error> {
+        let key = format!(
+            "{key}:{kind}:{uid}",
+            key = DBRUNNER_CACHER_KEY,
+            kind = kind,
+            uid = query_uid,
+        );
+        let value_json = serde_json::to_string(&value).unwrap();
+
+        self.conn
+            .set_ex::<_, _, ()>(key, value_json, EXPIRE_SECONDS)
+            .await?;
+
+        Ok(())
+    }
 }
 
 pub enum Kind {
     Input,
-    Output,
+    Response,
+    Plan,
+    Statistics,
 }
 
 impl Display for Kind {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Kind::Input => write!(f, "input"),
-            Kind::Output => write!(f, "output"),
+            Kind::Response => write!(f, "response"),
+            Kind::Plan => write!(f, "plan"),
+            Kind::Statistics => write!(f, "statistics"),
         }
     }
 }
@@ -153,9 +209,11 @@ pub enum Error {
 
 #[cfg(all(test, feature = "test_redis"))]
 mod tests {
-    use crate::sql::{Query, QueryResponse, UidGetter};
+    use crate::sql::{
+        Cell, ColumnMetadata, PlanNode, Query, QueryPlan, QueryResponse, ResultSet, UidGetter,
+    };
 
-    use super::RedisCacher;
+    use super::{Kind, RedisCacher};
 
     #[tokio::test]
     async fn test_cache() {
@@ -165,12 +223,20 @@ mod tests {
         let input = Query {
             initial_sql: "CREATE TABLE test (id INT); INSERT INTO test VALUES (1);".to_string(),
             query: "SELECT * FROM test".to_string(),
+            ..Default::default()
         }
         .format()
         .expect("formatting query");
         let output = QueryResponse {
-            header: vec![("id".to_string())],
-            rows: vec![vec![Some("1".to_string())]],
+            result_sets: vec![ResultSet {
+                header: vec![("id".to_string())],
+                rows: vec![vec![Cell::Integer(1)]],
+                truncated: false,
+                total_rows: 1,
+                affected_rows: 0,
+                ..Default::default()
+            }],
+            ..Default::default()
         };
         let output_c = output.clone();
         let input_uid = input.get_uid();
@@ -195,6 +261,7 @@ mod tests {
         let input = Query {
             initial_sql: "CREATE TABLE test (id INT); INSERT INTO test VALUES (1);".to_string(),
             query: "SELECT * FROM test".to_string(),
+            ..Default::default()
         }
         .format()
         .expect("formatting query");
@@ -214,26 +281,43 @@ mod tests {
         let input_a1 = Query {
             initial_sql: "CREATE TABLE test (id INT); INSERT INTO test VALUES (1);".to_string(),
             query: "SELECT * FROM test".to_string(),
+            ..Default::default()
         };
 
         let input_a2 = Query {
             initial_sql: "CREATE TABLE test (id INT); INSERT INTO test VALUES (1);".to_string(),
             query: "select * from test".to_string(),
+            ..Default::default()
         };
 
         let input_b = Query {
             initial_sql: "CREATE TABLE test (id INT); INSERT INTO test VALUES (1);".to_string(),
             query: "SELECT * FROM test WHERE id = 114514".to_string(),
+            ..Default::default()
         };
 
         let output_a = QueryResponse {
-            header: vec![("id".to_string())],
-            rows: vec![vec![Some("1".to_string())]],
+            result_sets: vec![ResultSet {
+                header: vec![("id".to_string())],
+                rows: vec![vec![Cell::Integer(1)]],
+                truncated: false,
+                total_rows: 1,
+                affected_rows: 0,
+                ..Default::default()
+            }],
+            ..Default::default()
         };
 
         let output_b = QueryResponse {
-            header: Default::default(),
-            rows: Default::default(),
+            result_sets: vec![ResultSet {
+                header: Default::default(),
+                rows: Default::default(),
+                truncated: false,
+                total_rows: 0,
+                affected_rows: 0,
+                ..Default::default()
+            }],
+            ..Default::default()
         };
 
         // register to cache
@@ -288,6 +372,116 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    async fn test_same_output_stored_apart() {
+        let mut conn = create_connection(4).await;
+        let mut cacher = RedisCacher::new(&mut conn);
+
+        let from_table = |table: &str| {
+            let input = Query {
+                initial_sql: format!(
+                    "CREATE TABLE {table} (id INT); INSERT INTO {table} VALUES (1);"
+                ),
+                query: format!("SELECT * FROM {table}"),
+                ..Default::default()
+            };
+            let output = QueryResponse {
+                result_sets: vec![ResultSet {
+                    header: vec![("id".to_string())],
+                    columns: vec![ColumnMetadata {
+                        decl_type: Some("INT".to_string()),
+                        origin_table: Some(table.to_string()),
+                        origin_column: Some("id".to_string()),
+                        is_expression: false,
+                    }],
+                    rows: vec![vec![Cell::Integer(1)]],
+                    total_rows: 1,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            };
+            (input.get_uid().to_hex(), output)
+        };
+        let (uid_a, output_a) = from_table("a");
+        let (uid_b, output_b) = from_table("b");
+
+        cacher
+            .set(uid_a.as_str(), output_a.clone())
+            .await
+            .expect("setting cache");
+        cacher
+            .set(uid_b.as_str(), output_b.clone())
+            .await
+            .expect("setting cache");
+
+        let result = cacher
+            .same_output_uid(uid_a.as_str(), uid_b.as_str())
+            .await
+            .expect("checking same output UID");
+        assert!(result, "the outputs should be the same");
+
+        let result = cacher
+            .get::<QueryResponse>(uid_a.as_str())
+            .await
+            .expect("getting cache");
+        assert!(
+            matches!(result, super::CacheState::Hit(v) if v == output_a),
+            "each query should keep its own columns"
+        );
+        let result = cacher
+            .get::<QueryResponse>(uid_b.as_str())
+            .await
+            .expect("getting cache");
+        assert!(matches!(result, super::CacheState::Hit(v) if v == output_b));
+    }
+
+    #[tokio::test]
+    async fn test_cache_entry() {
+        let mut conn = create_connection(3).await;
+        let mut cacher = RedisCacher::new(&mut conn);
+
+        let input_uid = Query {
+            initial_sql: "CREATE TABLE test (id INT);".to_string(),
+            query: "SELECT * FROM test".to_string(),
+            ..Default::default()
+        }
+        .get_uid();
+        let plan = QueryPlan {
+            statements: vec![vec![PlanNode {
+                id: 2,
+                parent: 0,
+                detail: "SCAN test".to_string(),
+                children: vec![],
+            }]],
+        };
+
+        let result = cacher
+            .get_entry::<QueryPlan>(Kind::Plan, input_uid.to_hex().as_str())
+            .await
+            .expect("getting cache");
+        assert!(matches!(result, super::CacheState::Miss));
+
+        cacher
+            .set_entry(Kind::Plan, input_uid.to_hex().as_str(), plan.clone())
+            .await
+            .expect("setting cache");
+
+        let result = cacher
+            .get_entry::<QueryPlan>(Kind::Plan, input_uid.to_hex().as_str())
+            .await
+            .expect("getting cache");
+        assert!(matches!(result, super::CacheState::Hit(v) if v == plan));
+
+        let result = cacher
+            .get::<QueryResponse>(input_uid.to_hex().as_str())
+            .await
+            .expect("getting cache");
+        assert!(
+            matches!(result, super::CacheState::Miss),
+            "the plan should not be taken as the output"
+        );
+    }
+
     async fn create_connection(test_id: u16) -> redis::aio::MultiplexedConnection {
         let integration_uri =
             std::env::var("REDIS_INTEGRATION_URI").expect("REDIS_INTEGRATION_URI is not set");
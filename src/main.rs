@@ -1,6 +1,4 @@
-#![feature(assert_matches)]
-
-use std::net::SocketAddr;
+use std::{net::SocketAddr, str::FromStr, sync::Arc, time::Duration};
 
 use mimalloc_rust::GlobalMiMalloc;
 use tonic::transport::Server;
@@ -23,12 +21,85 @@ async fn main() {
     let redis_addr = std::env::var("REDIS_ADDR").expect("REDIS_ADDR must be set");
     let redis_client = redis::Client::open(redis_addr).expect("Failed to open Redis client");
 
-    let dbrunner_service = rpc::DbRunner::new(redis_client);
+    let default_executor_config = sql::executor::Config::default();
+    let executor_config = sql::executor::Config {
+        timeout: Duration::from_millis(env_or(
+            "QUERY_TIMEOUT_MS",
+            default_executor_config.timeout.as_millis() as u64,
+        )),
+        max_instructions: env_or(
+            "QUERY_MAX_INSTRUCTIONS",
+            default_executor_config.max_instructions,
+        ),
+        max_database_bytes: env_or(
+            "QUERY_MAX_DATABASE_BYTES",
+            default_executor_config.max_database_bytes,
+        ),
+        max_temp_bytes: env_or(
+            "QUERY_MAX_TEMP_BYTES",
+            default_executor_config.max_temp_bytes,
+        ),
+        schema_policy: env_or("SCHEMA_POLICY", default_executor_config.schema_policy),
+        query_policy: env_or("QUERY_POLICY", default_executor_config.query_policy),
+        max_rows: env_or("QUERY_MAX_ROWS", default_executor_config.max_rows),
+        max_bytes: env_or("QUERY_MAX_BYTES", default_executor_config.max_bytes),
+        sqlite_profiles: env_or("SQLITE_PROFILES", default_executor_config.sqlite_profiles),
+        workers: env_or("QUERY_WORKERS", default_executor_config.workers),
+        queue_size: env_or("QUERY_QUEUE_SIZE", default_executor_config.queue_size),
+        snapshot_cache_bytes: env_or(
+            "SNAPSHOT_CACHE_BYTES",
+            default_executor_config.snapshot_cache_bytes,
+        ),
+    };
+    sql::executor::set_heap_limit(env_or(
+        "SQLITE_HEAP_LIMIT_BYTES",
+        executor_config.heap_limit(),
+    ));
+
+    let dbrunner_service = Arc::new(rpc::DbRunner::new(
+        redis_client,
+        sql::Executor::new(executor_config),
+    ));
     println!("Server listening on {}", addr);
 
+    let stats_interval = Duration::from_secs(env_or("STATS_LOG_INTERVAL_SECS", 60));
+    if !stats_interval.is_zero() {
+        tokio::spawn(log_stats(dbrunner_service.clone(), stats_interval));
+    }
+
     Server::builder()
-        .add_service(rpc::DbRunnerServiceServer::new(dbrunner_service))
+        .add_service(rpc::v1::DbRunnerServiceServer::from_arc(
+            dbrunner_service.clone(),
+        ))
+        .add_service(rpc::v2::DbRunnerServiceServer::from_arc(dbrunner_service))
         .serve(addr)
         .await
         .expect("Failed to serve");
 }
+
+/// Print the counters of the executor every `interval`.
+async fn log_stats(service: Arc<rpc::DbRunner>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    // The first tick completes immediately.
+    ticker.tick().await;
+
+    loop {
+        ticker.tick().await;
+        let executor = service.executor();
+        println!(
+            "Worker pool: {}; schema snapshots: {}",
+            executor.pool_stats(),
+            executor.snapshot_stats()
+        );
+    }
+}
+
+/// Read and parse the environment variable, or return `default` if it is unset.
+fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    match std::env::var(key) {
+        Ok(value) => value
+            .parse()
+            .unwrap_or_else(|_| panic!("{key} must be a valid value")),
+        Err(_) => default,
+    }
+}
@@ -1,31 +1,57 @@
-use std::{pin::Pin, result::Result};
+use std::{
+    result::Result,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-pub use dbrunner::db_runner_service_server::{DbRunnerService, DbRunnerServiceServer};
-use dbrunner::{
-    retrieve_query_response::Kind, run_query_response::ResponseType, AreQueriesOutputSameRequest,
-    AreQueriesOutputSameResponse, Cell, DataRow, HeaderRow, RetrieveQueryRequest,
-    RetrieveQueryResponse, RunQueryResponse,
+use tonic::{
+    metadata::{MetadataMap, MetadataValue},
+    Code, Status,
 };
-use tokio_stream::{iter as stream_iter, Stream};
-use tonic::{Request, Response, Status};
 
 use crate::{
     cache,
-    sql::{self, Query, QueryResponse, UidGetter},
+    sql::{self, Query, QueryPlan, QueryResponse, UidGetter},
 };
 
-pub mod dbrunner {
-    tonic::include_proto!("dbrunner.v1");
+pub mod v1;
+pub mod v2;
+
+/// The query run, or whose response was cached.
+#[derive(Debug)]
+struct RanQuery {
+    /// The ID to retrieve the response.
+    id: String,
+
+    /// The non-deterministic inputs the query read.
+    nondeterminism: sql::Nondeterminism,
 }
 
+/// The DbRunner service, served by every version of the API.
 #[derive(Debug)]
 pub struct DbRunner {
     redis_client: redis::Client,
+    executor: sql::Executor,
 }
 
 impl DbRunner {
-    pub fn new(redis_client: redis::Client) -> Self {
-        Self { redis_client }
+    pub fn new(redis_client: redis::Client, executor: sql::Executor) -> Self {
+        Self {
+            redis_client,
+            executor,
+        }
+    }
+
+    /// The executor running the queries.
+    pub fn executor(&self) -> &sql::Executor {
+        &self.executor
+    }
+
+    /// Resolve the query for the executor, and return it with its UID.
+    fn resolve(&self, query: Query) -> Result<(sql::executor::ResolvedQuery, String), sql::Error> {
+        let resolved = self.executor.resolve(query)?;
+        let query_uid = resolved.get_uid().to_hex().to_string();
+
+        Ok((resolved, query_uid))
     }
 
     async fn redis_conn(&self) -> Result<redis::aio::MultiplexedConnection, Status> {
@@ -37,124 +63,192 @@ impl DbRunner {
 
         Ok(client)
     }
-}
-
-#[tonic::async_trait]
-impl DbRunnerService for DbRunner {
-    type RetrieveQueryStream =
-        Pin<Box<dyn Stream<Item = Result<RetrieveQueryResponse, Status>> + Send + Sync>>;
-
-    async fn run_query(
-        &self,
-        request: Request<dbrunner::RunQueryRequest>,
-    ) -> Result<Response<RunQueryResponse>, Status> {
-        let (_, _, data) = request.into_parts();
 
+    /// Run the query, or reuse its cached response, and return its ID.
+    ///
+    /// The errors caused by the query itself are returned in the inner
+    /// result, to be reported to the user.
+    ///
+    /// tonic drops the future when the client goes away or the deadline of
+    /// its `grpc-timeout` passes, which interrupts the execution before
+    /// anything is cached.
+    async fn run_query(&self, query: Query) -> Result<Result<RanQuery, sql::Error>, Status> {
         let mut conn = self.redis_conn().await?;
         let mut cacher = cache::RedisCacher::new(&mut conn);
 
-        let query = Query {
-            initial_sql: data.schema,
-            query: data.query,
-        }
-        .format()
-        .map_err(|e| Status::invalid_argument(format!("Invalid query: {e}")))?;
-
-        let query_uid = query.get_uid().to_hex();
+        let (query, query_uid) = match self.resolve(query) {
+            Ok(resolved) => resolved,
+            Err(e) => return user_error(e).map(Err),
+        };
 
-        // Return the cache if it exists.
-        if let Ok(cache::CacheState::Hit(_)) = cacher.get::<QueryResponse>(query_uid.as_str()).await
+        // Return the cache if it exists, with the statistics of its execution.
+        if let Ok(cache::CacheState::Hit(response)) =
+            cacher.get::<QueryResponse>(query_uid.as_str()).await
+            && let Ok(cache::CacheState::Hit(_)) = cacher
+                .get_entry::<sql::Statistics>(cache::Kind::Statistics, query_uid.as_str())
+                .await
         {
-            return Ok(Response::new(RunQueryResponse {
-                response_type: Some(ResponseType::Id(query_uid.to_string())),
+            return Ok(Ok(RanQuery {
+                id: query_uid.to_string(),
+                nondeterminism: response.nondeterminism,
             }));
         }
 
         // Run the query.
-        match sql::execute_query(query).await {
+        match self.executor.execute_resolved(query).await {
             Ok(response) => {
-                // Store the response in the cache.
+                let nondeterminism = response.nondeterminism;
+                let statistics = response.statistics;
+
+                // Store the response and its statistics in the cache.
                 cacher
                     .set(&query_uid, response)
                     .await
                     .map_err(|e| Status::internal(format!("Failed to set cache: {e}")))?;
+                cacher
+                    .set_entry(cache::Kind::Statistics, &query_uid, statistics)
+                    .await
+                    .map_err(|e| Status::internal(format!("Failed to set cache: {e}")))?;
 
-                Ok(Response::new(RunQueryResponse {
-                    response_type: Some(ResponseType::Id(query_uid.to_string())),
+                Ok(Ok(RanQuery {
+                    id: query_uid.to_string(),
+                    nondeterminism,
                 }))
             }
-            Err(e) => match e {
-                sql::Error::ExecuteInitialSql(_)
-                | sql::Error::ExecuteQuery(_)
-                | sql::Error::QueryTimedOut
-                | sql::Error::TransformQueryResult(_) => Ok(Response::new(RunQueryResponse {
-                    response_type: Some(ResponseType::Error(e.to_string())),
-                })),
-                _ => Err(Status::internal(format!("Failed to run query: {e}"))),
-            },
+            Err(e) => user_error(e).map(Err),
         }
     }
 
-    async fn retrieve_query(
+    /// Explain the query plan of the query, or reuse its cached plan.
+    ///
+    /// The errors caused by the query itself are returned in the inner
+    /// result, to be reported to the user.
+    async fn explain_query_plan(
         &self,
-        request: Request<RetrieveQueryRequest>,
-    ) -> Result<Response<Self::RetrieveQueryStream>, Status> {
-        let data = request.get_ref();
-
+        query: Query,
+    ) -> Result<Result<QueryPlan, sql::Error>, Status> {
         let mut conn = self.redis_conn().await?;
         let mut cacher = cache::RedisCacher::new(&mut conn);
 
-        let query_uid = data.id.as_str();
-
-        let response = match cacher.get::<QueryResponse>(query_uid).await {
-            Ok(cache::CacheState::Hit(response)) => response,
-            Ok(cache::CacheState::Miss) => {
-                return Err(Status::not_found(format!(
-                    "Query with ID {} not found. Run RunQuery again?",
-                    query_uid
-                )))
-            }
-            Err(e) => return Err(Status::internal(format!("Failed to get cache: {e}"))),
+        let (query, query_uid) = match self.resolve(query) {
+            Ok(resolved) => resolved,
+            Err(e) => return user_error(e).map(Err),
         };
 
-        // map the response to a RetrieveQueryResponse stream
-        let query_responses = Box::pin(stream_iter(
-            itertools::chain![
-                std::iter::once(RetrieveQueryResponse {
-                    kind: Some(Kind::Header(HeaderRow {
-                        cells: response.header,
-                    })),
-                }),
-                response.rows.into_iter().map(|row| RetrieveQueryResponse {
-                    kind: Some(Kind::Row(DataRow {
-                        cells: row.into_iter().map(|r| Cell { value: r }).collect(),
-                    })),
-                })
-            ]
-            .map(Ok::<_, Status>),
-        )) as Self::RetrieveQueryStream;
-
-        Ok(Response::new(query_responses))
+        // Return the cache if it exists.
+        if let Ok(cache::CacheState::Hit(plan)) = cacher
+            .get_entry::<QueryPlan>(cache::Kind::Plan, query_uid.as_str())
+            .await
+        {
+            return Ok(Ok(plan));
+        }
+
+        // Explain the query.
+        match self.executor.explain_resolved(query).await {
+            Ok(plan) => {
+                // Store the plan in the cache.
+                cacher
+                    .set_entry(cache::Kind::Plan, &query_uid, &plan)
+                    .await
+                    .map_err(|e| Status::internal(format!("Failed to set cache: {e}")))?;
+
+                Ok(Ok(plan))
+            }
+            Err(e) => user_error(e).map(Err),
+        }
     }
 
-    async fn are_queries_output_same(
-        &self,
-        request: Request<AreQueriesOutputSameRequest>,
-    ) -> Result<Response<AreQueriesOutputSameResponse>, Status> {
-        let data = request.get_ref();
-        let (left, right) = (data.left_id.as_str(), data.right_id.as_str());
+    /// Retrieve the cached response of the query.
+    async fn retrieve_query(&self, query_uid: &str) -> Result<QueryResponse, Status> {
+        let mut conn = self.redis_conn().await?;
+        let mut cacher = cache::RedisCacher::new(&mut conn);
+
+        match cacher.get::<QueryResponse>(query_uid).await {
+            Ok(cache::CacheState::Hit(response)) => Ok(response),
+            Ok(cache::CacheState::Miss) => Err(Status::not_found(format!(
+                "Query with ID {} not found. Run RunQuery again?",
+                query_uid
+            ))),
+            Err(e) => Err(Status::internal(format!("Failed to get cache: {e}"))),
+        }
+    }
 
+    /// Retrieve the cached statistics of the execution of the query.
+    async fn retrieve_statistics(&self, query_uid: &str) -> Result<sql::Statistics, Status> {
         let mut conn = self.redis_conn().await?;
         let mut cacher = cache::RedisCacher::new(&mut conn);
 
-        cacher
-            .same_output_uid(left, right)
+        match cacher
+            .get_entry::<sql::Statistics>(cache::Kind::Statistics, query_uid)
             .await
-            .map_err(|e| {
-                Status::internal(format!(
-                    "Failed to check if queries have the same output: {e}"
-                ))
-            })
-            .map(|same| Response::new(AreQueriesOutputSameResponse { same }))
+        {
+            Ok(cache::CacheState::Hit(statistics)) => Ok(statistics),
+            Ok(cache::CacheState::Miss) => Err(Status::not_found(format!(
+                "Query with ID {} not found. Run RunQuery again?",
+                query_uid
+            ))),
+            Err(e) => Err(Status::internal(format!("Failed to get cache: {e}"))),
+        }
+    }
+
+    /// Check if the two queries have the same output.
+    async fn are_queries_output_same(&self, left: &str, right: &str) -> Result<bool, Status> {
+        let mut conn = self.redis_conn().await?;
+        let mut cacher = cache::RedisCacher::new(&mut conn);
+
+        cacher.same_output_uid(left, right).await.map_err(|e| {
+            Status::internal(format!(
+                "Failed to check if queries have the same output: {e}"
+            ))
+        })
     }
 }
+
+/// Return the error if it is caused by the query itself, to be reported to
+/// the user, or the status of the failure of the service otherwise.
+#[allow(clippy::result_large_err)]
+fn user_error(e: sql::Error) -> Result<sql::Error, Status> {
+    match e {
+        sql::Error::Format { .. }
+        | sql::Error::Untranslatable { .. }
+        | sql::Error::ExecuteInitialSql { .. }
+        | sql::Error::TranslateInitialSql { .. }
+        | sql::Error::LoadFixture { .. }
+        | sql::Error::InvalidFixture { .. }
+        | sql::Error::ExecuteQuery { .. }
+        | sql::Error::QueryTimedOut { .. }
+        | sql::Error::InstructionLimitExceeded { .. }
+        | sql::Error::ResourceExhausted { .. }
+        | sql::Error::Forbidden { .. }
+        | sql::Error::TransformQueryResult(_)
+        | sql::Error::WriteNotAllowed { .. } => Ok(e),
+        sql::Error::QueueFull { retry_after } => {
+            let mut metadata = MetadataMap::new();
+            metadata.insert(
+                "retry-after-ms",
+                MetadataValue::from(retry_after.as_millis() as u64),
+            );
+
+            Err(Status::with_metadata(
+                Code::ResourceExhausted,
+                format!("Too many queries are running: {e}"),
+                metadata,
+            ))
+        }
+        sql::Error::UnknownSqliteProfile(_) => Err(Status::invalid_argument(e.to_string())),
+        sql::Error::Cancelled => Err(Status::cancelled(e.to_string())),
+        _ => Err(Status::internal(format!("Failed to run query: {e}"))),
+    }
+}
+
+/// The clock of the queries not freezing it: the start of the current UTC
+/// day, so that their cached responses are reused within the day.
+fn default_clock_ms() -> i64 {
+    const DAY_MS: i64 = 24 * 60 * 60 * 1000;
+
+    let now_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or(0);
+    now_ms - now_ms.rem_euclid(DAY_MS)
+}
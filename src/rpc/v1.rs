@@ -0,0 +1,119 @@
+//! The deprecated version of the API, rendering every cell as text.
+
+use std::{pin::Pin, result::Result};
+
+pub use dbrunner::db_runner_service_server::{DbRunnerService, DbRunnerServiceServer};
+use dbrunner::{
+    retrieve_query_response::Kind, run_query_response::ResponseType, AreQueriesOutputSameRequest,
+    AreQueriesOutputSameResponse, BlobRendering, Cell, DataRow, HeaderRow, RetrieveQueryRequest,
+    RetrieveQueryResponse, RunQueryRequest, RunQueryResponse,
+};
+use tokio_stream::{iter as stream_iter, Stream};
+use tonic::{Request, Response, Status};
+
+use super::{default_clock_ms, DbRunner};
+use crate::sql::{self, Query};
+
+pub mod dbrunner {
+    tonic::include_proto!("dbrunner.v1");
+}
+
+#[tonic::async_trait]
+impl DbRunnerService for DbRunner {
+    type RetrieveQueryStream =
+        Pin<Box<dyn Stream<Item = Result<RetrieveQueryResponse, Status>> + Send + Sync>>;
+
+    async fn run_query(
+        &self,
+        request: Request<RunQueryRequest>,
+    ) -> Result<Response<RunQueryResponse>, Status> {
+        let (_, _, data) = request.into_parts();
+
+        let query = Query {
+            blob_rendering: data.blob_rendering().into(),
+            initial_sql: data.schema,
+            query: data.query,
+            clock_ms: default_clock_ms(),
+            ..Default::default()
+        };
+
+        let response_type = match DbRunner::run_query(self, query).await? {
+            Ok(ran) => ResponseType::Id(ran.id),
+            // v1 rejects the queries that fail to parse as invalid arguments.
+            Err(e @ (sql::Error::Format { .. } | sql::Error::Untranslatable { .. })) => {
+                return Err(Status::invalid_argument(format!("Invalid query: {e}")));
+            }
+            Err(e) => ResponseType::Error(e.to_string()),
+        };
+
+        Ok(Response::new(RunQueryResponse {
+            response_type: Some(response_type),
+        }))
+    }
+
+    async fn retrieve_query(
+        &self,
+        request: Request<RetrieveQueryRequest>,
+    ) -> Result<Response<Self::RetrieveQueryStream>, Status> {
+        let response = DbRunner::retrieve_query(self, &request.get_ref().id).await?;
+        let blob_rendering = response.blob_rendering;
+
+        // map the response to a RetrieveQueryResponse stream
+        let query_responses = Box::pin(stream_iter(
+            response
+                .result_sets
+                .into_iter()
+                .enumerate()
+                .flat_map(move |(statement_index, result_set)| {
+                    itertools::chain![
+                        std::iter::once(RetrieveQueryResponse {
+                            kind: Some(Kind::Header(HeaderRow {
+                                cells: result_set.header,
+                                truncated: result_set.truncated,
+                                total_rows: result_set.total_rows,
+                                statement_index: statement_index as u32,
+                                affected_rows: result_set.affected_rows,
+                            })),
+                        }),
+                        result_set
+                            .rows
+                            .into_iter()
+                            .map(move |row| RetrieveQueryResponse {
+                                kind: Some(Kind::Row(DataRow {
+                                    cells: row
+                                        .iter()
+                                        .map(|cell| Cell {
+                                            value: cell.to_text(blob_rendering),
+                                        })
+                                        .collect(),
+                                })),
+                            })
+                    ]
+                })
+                .map(Ok::<_, Status>),
+        )) as Self::RetrieveQueryStream;
+
+        Ok(Response::new(query_responses))
+    }
+
+    async fn are_queries_output_same(
+        &self,
+        request: Request<AreQueriesOutputSameRequest>,
+    ) -> Result<Response<AreQueriesOutputSameResponse>, Status> {
+        let data = request.get_ref();
+
+        DbRunner::are_queries_output_same(self, &data.left_id, &data.right_id)
+            .await
+            .map(|same| Response::new(AreQueriesOutputSameResponse { same }))
+    }
+}
+
+impl From<BlobRendering> for sql::BlobRendering {
+    fn from(value: BlobRendering) -> Self {
+        match value {
+            BlobRendering::Lossy => sql::BlobRendering::Lossy,
+            BlobRendering::Hex => sql::BlobRendering::Hex,
+            BlobRendering::Base64 => sql::BlobRendering::Base64,
+        }
+    }
+}
@@ -0,0 +1,401 @@
+//! The current version of the API, keeping the storage class of the cells.
+
+use std::{pin::Pin, result::Result};
+
+pub use dbrunner::db_runner_service_server::{DbRunnerService, DbRunnerServiceServer};
+use dbrunner::{
+    cell, explain_query_plan_response, function, retrieve_query_response::Kind,
+    run_query_response::ResponseType, AreQueriesOutputSameRequest, AreQueriesOutputSameResponse,
+    Cell, Column, DataRow, Dialect, ErrorCategory, ErrorDetails, ErrorPhase,
+    ExplainQueryPlanRequest, ExplainQueryPlanResponse, Fixture, FixtureFormat, Function,
+    FunctionProfile, HeaderRow, ListFunctionsRequest, ListFunctionsResponse, Nondeterminism,
+    PlanNode, Position, QueryPlan, RetrieveQueryMetadataRequest, RetrieveQueryMetadataResponse,
+    RetrieveQueryRequest, RetrieveQueryResponse, RunQueryRequest, RunQueryResponse,
+    StatementLocation, StatementPlan, Statistics,
+};
+use tokio_stream::{iter as stream_iter, Stream};
+use tonic::{Request, Response, Status};
+
+use super::{default_clock_ms, DbRunner};
+use crate::sql::{self, Parameters, Query, StateCapture};
+
+pub mod dbrunner {
+    tonic::include_proto!("dbrunner.v2");
+}
+
+#[tonic::async_trait]
+impl DbRunnerService for DbRunner {
+    type RetrieveQueryStream =
+        Pin<Box<dyn Stream<Item = Result<RetrieveQueryResponse, Status>> + Send + Sync>>;
+
+    async fn run_query(
+        &self,
+        request: Request<RunQueryRequest>,
+    ) -> Result<Response<RunQueryResponse>, Status> {
+        let (_, _, data) = request.into_parts();
+        let query = Query::from(data);
+
+        let response = match DbRunner::run_query(self, query).await? {
+            Ok(ran) => RunQueryResponse {
+                response_type: Some(ResponseType::Id(ran.id)),
+                nondeterminism: Some(Nondeterminism {
+                    clock: ran.nondeterminism.clock,
+                    random: ran.nondeterminism.random,
+                }),
+                failed_schema_statement: None,
+                query_error_position: None,
+                error_details: None,
+            },
+            Err(e) => RunQueryResponse {
+                response_type: Some(ResponseType::Error(e.to_string())),
+                nondeterminism: None,
+                failed_schema_statement: failed_schema_statement(&e),
+                query_error_position: query_error_position(&e),
+                error_details: Some(ErrorDetails::from(&e)),
+            },
+        };
+
+        Ok(Response::new(response))
+    }
+
+    async fn retrieve_query(
+        &self,
+        request: Request<RetrieveQueryRequest>,
+    ) -> Result<Response<Self::RetrieveQueryStream>, Status> {
+        let response = DbRunner::retrieve_query(self, &request.get_ref().id).await?;
+
+        // map the response to a RetrieveQueryResponse stream
+        let statements = response.result_sets.into_iter().enumerate().flat_map(
+            |(statement_index, result_set)| {
+                stream_result_set(result_set, statement_index as u32, None)
+            },
+        );
+        let tables = response
+            .state
+            .into_iter()
+            .flat_map(|(table, contents)| stream_result_set(contents, 0, Some(table)));
+        let query_responses = Box::pin(stream_iter(statements.chain(tables).map(Ok::<_, Status>)))
+            as Self::RetrieveQueryStream;
+
+        Ok(Response::new(query_responses))
+    }
+
+    async fn retrieve_query_metadata(
+        &self,
+        request: Request<RetrieveQueryMetadataRequest>,
+    ) -> Result<Response<RetrieveQueryMetadataResponse>, Status> {
+        let statistics = DbRunner::retrieve_statistics(self, &request.get_ref().id).await?;
+
+        Ok(Response::new(RetrieveQueryMetadataResponse {
+            statistics: Some(Statistics {
+                wall_time_us: statistics.wall_time.as_micros() as u64,
+                vm_steps: statistics.vm_steps,
+                fullscan_steps: statistics.fullscan_steps,
+                sorts: statistics.sorts,
+                autoindexes: statistics.autoindexes,
+                rows: statistics.rows,
+            }),
+        }))
+    }
+
+    async fn are_queries_output_same(
+        &self,
+        request: Request<AreQueriesOutputSameRequest>,
+    ) -> Result<Response<AreQueriesOutputSameResponse>, Status> {
+        let data = request.get_ref();
+
+        DbRunner::are_queries_output_same(self, &data.left_id, &data.right_id)
+            .await
+            .map(|same| Response::new(AreQueriesOutputSameResponse { same }))
+    }
+
+    async fn list_functions(
+        &self,
+        request: Request<ListFunctionsRequest>,
+    ) -> Result<Response<ListFunctionsResponse>, Status> {
+        let profile = request.get_ref().profile().into();
+
+        let functions = sql::functions::list(profile)
+            .map(|info| Function {
+                name: info.name.to_string(),
+                kind: match info.kind {
+                    sql::functions::FunctionKind::Scalar => function::Kind::Scalar,
+                    sql::functions::FunctionKind::Aggregate => function::Kind::Aggregate,
+                }
+                .into(),
+                library: info.library.as_str().to_string(),
+                min_args: info.min_args as u32,
+                max_args: info.max_args as u32,
+                description: info.description.to_string(),
+            })
+            .collect();
+
+        Ok(Response::new(ListFunctionsResponse { functions }))
+    }
+
+    async fn explain_query_plan(
+        &self,
+        request: Request<ExplainQueryPlanRequest>,
+    ) -> Result<Response<ExplainQueryPlanResponse>, Status> {
+        let (_, _, data) = request.into_parts();
+        let query = Query::from(data.query.unwrap_or_default());
+
+        let response = match DbRunner::explain_query_plan(self, query).await? {
+            Ok(plan) => ExplainQueryPlanResponse {
+                response_type: Some(explain_query_plan_response::ResponseType::Plan(QueryPlan {
+                    statements: plan
+                        .statements
+                        .into_iter()
+                        .map(|nodes| StatementPlan {
+                            nodes: nodes.into_iter().map(PlanNode::from).collect(),
+                        })
+                        .collect(),
+                })),
+                failed_schema_statement: None,
+                query_error_position: None,
+                error_details: None,
+            },
+            Err(e) => ExplainQueryPlanResponse {
+                response_type: Some(explain_query_plan_response::ResponseType::Error(
+                    e.to_string(),
+                )),
+                failed_schema_statement: failed_schema_statement(&e),
+                query_error_position: query_error_position(&e),
+                error_details: Some(ErrorDetails::from(&e)),
+            },
+        };
+
+        Ok(Response::new(response))
+    }
+}
+
+/// The statement of the schema that caused the error, if any.
+fn failed_schema_statement(e: &sql::Error) -> Option<StatementLocation> {
+    match e {
+        sql::Error::ExecuteInitialSql { statement, .. }
+        | sql::Error::TranslateInitialSql { statement, .. } => Some(StatementLocation {
+            index: statement.index as u32,
+            offset: statement.offset as u64,
+            line: statement.line as u32,
+            column: statement.column as u32,
+            text: statement.text.clone(),
+        }),
+        _ => None,
+    }
+}
+
+/// Where the error is in the query, if it is known.
+fn query_error_position(e: &sql::Error) -> Option<Position> {
+    match e {
+        sql::Error::Format { position, .. } | sql::Error::ExecuteQuery { position, .. } => position
+            .map(|position| Position {
+                offset: position.offset as u64,
+                line: position.line as u32,
+                column: position.column as u32,
+            }),
+        _ => None,
+    }
+}
+
+/// Stream the result set as its header followed by its rows.
+fn stream_result_set(
+    result_set: sql::ResultSet,
+    statement_index: u32,
+    table: Option<String>,
+) -> impl Iterator<Item = RetrieveQueryResponse> {
+    itertools::chain![
+        std::iter::once(RetrieveQueryResponse {
+            kind: Some(Kind::Header(HeaderRow {
+                cells: result_set.header,
+                truncated: result_set.truncated,
+                total_rows: result_set.total_rows,
+                statement_index,
+                affected_rows: result_set.affected_rows,
+                columns: result_set.columns.into_iter().map(Column::from).collect(),
+                table,
+                changes: result_set.changes,
+                total_changes: result_set.total_changes,
+                last_insert_rowid: result_set.last_insert_rowid,
+            })),
+        }),
+        result_set
+            .rows
+            .into_iter()
+            .map(|row| RetrieveQueryResponse {
+                kind: Some(Kind::Row(DataRow {
+                    cells: row.into_iter().map(Cell::from).collect(),
+                })),
+            })
+    ]
+}
+
+impl From<RunQueryRequest> for Query {
+    fn from(data: RunQueryRequest) -> Self {
+        Query {
+            function_profile: data.function_profile().into(),
+            dialect: data.dialect().into(),
+            seed: data.seed,
+            clock_ms: data.clock_ms.unwrap_or_else(default_clock_ms),
+            parameters: Parameters {
+                positional: data
+                    .positional_parameters
+                    .into_iter()
+                    .map(sql::Cell::from)
+                    .collect(),
+                named: data
+                    .named_parameters
+                    .into_iter()
+                    .map(|(name, value)| (name, value.into()))
+                    .collect(),
+            },
+            capture: StateCapture {
+                written_tables: data.capture_written_tables,
+                tables: data.captured_tables.into_iter().collect(),
+            },
+            read_only: data.read_only,
+            sqlite_profile: data.sqlite_profile,
+            fixtures: data.fixtures.into_iter().map(sql::Fixture::from).collect(),
+            initial_sql: data.schema,
+            query: data.query,
+            ..Default::default()
+        }
+    }
+}
+
+impl From<sql::PlanNode> for PlanNode {
+    fn from(node: sql::PlanNode) -> Self {
+        PlanNode {
+            id: node.id,
+            parent: node.parent,
+            detail: node.detail,
+            children: node.children.into_iter().map(PlanNode::from).collect(),
+        }
+    }
+}
+
+impl From<&sql::Error> for ErrorDetails {
+    fn from(e: &sql::Error) -> Self {
+        let sqlite_error = e.sqlite_error();
+        ErrorDetails {
+            category: ErrorCategory::from(e.category()).into(),
+            phase: e
+                .phase()
+                .map_or(ErrorPhase::Unspecified, ErrorPhase::from)
+                .into(),
+            sqlite_code: sqlite_error.map_or(0, |e| e.extended_code & 0xff),
+            sqlite_extended_code: sqlite_error.map_or(0, |e| e.extended_code),
+        }
+    }
+}
+
+impl From<sql::error::Category> for ErrorCategory {
+    fn from(value: sql::error::Category) -> Self {
+        use sql::error::Category;
+
+        match value {
+            Category::Syntax => ErrorCategory::Syntax,
+            Category::NoSuchTable => ErrorCategory::NoSuchTable,
+            Category::NoSuchColumn => ErrorCategory::NoSuchColumn,
+            Category::NoSuchFunction => ErrorCategory::NoSuchFunction,
+            Category::Sql => ErrorCategory::Sql,
+            Category::Constraint => ErrorCategory::Constraint,
+            Category::DatatypeMismatch => ErrorCategory::DatatypeMismatch,
+            Category::Parameter => ErrorCategory::Parameter,
+            Category::Timeout => ErrorCategory::Timeout,
+            Category::InstructionLimit => ErrorCategory::InstructionLimit,
+            Category::ResourceExhausted => ErrorCategory::ResourceExhausted,
+            Category::Forbidden => ErrorCategory::Forbidden,
+            Category::ReadOnly => ErrorCategory::ReadOnly,
+            Category::InvalidRequest => ErrorCategory::InvalidRequest,
+            Category::Internal => ErrorCategory::Internal,
+            Category::Cancelled => ErrorCategory::Cancelled,
+            Category::Unsupported => ErrorCategory::Unsupported,
+        }
+    }
+}
+
+impl From<sql::error::Phase> for ErrorPhase {
+    fn from(value: sql::error::Phase) -> Self {
+        match value {
+            sql::error::Phase::Format => ErrorPhase::Format,
+            sql::error::Phase::Schema => ErrorPhase::Schema,
+            sql::error::Phase::Query => ErrorPhase::Query,
+            sql::error::Phase::Transform => ErrorPhase::Transform,
+        }
+    }
+}
+
+impl From<sql::Cell> for Cell {
+    fn from(value: sql::Cell) -> Self {
+        let value = match value {
+            sql::Cell::Null => cell::Value::Null(true),
+            sql::Cell::Integer(i) => cell::Value::Integer(i),
+            sql::Cell::Real(f) => cell::Value::Real(f),
+            sql::Cell::Text(s) => cell::Value::Text(s),
+            sql::Cell::Blob(b) => cell::Value::Blob(b),
+        };
+
+        Cell { value: Some(value) }
+    }
+}
+
+impl From<Cell> for sql::Cell {
+    fn from(value: Cell) -> Self {
+        match value.value {
+            None | Some(cell::Value::Null(_)) => sql::Cell::Null,
+            Some(cell::Value::Integer(i)) => sql::Cell::Integer(i),
+            Some(cell::Value::Real(f)) => sql::Cell::Real(f),
+            Some(cell::Value::Text(s)) => sql::Cell::Text(s),
+            Some(cell::Value::Blob(b)) => sql::Cell::Blob(b),
+        }
+    }
+}
+
+impl From<sql::ColumnMetadata> for Column {
+    fn from(value: sql::ColumnMetadata) -> Self {
+        Column {
+            decl_type: value.decl_type,
+            origin_table: value.origin_table,
+            origin_column: value.origin_column,
+            is_expression: value.is_expression,
+        }
+    }
+}
+
+impl From<FunctionProfile> for sql::FunctionProfile {
+    fn from(value: FunctionProfile) -> Self {
+        match value {
+            FunctionProfile::Extended => sql::FunctionProfile::Extended,
+            FunctionProfile::Sqlite => sql::FunctionProfile::Sqlite,
+        }
+    }
+}
+
+impl From<Fixture> for sql::Fixture {
+    fn from(value: Fixture) -> Self {
+        sql::Fixture {
+            format: value.format().into(),
+            table: value.table,
+            data: value.data,
+        }
+    }
+}
+
+impl From<FixtureFormat> for sql::FixtureFormat {
+    fn from(value: FixtureFormat) -> Self {
+        match value {
+            FixtureFormat::Csv => sql::FixtureFormat::Csv,
+            FixtureFormat::JsonLines => sql::FixtureFormat::JsonLines,
+        }
+    }
+}
+
+impl From<Dialect> for sql::Dialect {
+    fn from(value: Dialect) -> Self {
+        match value {
+            Dialect::Sqlite => sql::Dialect::Sqlite,
+            Dialect::Postgresql => sql::Dialect::Postgresql,
+            Dialect::Mysql => sql::Dialect::Mysql,
+        }
+    }
+}
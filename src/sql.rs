@@ -1,37 +1,440 @@
+pub mod authorizer;
+pub mod column;
+pub mod determinism;
+pub mod dialect;
 pub mod error;
 pub mod executor;
+pub mod fixture;
 pub mod fmt;
+pub mod functions;
+pub mod plan;
+pub mod pool;
+pub mod profile;
+pub mod read_only;
+pub mod snapshot;
+pub mod statements;
 pub mod uid;
 
+pub use determinism::Nondeterminism;
+pub use dialect::Dialect;
 pub use error::Error;
-pub use executor::execute_query;
+pub use executor::Executor;
+pub use fixture::{Fixture, FixtureFormat};
+pub use functions::FunctionProfile;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    time::Duration,
+};
+
 use serde::{Deserialize, Serialize};
 pub use uid::{Hash as Blake3Hash, UidGetter};
 
 /// A SQL query.
-#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+#[derive(Clone, Debug, Default, Hash, PartialEq, Eq)]
 pub struct Query {
     /// The initial SQL (migration).
     pub initial_sql: String,
 
+    /// The rows inserted into the tables once the initial SQL ran.
+    pub fixtures: Vec<Fixture>,
+
     /// The SQL query to run.
     pub query: String,
+
+    /// How the blobs in the result are rendered as text.
+    pub blob_rendering: BlobRendering,
+
+    /// The seed of `random()` and `randomblob()`.
+    pub seed: u64,
+
+    /// The frozen clock read by `'now'` and `CURRENT_TIMESTAMP`, in
+    /// milliseconds since the Unix epoch.
+    pub clock_ms: i64,
+
+    /// The functions available to the initial SQL and the query.
+    pub function_profile: FunctionProfile,
+
+    /// The values bound to the parameters of the query.
+    pub parameters: Parameters,
+
+    /// The tables whose contents are captured after the query.
+    pub capture: StateCapture,
+
+    /// Whether the query may only read the database, once the initial SQL
+    /// ran.
+    pub read_only: bool,
+
+    /// The name of the SQLite configuration profile of the executor the
+    /// initial SQL and the query run with, or the empty string for the
+    /// default one.
+    pub sqlite_profile: String,
+
+    /// The dialect the query and the initial SQL are written in. The query
+    /// is translated to SQLite when it is formatted, and the initial SQL
+    /// when it runs.
+    pub dialect: Dialect,
+}
+
+/// The tables whose contents are captured after the query, to compare the
+/// queries modifying the database by their resulting state.
+#[derive(Clone, Debug, Default, Hash, PartialEq, Eq)]
+pub struct StateCapture {
+    /// Capture the tables the query inserted into, updated or deleted from,
+    /// including by triggers, which still exist after the query.
+    pub written_tables: bool,
+
+    /// Capture these tables, or views.
+    pub tables: BTreeSet<String>,
+}
+
+/// The values bound to the parameters of each statement of a query.
+///
+/// `?` and `?NNN` take their value from `positional`, by their index in the
+/// statement starting from 1. `:name`, `@name` and `$name` take their value
+/// from `named`, keyed by the name without its prefix.
+#[derive(Clone, Debug, Default, Hash, PartialEq, Eq)]
+pub struct Parameters {
+    pub positional: Vec<Cell>,
+    pub named: BTreeMap<String, Cell>,
+}
+
+impl StateCapture {
+    /// Whether any table is captured.
+    pub fn is_requested(&self) -> bool {
+        self.written_tables || !self.tables.is_empty()
+    }
+}
+
+impl Parameters {
+    /// The value of the parameter of the statement at `index`, named `name`
+    /// if it is not anonymous.
+    pub fn get(&self, index: usize, name: Option<&str>) -> Option<&Cell> {
+        match name {
+            None => self.positional.get(index.checked_sub(1)?),
+            Some(name) if name.starts_with('?') => self
+                .positional
+                .get(name[1..].parse::<usize>().ok()?.checked_sub(1)?),
+            Some(name) => self.named.get(&name[1..]),
+        }
+    }
 }
 
 impl Query {
-    /// Format the SQL query.
+    /// Format the SQL query, translated to SQLite.
+    ///
+    /// The initial SQL is kept as written, along with its dialect, as it is
+    /// translated statement by statement when it runs.
     pub fn format(self) -> Result<Query, Error> {
-        let formatted_query = fmt::format_sql(&self.query)?;
+        let formatted_query = fmt::format_sql(&self.query, self.dialect)?;
         Ok(Query {
-            initial_sql: self.initial_sql,
             query: formatted_query,
+            ..self
         })
     }
 }
 
 /// A standard SQL query response.
-#[derive(Clone, Debug, Hash, Serialize, Deserialize, PartialEq, Eq)]
+#[derive(Clone, Debug, Default, Hash, Serialize, Deserialize, PartialEq, Eq)]
 pub struct QueryResponse {
+    /// The result sets of the statements in the query, in order.
+    pub result_sets: Vec<ResultSet>,
+
+    /// How the blobs in the result sets are rendered as text.
+    #[serde(default)]
+    pub blob_rendering: BlobRendering,
+
+    /// The non-deterministic inputs the query read.
+    #[serde(default)]
+    pub nondeterminism: Nondeterminism,
+
+    /// The contents of the captured tables after the query, keyed by their
+    /// name. The tables outside of the main database are prefixed by their
+    /// database, such as `temp.`.
+    #[serde(default)]
+    pub state: BTreeMap<String, ResultSet>,
+
+    /// Whether the tables were captured, in which case the output is
+    /// compared by `state` alone: the queries writing the same contents
+    /// are the same whatever their statements return.
+    #[serde(default)]
+    pub captured: bool,
+
+    /// The cost of the execution.
+    ///
+    /// It varies between the executions of the same query, so it is neither
+    /// part of the UID nor stored with the output.
+    #[serde(skip)]
+    pub statistics: Statistics,
+}
+
+/// The cost of the statements of a query, as counted by SQLite.
+#[derive(Clone, Copy, Debug, Default, Hash, Serialize, Deserialize, PartialEq, Eq)]
+pub struct Statistics {
+    /// The wall-clock time the query took, excluding the initial SQL.
+    pub wall_time: Duration,
+
+    /// The number of virtual machine instructions run.
+    pub vm_steps: u64,
+
+    /// The number of times a table or an index was stepped through in a full
+    /// scan.
+    pub fullscan_steps: u64,
+
+    /// The number of sort operations.
+    pub sorts: u64,
+
+    /// The number of rows inserted into automatic indexes.
+    pub autoindexes: u64,
+
+    /// The number of rows the statements produced, up to the first truncated
+    /// one of each.
+    pub rows: u64,
+}
+
+/// The result of a statement in the query.
+#[derive(Clone, Debug, Default, Hash, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ResultSet {
     pub header: Vec<String>,
-    pub rows: Vec<Vec<Option<String>>>,
+    pub rows: Vec<Vec<Cell>>,
+
+    /// The metadata of the columns, in the order of `header`.
+    #[serde(default)]
+    pub columns: Vec<ColumnMetadata>,
+
+    /// Whether `rows` was cut short by the row or byte limit.
+    #[serde(default)]
+    pub truncated: bool,
+
+    /// The number of rows the statement produced, up to the first truncated
+    /// one: a lower bound of its total if `truncated`.
+    #[serde(default)]
+    pub total_rows: u64,
+
+    /// The number of rows inserted, updated or deleted by the statement,
+    /// including the changes made by triggers.
+    #[serde(default)]
+    pub affected_rows: u64,
+
+    /// `changes()` after the statement: the number of rows it inserted,
+    /// updated or deleted, excluding the changes made by triggers.
+    ///
+    /// Unlike SQLite, which keeps the count of the last INSERT, UPDATE or
+    /// DELETE, it is 0 for the statements changing no row.
+    #[serde(default)]
+    pub changes: u64,
+
+    /// `total_changes()` after the statement, counted from the start of the
+    /// query rather than of the initial SQL.
+    #[serde(default)]
+    pub total_changes: u64,
+
+    /// `last_insert_rowid()` after the statement, or 0 if no row was
+    /// inserted into a rowid table since the start of the query.
+    #[serde(default)]
+    pub last_insert_rowid: i64,
+}
+
+/// The metadata of a column of a result set.
+#[derive(Clone, Debug, Default, Hash, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ColumnMetadata {
+    /// The declared type of the column in the table, if it comes from one.
+    pub decl_type: Option<String>,
+
+    /// The table the column comes from.
+    pub origin_table: Option<String>,
+
+    /// The column of `origin_table` the column comes from.
+    pub origin_column: Option<String>,
+
+    /// Whether the column is an expression rather than a table column.
+    pub is_expression: bool,
+}
+
+/// The query plan of each statement in the query, in order.
+#[derive(Clone, Debug, Default, Hash, Serialize, Deserialize, PartialEq, Eq)]
+pub struct QueryPlan {
+    /// The root steps of the plan of each statement. The statements not
+    /// reading any table, such as `CREATE TABLE`, have no step.
+    pub statements: Vec<Vec<PlanNode>>,
+}
+
+/// A step of a query plan, as reported by `EXPLAIN QUERY PLAN`.
+#[derive(Clone, Debug, Default, Hash, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PlanNode {
+    /// The ID of the step, unique within the plan of the statement.
+    pub id: i64,
+
+    /// The ID of the parent step, or 0 for a root step.
+    pub parent: i64,
+
+    /// The description of the step, such as `SCAN test`.
+    pub detail: String,
+
+    /// The steps nested in this one, in order.
+    pub children: Vec<PlanNode>,
+}
+
+/// A cell of a result set, keeping the storage class of the SQLite value.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Cell {
+    Null,
+    Integer(i64),
+    Real(f64),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+impl Cell {
+    /// The number of bytes the cell counts for in the result size limit.
+    pub fn size(&self) -> usize {
+        match self {
+            Cell::Null => 0,
+            Cell::Integer(_) | Cell::Real(_) => 8,
+            Cell::Text(s) => s.len(),
+            Cell::Blob(b) => b.len(),
+        }
+    }
+
+    /// Render the cell as text, or `None` for NULL.
+    pub fn to_text(&self, blob_rendering: BlobRendering) -> Option<String> {
+        match self {
+            Cell::Null => None,
+            Cell::Integer(i) => Some(i.to_string()),
+            Cell::Real(f) => Some(f.to_string()),
+            Cell::Text(s) => Some(s.clone()),
+            Cell::Blob(b) => Some(blob_rendering.render(b)),
+        }
+    }
+}
+
+/// How a blob is rendered as text.
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BlobRendering {
+    /// The UTF-8 text of the blob, with the invalid sequences replaced.
+    #[default]
+    Lossy,
+    /// The SQL hex literal of the blob, such as `X'FFFE'`.
+    Hex,
+    /// The standard base64 encoding of the blob, with padding.
+    Base64,
+}
+
+impl BlobRendering {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BlobRendering::Lossy => "lossy",
+            BlobRendering::Hex => "hex",
+            BlobRendering::Base64 => "base64",
+        }
+    }
+
+    /// Render the blob as text.
+    pub fn render(&self, blob: &[u8]) -> String {
+        use base64::Engine;
+
+        match self {
+            BlobRendering::Lossy => String::from_utf8_lossy(blob).to_string(),
+            BlobRendering::Hex => {
+                let hex = blob.iter().map(|b| format!("{b:02X}")).collect::<String>();
+                format!("X'{hex}'")
+            }
+            BlobRendering::Base64 => base64::engine::general_purpose::STANDARD.encode(blob),
+        }
+    }
+}
+
+impl rusqlite::ToSql for Cell {
+    fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
+        use rusqlite::types::{ToSqlOutput, ValueRef};
+
+        Ok(ToSqlOutput::Borrowed(match self {
+            Cell::Null => ValueRef::Null,
+            Cell::Integer(i) => ValueRef::Integer(*i),
+            Cell::Real(f) => ValueRef::Real(*f),
+            Cell::Text(s) => ValueRef::Text(s.as_bytes()),
+            Cell::Blob(b) => ValueRef::Blob(b),
+        }))
+    }
+}
+
+impl From<rusqlite::types::Value> for Cell {
+    fn from(value: rusqlite::types::Value) -> Self {
+        use rusqlite::types::Value;
+
+        match value {
+            Value::Null => Cell::Null,
+            Value::Integer(i) => Cell::Integer(i),
+            Value::Real(f) => Cell::Real(f),
+            Value::Text(s) => Cell::Text(s),
+            Value::Blob(b) => Cell::Blob(b),
+        }
+    }
+}
+
+// Reals are compared and hashed by their bits, which is exact as SQLite turns
+// NaN into NULL.
+impl PartialEq for Cell {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Cell::Null, Cell::Null) => true,
+            (Cell::Integer(a), Cell::Integer(b)) => a == b,
+            (Cell::Real(a), Cell::Real(b)) => a.to_bits() == b.to_bits(),
+            (Cell::Text(a), Cell::Text(b)) => a == b,
+            (Cell::Blob(a), Cell::Blob(b)) => a == b,
+            _ => false,
+        }
+    }
+}
+
+impl Eq for Cell {}
+
+impl std::hash::Hash for Cell {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Cell::Null => {}
+            Cell::Integer(i) => i.hash(state),
+            Cell::Real(f) => f.to_bits().hash(state),
+            Cell::Text(s) => s.hash(state),
+            Cell::Blob(b) => b.hash(state),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parameters() {
+        let parameters = Parameters {
+            positional: vec![Cell::Integer(1), Cell::Integer(2)],
+            named: BTreeMap::from([("id".to_string(), Cell::Integer(3))]),
+        };
+
+        assert_eq!(parameters.get(1, None), Some(&Cell::Integer(1)));
+        assert_eq!(parameters.get(1, Some("?2")), Some(&Cell::Integer(2)));
+        assert_eq!(parameters.get(2, Some(":id")), Some(&Cell::Integer(3)));
+        assert_eq!(parameters.get(1, Some("@id")), Some(&Cell::Integer(3)));
+        assert_eq!(parameters.get(1, Some("$id")), Some(&Cell::Integer(3)));
+        assert_eq!(parameters.get(3, None), None);
+        assert_eq!(parameters.get(1, Some("?0")), None);
+        assert_eq!(parameters.get(1, Some(":name")), None);
+    }
+
+    #[test]
+    fn test_render_blob() {
+        let blob = [0xFF, 0xFE, b'a'];
+
+        assert_eq!(BlobRendering::Hex.render(&blob), "X'FFFE61'");
+        assert_eq!(BlobRendering::Base64.render(&blob), "//5h");
+        assert_eq!(BlobRendering::Lossy.render(&blob), "\u{FFFD}\u{FFFD}a");
+        assert_eq!(BlobRendering::Hex.render(&[]), "X''");
+        assert_ne!(
+            BlobRendering::Hex.render(&[0xFF]),
+            BlobRendering::Hex.render(&[0xFE])
+        );
+    }
 }
@@ -0,0 +1,450 @@
+//! The authorizer that decides which statements an execution may run.
+
+use std::{
+    collections::{BTreeSet, HashSet},
+    fmt::Display,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use rusqlite::hooks::{AuthAction, AuthContext, Authorization};
+use serde::{Deserialize, Serialize};
+
+/// The action codes of the SQLite authorizer.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ActionCode {
+    CreateIndex,
+    CreateTable,
+    CreateTempIndex,
+    CreateTempTable,
+    CreateTempTrigger,
+    CreateTempView,
+    CreateTrigger,
+    CreateView,
+    Delete,
+    DropIndex,
+    DropTable,
+    DropTempIndex,
+    DropTempTable,
+    DropTempTrigger,
+    DropTempView,
+    DropTrigger,
+    DropView,
+    Insert,
+    Pragma,
+    Read,
+    Select,
+    Transaction,
+    Update,
+    Attach,
+    Detach,
+    AlterTable,
+    Reindex,
+    Analyze,
+    CreateVtable,
+    DropVtable,
+    Function,
+    Savepoint,
+    Recursive,
+    Unknown,
+}
+
+impl ActionCode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ActionCode::CreateIndex => "create_index",
+            ActionCode::CreateTable => "create_table",
+            ActionCode::CreateTempIndex => "create_temp_index",
+            ActionCode::CreateTempTable => "create_temp_table",
+            ActionCode::CreateTempTrigger => "create_temp_trigger",
+            ActionCode::CreateTempView => "create_temp_view",
+            ActionCode::CreateTrigger => "create_trigger",
+            ActionCode::CreateView => "create_view",
+            ActionCode::Delete => "delete",
+            ActionCode::DropIndex => "drop_index",
+            ActionCode::DropTable => "drop_table",
+            ActionCode::DropTempIndex => "drop_temp_index",
+            ActionCode::DropTempTable => "drop_temp_table",
+            ActionCode::DropTempTrigger => "drop_temp_trigger",
+            ActionCode::DropTempView => "drop_temp_view",
+            ActionCode::DropTrigger => "drop_trigger",
+            ActionCode::DropView => "drop_view",
+            ActionCode::Insert => "insert",
+            ActionCode::Pragma => "pragma",
+            ActionCode::Read => "read",
+            ActionCode::Select => "select",
+            ActionCode::Transaction => "transaction",
+            ActionCode::Update => "update",
+            ActionCode::Attach => "attach",
+            ActionCode::Detach => "detach",
+            ActionCode::AlterTable => "alter_table",
+            ActionCode::Reindex => "reindex",
+            ActionCode::Analyze => "analyze",
+            ActionCode::CreateVtable => "create_vtable",
+            ActionCode::DropVtable => "drop_vtable",
+            ActionCode::Function => "function",
+            ActionCode::Savepoint => "savepoint",
+            ActionCode::Recursive => "recursive",
+            ActionCode::Unknown => "unknown",
+        }
+    }
+}
+
+impl Display for ActionCode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Describe the action with its code and the objects it touches.
+fn describe(action: &AuthAction<'_>) -> (ActionCode, String) {
+    use AuthAction::*;
+
+    match *action {
+        CreateIndex {
+            index_name,
+            table_name,
+        } => (
+            ActionCode::CreateIndex,
+            format!("{index_name} ON {table_name}"),
+        ),
+        CreateTable { table_name } => (ActionCode::CreateTable, table_name.to_string()),
+        CreateTempIndex {
+            index_name,
+            table_name,
+        } => (
+            ActionCode::CreateTempIndex,
+            format!("{index_name} ON {table_name}"),
+        ),
+        CreateTempTable { table_name } => (ActionCode::CreateTempTable, table_name.to_string()),
+        CreateTempTrigger {
+            trigger_name,
+            table_name,
+        } => (
+            ActionCode::CreateTempTrigger,
+            format!("{trigger_name} ON {table_name}"),
+        ),
+        CreateTempView { view_name } => (ActionCode::CreateTempView, view_name.to_string()),
+        CreateTrigger {
+            trigger_name,
+            table_name,
+        } => (
+            ActionCode::CreateTrigger,
+            format!("{trigger_name} ON {table_name}"),
+        ),
+        CreateView { view_name } => (ActionCode::CreateView, view_name.to_string()),
+        Delete { table_name } => (ActionCode::Delete, table_name.to_string()),
+        DropIndex {
+            index_name,
+            table_name,
+        } => (
+            ActionCode::DropIndex,
+            format!("{index_name} ON {table_name}"),
+        ),
+        DropTable { table_name } => (ActionCode::DropTable, table_name.to_string()),
+        DropTempIndex {
+            index_name,
+            table_name,
+        } => (
+            ActionCode::DropTempIndex,
+            format!("{index_name} ON {table_name}"),
+        ),
+        DropTempTable { table_name } => (ActionCode::DropTempTable, table_name.to_string()),
+        DropTempTrigger {
+            trigger_name,
+            table_name,
+        } => (
+            ActionCode::DropTempTrigger,
+            format!("{trigger_name} ON {table_name}"),
+        ),
+        DropTempView { view_name } => (ActionCode::DropTempView, view_name.to_string()),
+        DropTrigger {
+            trigger_name,
+            table_name,
+        } => (
+            ActionCode::DropTrigger,
+            format!("{trigger_name} ON {table_name}"),
+        ),
+        DropView { view_name } => (ActionCode::DropView, view_name.to_string()),
+        Insert { table_name } => (ActionCode::Insert, table_name.to_string()),
+        Pragma {
+            pragma_name,
+            pragma_value: Some(pragma_value),
+        } => (
+            ActionCode::Pragma,
+            format!("{pragma_name} = {pragma_value}"),
+        ),
+        Pragma {
+            pragma_name,
+            pragma_value: None,
+        } => (ActionCode::Pragma, pragma_name.to_string()),
+        Read {
+            table_name,
+            column_name,
+        } => (ActionCode::Read, format!("{table_name}.{column_name}")),
+        Select => (ActionCode::Select, String::new()),
+        Transaction { operation } => (ActionCode::Transaction, format!("{operation:?}")),
+        Update {
+            table_name,
+            column_name,
+        } => (ActionCode::Update, format!("{table_name}.{column_name}")),
+        Attach { filename } => (ActionCode::Attach, filename.to_string()),
+        Detach { database_name } => (ActionCode::Detach, database_name.to_string()),
+        AlterTable {
+            database_name,
+            table_name,
+        } => (
+            ActionCode::AlterTable,
+            format!("{database_name}.{table_name}"),
+        ),
+        Reindex { index_name } => (ActionCode::Reindex, index_name.to_string()),
+        Analyze { table_name } => (ActionCode::Analyze, table_name.to_string()),
+        CreateVtable {
+            table_name,
+            module_name,
+        } => (
+            ActionCode::CreateVtable,
+            format!("{table_name} USING {module_name}"),
+        ),
+        DropVtable {
+            table_name,
+            module_name,
+        } => (
+            ActionCode::DropVtable,
+            format!("{table_name} USING {module_name}"),
+        ),
+        Function { function_name } => (ActionCode::Function, function_name.to_string()),
+        Savepoint {
+            operation,
+            savepoint_name,
+        } => (
+            ActionCode::Savepoint,
+            format!("{operation:?} {savepoint_name}"),
+        ),
+        Recursive => (ActionCode::Recursive, String::new()),
+        Unknown { code, .. } => (ActionCode::Unknown, format!("action code {code}")),
+        _ => (ActionCode::Unknown, format!("{action:?}")),
+    }
+}
+
+/// The policy deciding which actions a statement may take.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Policy {
+    /// The actions that are denied.
+    pub denied_actions: HashSet<ActionCode>,
+
+    /// The SQL functions that are denied, in lowercase.
+    pub denied_functions: HashSet<String>,
+
+    /// The pragmas that may be set, in lowercase.
+    ///
+    /// Other pragmas can only be read.
+    pub writable_pragmas: HashSet<String>,
+}
+
+/// The pragmas which only read the database even when given an argument,
+/// such as `PRAGMA table_info(students)`, in lowercase.
+const READ_ONLY_PRAGMAS: &[&str] = &[
+    "foreign_key_check",
+    "foreign_key_list",
+    "index_info",
+    "index_list",
+    "index_xinfo",
+    "integrity_check",
+    "quick_check",
+    "table_info",
+    "table_list",
+    "table_xinfo",
+];
+
+/// Whether the pragma only reads the database, whatever its argument.
+pub fn is_read_only_pragma(pragma_name: &str) -> bool {
+    READ_ONLY_PRAGMAS.contains(&pragma_name.to_lowercase().as_str())
+}
+
+impl Policy {
+    /// The default policy for the trusted initial SQL.
+    pub fn schema() -> Self {
+        Self {
+            denied_actions: HashSet::from([ActionCode::Attach, ActionCode::Detach]),
+            denied_functions: HashSet::from(["load_extension".to_string()]),
+            writable_pragmas: [
+                "foreign_keys",
+                "defer_foreign_keys",
+                "recursive_triggers",
+                "case_sensitive_like",
+                "legacy_alter_table",
+                "user_version",
+            ]
+            .into_iter()
+            .map(String::from)
+            .collect(),
+        }
+    }
+
+    /// The default policy for the untrusted query.
+    pub fn query() -> Self {
+        Self {
+            denied_actions: HashSet::from([
+                ActionCode::Attach,
+                ActionCode::Detach,
+                ActionCode::CreateVtable,
+                ActionCode::DropVtable,
+                ActionCode::Unknown,
+            ]),
+            denied_functions: HashSet::from(["load_extension".to_string()]),
+            writable_pragmas: HashSet::new(),
+        }
+    }
+
+    /// Check if the action is allowed by this policy.
+    pub fn allows(&self, action: &AuthAction<'_>) -> bool {
+        let (code, _) = describe(action);
+        if self.denied_actions.contains(&code) {
+            return false;
+        }
+
+        match *action {
+            AuthAction::Function { function_name } => !self
+                .denied_functions
+                .contains(&function_name.to_lowercase()),
+            AuthAction::Pragma {
+                pragma_name,
+                pragma_value: Some(_),
+            } => {
+                is_read_only_pragma(pragma_name)
+                    || self.writable_pragmas.contains(&pragma_name.to_lowercase())
+            }
+            _ => true,
+        }
+    }
+}
+
+impl FromStr for Policy {
+    type Err = serde_json::Error;
+
+    /// Parse the policy from its JSON representation.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_str(s)
+    }
+}
+
+/// The action denied by the authorizer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Denial {
+    pub action: ActionCode,
+    pub detail: String,
+}
+
+/// Installs a [`Policy`] on a connection and records the action it denied,
+/// the pragmas it allowed to be set and the tables it allowed to be written.
+#[derive(Clone, Default)]
+pub struct Authorizer {
+    denial: Arc<Mutex<Option<Denial>>>,
+    written_pragmas: Arc<Mutex<Vec<(String, String)>>>,
+    written_tables: Arc<Mutex<BTreeSet<(String, String)>>>,
+}
+
+impl Authorizer {
+    /// Install the policy on the connection, replacing the previous one.
+    pub fn install(&self, conn: &rusqlite::Connection, policy: Policy) {
+        let denial = self.denial.clone();
+        let written_pragmas = self.written_pragmas.clone();
+        let written_tables = self.written_tables.clone();
+        written_tables.lock().unwrap().clear();
+
+        conn.authorizer(Some(move |ctx: AuthContext<'_>| {
+            if policy.allows(&ctx.action) {
+                match ctx.action {
+                    AuthAction::Pragma {
+                        pragma_name,
+                        pragma_value: Some(pragma_value),
+                    } if !is_read_only_pragma(pragma_name) => written_pragmas
+                        .lock()
+                        .unwrap()
+                        .push((pragma_name.to_string(), pragma_value.to_string())),
+                    AuthAction::Insert { table_name }
+                    | AuthAction::Update { table_name, .. }
+                    | AuthAction::Delete { table_name } => {
+                        written_tables.lock().unwrap().insert((
+                            ctx.database_name.unwrap_or("main").to_string(),
+                            table_name.to_string(),
+                        ));
+                    }
+                    _ => {}
+                }
+
+                return Authorization::Allow;
+            }
+
+            let (action, detail) = describe(&ctx.action);
+            *denial.lock().unwrap() = Some(Denial { action, detail });
+            Authorization::Deny
+        }));
+    }
+
+    /// Take the action denied by the authorizer, if any.
+    pub fn take_denial(&self) -> Option<Denial> {
+        self.denial.lock().unwrap().take()
+    }
+
+    /// The pragmas set so far, in the order they were set.
+    pub fn written_pragmas(&self) -> Vec<(String, String)> {
+        self.written_pragmas.lock().unwrap().clone()
+    }
+
+    /// The `(database, table)` pairs written since the policy was installed,
+    /// including by triggers and the schema tables of SQLite.
+    pub fn written_tables(&self) -> BTreeSet<(String, String)> {
+        self.written_tables.lock().unwrap().clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_policy() {
+        let policy: Policy =
+            r#"{"denied_actions":["drop_table"],"writable_pragmas":["foreign_keys"]}"#
+                .parse()
+                .expect("valid policy");
+
+        assert!(!policy.allows(&AuthAction::DropTable { table_name: "test" }));
+        assert!(policy.allows(&AuthAction::Attach { filename: "test" }));
+        assert!(policy.allows(&AuthAction::Pragma {
+            pragma_name: "FOREIGN_KEYS",
+            pragma_value: Some("ON"),
+        }));
+        assert!(!policy.allows(&AuthAction::Pragma {
+            pragma_name: "max_page_count",
+            pragma_value: Some("1"),
+        }));
+        assert!(policy.allows(&AuthAction::Pragma {
+            pragma_name: "max_page_count",
+            pragma_value: None,
+        }));
+    }
+
+    #[test]
+    fn test_read_only_pragmas() {
+        let policy = Policy::query();
+
+        for pragma_name in ["table_info", "INDEX_LIST", "foreign_key_list", "index_info"] {
+            assert!(
+                policy.allows(&AuthAction::Pragma {
+                    pragma_name,
+                    pragma_value: Some("students"),
+                }),
+                "{pragma_name} should be allowed with an argument"
+            );
+        }
+        assert!(!policy.allows(&AuthAction::Pragma {
+            pragma_name: "foreign_keys",
+            pragma_value: Some("ON"),
+        }));
+    }
+}
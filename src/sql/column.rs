@@ -0,0 +1,66 @@
+//! The metadata of the columns of a statement.
+
+use std::{
+    ffi::{c_char, CStr, CString},
+    ptr,
+};
+
+use rusqlite::ffi;
+
+use super::ColumnMetadata;
+
+/// Describe the columns of the statement in `sql`.
+///
+/// [`rusqlite::Statement`] only exposes the declared type, so the statement
+/// is prepared again with the C API to get the origin of its columns.
+pub fn describe(
+    conn: &rusqlite::Connection,
+    sql: &str,
+) -> Result<Vec<ColumnMetadata>, rusqlite::Error> {
+    let sql = CString::new(sql)?;
+
+    // SAFETY: the handle is only used on this thread while `conn` is
+    // borrowed, and the statement is finalized before returning.
+    unsafe {
+        let db = conn.handle();
+        let mut stmt = ptr::null_mut();
+        let rc = ffi::sqlite3_prepare_v2(db, sql.as_ptr(), -1, &mut stmt, ptr::null_mut());
+        if rc != ffi::SQLITE_OK {
+            let message = text(ffi::sqlite3_errmsg(db));
+            return Err(rusqlite::Error::SqliteFailure(ffi::Error::new(rc), message));
+        }
+        if stmt.is_null() {
+            // The SQL is empty or a comment.
+            return Ok(Vec::new());
+        }
+
+        let columns = (0..ffi::sqlite3_column_count(stmt))
+            .map(|i| {
+                let origin_column = text(ffi::sqlite3_column_origin_name(stmt, i));
+
+                ColumnMetadata {
+                    decl_type: text(ffi::sqlite3_column_decltype(stmt, i)),
+                    origin_table: text(ffi::sqlite3_column_table_name(stmt, i)),
+                    is_expression: origin_column.is_none(),
+                    origin_column,
+                }
+            })
+            .collect();
+        ffi::sqlite3_finalize(stmt);
+
+        Ok(columns)
+    }
+}
+
+/// Copy the C string returned by SQLite, which is `NULL` if not applicable.
+///
+/// # Safety
+///
+/// `s` must be null or a valid NUL-terminated string.
+unsafe fn text(s: *const c_char) -> Option<String> {
+    if s.is_null() {
+        return None;
+    }
+
+    Some(unsafe { CStr::from_ptr(s) }.to_string_lossy().into_owned())
+}
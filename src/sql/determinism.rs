@@ -0,0 +1,237 @@
+//! The seeded randomness and the frozen clock of the executions.
+//!
+//! SQLite reads the time from the VFS of the connection and its random
+//! numbers from a process-wide generator, so the connections are opened with
+//! a VFS whose clock is frozen per thread, and `random()` and `randomblob()`
+//! are replaced by functions drawing from a per-thread seeded generator.
+//! Each execution runs on a single worker thread within a [`Session`].
+
+use std::{
+    cell::RefCell,
+    ffi::{c_double, c_int, CStr},
+    sync::OnceLock,
+};
+
+use rusqlite::{ffi, functions::FunctionFlags, limits::Limit};
+use serde::{Deserialize, Serialize};
+
+/// The name of the VFS with the frozen clock.
+const VFS_NAME: &CStr = c"dbrunner";
+
+/// The Julian day number of the Unix epoch, in milliseconds.
+const UNIX_EPOCH_JULIAN_DAY_MS: i64 = 210_866_760_000_000;
+
+/// The non-deterministic inputs an execution read, which were replaced by
+/// the frozen clock and the seed of the query.
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Nondeterminism {
+    /// The clock was read, by `'now'` or `CURRENT_TIMESTAMP` for example.
+    pub clock: bool,
+
+    /// `random()` or `randomblob()` was called.
+    pub random: bool,
+}
+
+#[derive(Debug)]
+struct State {
+    clock_ms: i64,
+    rng: SplitMix64,
+    used: Nondeterminism,
+}
+
+thread_local! {
+    static STATE: RefCell<Option<State>> = const { RefCell::new(None) };
+}
+
+/// The frozen clock and the seeded randomness of the current thread, until
+/// dropped.
+#[derive(Debug)]
+pub struct Session(());
+
+impl Session {
+    /// Freeze the clock at `clock_ms` milliseconds since the Unix epoch and
+    /// seed the randomness with `seed` on the current thread.
+    pub fn enter(seed: u64, clock_ms: i64) -> Self {
+        STATE.with_borrow_mut(|state| {
+            *state = Some(State {
+                clock_ms,
+                rng: SplitMix64(seed),
+                used: Nondeterminism::default(),
+            })
+        });
+
+        Self(())
+    }
+
+    /// The non-deterministic inputs read so far.
+    pub fn used(&self) -> Nondeterminism {
+        STATE.with_borrow(|state| state.as_ref().map(|s| s.used).unwrap_or_default())
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        STATE.with_borrow_mut(|state| *state = None);
+    }
+}
+
+/// The SplitMix64 generator, which is small and good enough for queries.
+#[derive(Debug)]
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+/// Draw from the generator of the session, or from an unseeded one outside
+/// of a session.
+fn with_rng<T>(f: impl FnOnce(&mut SplitMix64) -> T) -> T {
+    STATE.with_borrow_mut(|state| match state {
+        Some(state) => {
+            state.used.random = true;
+            f(&mut state.rng)
+        }
+        None => f(&mut SplitMix64(0)),
+    })
+}
+
+/// Open an in-memory database with the frozen clock.
+pub fn open_in_memory() -> Result<rusqlite::Connection, rusqlite::Error> {
+    register_vfs();
+
+    rusqlite::Connection::open_in_memory_with_flags_and_vfs(
+        rusqlite::OpenFlags::default(),
+        VFS_NAME.to_str().expect("VFS name is UTF-8"),
+    )
+}
+
+/// Replace the random functions of the connection by the seeded ones.
+///
+/// The blobs of `randomblob()` are limited to the length limit of the
+/// connection, which should be set beforehand.
+pub fn install(conn: &rusqlite::Connection) -> Result<(), rusqlite::Error> {
+    let flags = FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_INNOCUOUS;
+    let max_length = i64::from(conn.limit(Limit::SQLITE_LIMIT_LENGTH));
+
+    conn.create_scalar_function("random", 0, flags, |_| {
+        Ok(with_rng(|rng| rng.next() as i64))
+    })?;
+    conn.create_scalar_function("randomblob", 1, flags, move |ctx| {
+        let len = ctx.get::<Option<i64>>(0).ok().flatten().unwrap_or(1).max(1);
+        if len > max_length {
+            return Err(rusqlite::Error::SqliteFailure(
+                ffi::Error::new(ffi::SQLITE_TOOBIG),
+                None,
+            ));
+        }
+        let len = len as usize;
+
+        Ok(with_rng(|rng| {
+            let mut blob = Vec::with_capacity(len + 8);
+            while blob.len() < len {
+                blob.extend_from_slice(&rng.next().to_le_bytes());
+            }
+            blob.truncate(len);
+            blob
+        }))
+    })?;
+
+    Ok(())
+}
+
+/// The clock of the session in milliseconds since the Julian epoch, marking
+/// it as read.
+fn frozen_clock() -> Option<i64> {
+    STATE.with_borrow_mut(|state| {
+        let state = state.as_mut()?;
+        state.used.clock = true;
+        Some(state.clock_ms + UNIX_EPOCH_JULIAN_DAY_MS)
+    })
+}
+
+/// The VFS the frozen one is derived from.
+fn default_vfs() -> &'static ffi::sqlite3_vfs {
+    static DEFAULT_VFS: OnceLock<usize> = OnceLock::new();
+
+    // SAFETY: the registered VFSes live until the process exits.
+    let ptr =
+        *DEFAULT_VFS.get_or_init(|| unsafe { ffi::sqlite3_vfs_find(std::ptr::null()) } as usize);
+    unsafe { &*(ptr as *const ffi::sqlite3_vfs) }
+}
+
+unsafe extern "C" fn current_time_int64(_vfs: *mut ffi::sqlite3_vfs, out: *mut i64) -> c_int {
+    if let Some(now) = frozen_clock() {
+        // SAFETY: SQLite passes a valid pointer to the output.
+        unsafe { *out = now };
+        return ffi::SQLITE_OK;
+    }
+
+    let default = default_vfs();
+    match default.xCurrentTimeInt64 {
+        // SAFETY: forwarded as is to the VFS we are derived from.
+        Some(f) => unsafe { f(default as *const _ as *mut _, out) },
+        None => ffi::SQLITE_ERROR,
+    }
+}
+
+unsafe extern "C" fn current_time(vfs: *mut ffi::sqlite3_vfs, out: *mut c_double) -> c_int {
+    let mut now = 0;
+    // SAFETY: `now` outlives the call.
+    let rc = unsafe { current_time_int64(vfs, &mut now) };
+    unsafe { *out = now as c_double / 86_400_000.0 };
+    rc
+}
+
+/// Register the VFS with the frozen clock, once.
+fn register_vfs() {
+    static REGISTERED: OnceLock<()> = OnceLock::new();
+
+    REGISTERED.get_or_init(|| {
+        let mut vfs = *default_vfs();
+        vfs.zName = VFS_NAME.as_ptr();
+        vfs.pNext = std::ptr::null_mut();
+        vfs.xCurrentTime = Some(current_time);
+        vfs.xCurrentTimeInt64 = Some(current_time_int64);
+
+        // SAFETY: the VFS is leaked so it outlives every connection.
+        let rc = unsafe { ffi::sqlite3_vfs_register(Box::leak(Box::new(vfs)), 0) };
+        assert_eq!(rc, ffi::SQLITE_OK, "register the frozen clock VFS");
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_seeded_random() {
+        let draw = |seed| {
+            let session = Session::enter(seed, 0);
+            let values = (0..3)
+                .map(|_| with_rng(|rng| rng.next()))
+                .collect::<Vec<_>>();
+            (values, session.used())
+        };
+
+        let (a1, used) = draw(1);
+        let (a2, _) = draw(1);
+        let (b, _) = draw(2);
+        assert_eq!(a1, a2);
+        assert_ne!(a1, b);
+        assert_eq!(
+            used,
+            Nondeterminism {
+                clock: false,
+                random: true
+            }
+        );
+    }
+}
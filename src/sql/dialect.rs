@@ -0,0 +1,572 @@
+//! The SQL dialects the queries may be written in, translated to SQLite.
+//!
+//! The queries are parsed with the parser of their dialect, and the parts of
+//! the syntax tree SQLite does not understand are rewritten into their
+//! SQLite equivalent before the tree is formatted back into SQL. The
+//! constructs without an equivalent are reported rather than left for
+//! SQLite to reject with a confusing message.
+//!
+//! The constructs SQLite already accepts, such as the MySQL backquoted
+//! identifiers, `IFNULL()` or `LIMIT ... OFFSET`, are kept as they are.
+
+use std::{fmt, mem, ops::ControlFlow};
+
+use sql_insight::sqlparser::{
+    ast::{
+        BinaryOperator, ColumnOption, DataType, DateTimeField, Distinct, ExactNumberInfo, Expr,
+        Function, FunctionArg, FunctionArgExpr, Ident, ObjectName, OffsetRows, OnInsert, Query,
+        SetExpr, Statement, TableConstraint, Value, VisitMut, VisitorMut,
+    },
+    dialect::{self, MySqlDialect, PostgreSqlDialect, SQLiteDialect},
+    keywords::Keyword,
+    tokenizer::Token,
+};
+
+use super::Error;
+
+/// The SQL dialect of a query.
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq)]
+pub enum Dialect {
+    /// SQLite, run as is.
+    #[default]
+    Sqlite,
+
+    /// PostgreSQL, translated to SQLite.
+    Postgresql,
+
+    /// MySQL, translated to SQLite.
+    Mysql,
+}
+
+impl Dialect {
+    /// The dialect of the parser.
+    pub fn parser_dialect(self) -> Box<dyn dialect::Dialect> {
+        match self {
+            Dialect::Sqlite => Box::new(SQLiteDialect {}),
+            Dialect::Postgresql => Box::new(PostgreSqlDialect {}),
+            Dialect::Mysql => Box::new(MySqlDialect {}),
+        }
+    }
+}
+
+impl fmt::Display for Dialect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Dialect::Sqlite => "SQLite",
+            Dialect::Postgresql => "PostgreSQL",
+            Dialect::Mysql => "MySQL",
+        })
+    }
+}
+
+/// Translate the statements parsed with the dialect to SQLite, in place.
+pub fn translate(dialect: Dialect, statements: &mut [Statement]) -> Result<(), Error> {
+    if dialect == Dialect::Sqlite {
+        return Ok(());
+    }
+
+    let mut translator = Translator { dialect };
+    for statement in statements {
+        if let ControlFlow::Break(e) = statement.visit(&mut translator) {
+            return Err(e);
+        }
+    }
+
+    Ok(())
+}
+
+struct Translator {
+    dialect: Dialect,
+}
+
+impl Translator {
+    /// Stop the translation at the construct SQLite has no equivalent for.
+    fn untranslatable<T>(&self, construct: impl Into<String>) -> ControlFlow<Error, T> {
+        ControlFlow::Break(Error::Untranslatable {
+            dialect: self.dialect,
+            construct: construct.into(),
+        })
+    }
+
+    /// `CAST(value AS data_type)`, with the types SQLite has an affinity or
+    /// a function for.
+    fn cast(&self, value: Expr, data_type: &DataType) -> ControlFlow<Error, Expr> {
+        let cast = |data_type| Expr::Cast {
+            expr: Box::new(value.clone()),
+            data_type,
+            format: None,
+        };
+
+        // The type names are matched by their first word, which covers
+        // their lengths and variants, such as `VARCHAR(10)` or `TIMESTAMP
+        // WITH TIME ZONE`.
+        let name = data_type.to_string().to_ascii_uppercase();
+        let name = name
+            .split(|c: char| !c.is_ascii_alphanumeric())
+            .next()
+            .unwrap_or_default();
+
+        ControlFlow::Continue(match name {
+            "INT" | "INTEGER" | "BIGINT" | "SMALLINT" | "TINYINT" | "MEDIUMINT" | "INT2"
+            | "INT4" | "INT8" | "SIGNED" | "UNSIGNED" => cast(DataType::Integer(None)),
+            "REAL" | "FLOAT" | "FLOAT4" | "FLOAT8" | "DOUBLE" => cast(DataType::Real),
+            "NUMERIC" | "DECIMAL" | "DEC" => cast(DataType::Numeric(ExactNumberInfo::None)),
+            "TEXT" | "CHAR" | "VARCHAR" | "CHARACTER" | "NCHAR" | "NVARCHAR" | "STRING" => {
+                cast(DataType::Text)
+            }
+            "BYTEA" | "BLOB" | "BINARY" | "VARBINARY" => cast(DataType::Blob(None)),
+            "DATE" => function("date", [value]),
+            "TIME" => function("time", [value]),
+            "TIMESTAMP" | "TIMESTAMPTZ" | "DATETIME" => function("datetime", [value]),
+            _ => return self.untranslatable(format!("casting to {data_type}")),
+        })
+    }
+
+    /// `EXTRACT(field FROM value)`, from the fields of `strftime()`.
+    fn extract(&self, field: &DateTimeField, value: Expr) -> ControlFlow<Error, Expr> {
+        let format = match field {
+            DateTimeField::Year => "%Y",
+            DateTimeField::Month => "%m",
+            DateTimeField::Day => "%d",
+            DateTimeField::Hour => "%H",
+            DateTimeField::Minute => "%M",
+            DateTimeField::Second => "%S",
+            DateTimeField::Dow | DateTimeField::DayOfWeek => "%w",
+            DateTimeField::Doy | DateTimeField::DayOfYear => "%j",
+            DateTimeField::Epoch => return ControlFlow::Continue(function("unixepoch", [value])),
+            _ => return self.untranslatable(format!("EXTRACT({field})")),
+        };
+
+        ControlFlow::Continue(Expr::Cast {
+            expr: Box::new(function("strftime", [string(format), value])),
+            data_type: DataType::Integer(None),
+            format: None,
+        })
+    }
+
+    /// `DATE_TRUNC(unit, value)`, from the modifiers and the formats of the
+    /// date and time functions.
+    ///
+    /// Returns [`None`] if the unit is not a literal, or is a quarter, which
+    /// is left to the native `date_trunc()` function.
+    fn date_trunc(&self, args: &[FunctionArg]) -> ControlFlow<Error, Option<Expr>> {
+        let [FunctionArg::Unnamed(FunctionArgExpr::Expr(Expr::Value(Value::SingleQuotedString(
+            unit,
+        )))), FunctionArg::Unnamed(FunctionArgExpr::Expr(value))] = args
+        else {
+            return ControlFlow::Continue(None);
+        };
+        let value = value.clone();
+
+        ControlFlow::Continue(Some(match unit.to_ascii_lowercase().as_str() {
+            "year" => function("datetime", [value, string("start of year")]),
+            "month" => function("datetime", [value, string("start of month")]),
+            // The weeks start on Monday.
+            "week" => function(
+                "datetime",
+                [
+                    value,
+                    string("-6 days"),
+                    string("weekday 1"),
+                    string("start of day"),
+                ],
+            ),
+            "day" => function("datetime", [value, string("start of day")]),
+            "hour" => function("strftime", [string("%Y-%m-%d %H:00:00"), value]),
+            "minute" => function("strftime", [string("%Y-%m-%d %H:%M:00"), value]),
+            "second" => function("strftime", [string("%Y-%m-%d %H:%M:%S"), value]),
+            "quarter" => return ControlFlow::Continue(None),
+            _ => return self.untranslatable(format!("DATE_TRUNC by {unit}")),
+        }))
+    }
+
+    /// Make the `SERIAL` and `AUTO_INCREMENT` columns `INTEGER PRIMARY KEY`,
+    /// which SQLite fills with the next rowid, and drop the table options
+    /// of MySQL.
+    fn create_table(&self, statement: &mut Statement) -> ControlFlow<Error> {
+        let Statement::CreateTable {
+            columns,
+            constraints,
+            engine,
+            default_charset,
+            collation,
+            auto_increment_offset,
+            comment,
+            ..
+        } = statement
+        else {
+            return ControlFlow::Continue(());
+        };
+        *engine = None;
+        *default_charset = None;
+        *collation = None;
+        *auto_increment_offset = None;
+        *comment = None;
+
+        for column in columns {
+            let serial = matches!(
+                &column.data_type,
+                DataType::Custom(name, _) if matches!(
+                    name.to_string().to_ascii_uppercase().as_str(),
+                    "SERIAL" | "SMALLSERIAL" | "BIGSERIAL" | "SERIAL2" | "SERIAL4" | "SERIAL8"
+                )
+            );
+            let auto_increment = column.options.iter().any(|option| {
+                matches!(&option.option, ColumnOption::DialectSpecific(tokens) if tokens.iter().any(
+                    |t| matches!(t, Token::Word(w) if w.keyword == Keyword::AUTO_INCREMENT)
+                ))
+            });
+            if !serial && !auto_increment {
+                continue;
+            }
+
+            let is_primary_key = column.options.iter().any(|option| {
+                matches!(
+                    option.option,
+                    ColumnOption::Unique {
+                        is_primary: true,
+                        ..
+                    }
+                )
+            }) || constraints.iter().any(|constraint| {
+                matches!(
+                    constraint,
+                    TableConstraint::Unique { columns, is_primary: true, .. }
+                        if columns.as_slice() == [column.name.clone()]
+                )
+            });
+            if !is_primary_key {
+                let kind = if serial { "SERIAL" } else { "AUTO_INCREMENT" };
+                return self.untranslatable(format!(
+                    "the {kind} column {} that is not the primary key",
+                    column.name
+                ));
+            }
+
+            column.data_type = DataType::Integer(None);
+            column
+                .options
+                .retain(|option| !matches!(option.option, ColumnOption::DialectSpecific(_)));
+        }
+
+        ControlFlow::Continue(())
+    }
+}
+
+impl VisitorMut for Translator {
+    type Break = Error;
+
+    fn pre_visit_query(&mut self, query: &mut Query) -> ControlFlow<Self::Break> {
+        if has_distinct_on(&query.body) {
+            return self.untranslatable("DISTINCT ON");
+        }
+
+        // `FETCH FIRST n ROWS ONLY` is `LIMIT n`.
+        if let Some(fetch) = query.fetch.take() {
+            if fetch.percent || fetch.with_ties {
+                return self.untranslatable(format!("{fetch}"));
+            }
+            query.limit = Some(fetch.quantity.unwrap_or(number("1")));
+        }
+
+        // SQLite only takes an OFFSET after a LIMIT.
+        if let Some(offset) = &mut query.offset {
+            offset.rows = OffsetRows::None;
+            query.limit.get_or_insert(number("-1"));
+        }
+
+        // The rows are never locked by another connection.
+        query.locks.clear();
+
+        ControlFlow::Continue(())
+    }
+
+    fn post_visit_statement(&mut self, statement: &mut Statement) -> ControlFlow<Self::Break> {
+        if let Statement::Insert {
+            on: Some(OnInsert::DuplicateKeyUpdate(_)),
+            ..
+        } = statement
+        {
+            return self.untranslatable("ON DUPLICATE KEY UPDATE");
+        }
+
+        self.create_table(statement)
+    }
+
+    fn post_visit_expr(&mut self, expr: &mut Expr) -> ControlFlow<Self::Break> {
+        // The children are translated already.
+        let translated = match expr {
+            // SQLite compares the ASCII letters of LIKE without their case
+            // by default, but not with the `case_sensitive_like` profile.
+            Expr::ILike {
+                negated,
+                expr,
+                pattern,
+                escape_char,
+            } => Expr::Like {
+                negated: *negated,
+                expr: Box::new(function("lower", [take(expr)])),
+                pattern: Box::new(function("lower", [take(pattern)])),
+                escape_char: *escape_char,
+            },
+            Expr::Cast {
+                expr, data_type, ..
+            }
+            | Expr::TryCast {
+                expr, data_type, ..
+            } => self.cast(take(expr), data_type)?,
+            Expr::TypedString { data_type, value } => self.cast(
+                Expr::Value(Value::SingleQuotedString(mem::take(value))),
+                data_type,
+            )?,
+            Expr::Extract { field, expr } => self.extract(field, take(expr))?,
+            Expr::Function(f) if is_function(f, "date_trunc") => match self.date_trunc(&f.args)? {
+                Some(translated) => translated,
+                None => return ControlFlow::Continue(()),
+            },
+            Expr::Function(f) if is_function(f, "now") && f.args.is_empty() => {
+                function("datetime", [string("now")])
+            }
+            Expr::BinaryOp {
+                left,
+                op: op @ (BinaryOperator::PGRegexMatch | BinaryOperator::PGRegexNotMatch),
+                right,
+            } => Expr::RLike {
+                negated: *op == BinaryOperator::PGRegexNotMatch,
+                expr: Box::new(take(left)),
+                pattern: Box::new(take(right)),
+                regexp: true,
+            },
+            Expr::RLike { regexp, .. } if !*regexp => {
+                *regexp = true;
+                return ControlFlow::Continue(());
+            }
+            // MySQL quotes the strings with double quotes as well.
+            Expr::Value(Value::DoubleQuotedString(value)) if self.dialect == Dialect::Mysql => {
+                Expr::Value(Value::SingleQuotedString(mem::take(value)))
+            }
+            // `a <=> b` of MySQL compares the NULLs as equal.
+            Expr::BinaryOp {
+                left,
+                op: BinaryOperator::Spaceship,
+                right,
+            } => Expr::IsNotDistinctFrom(Box::new(take(left)), Box::new(take(right))),
+            Expr::BinaryOp { op, .. } if !is_sqlite_operator(op) => {
+                return self.untranslatable(format!("the {op} operator"));
+            }
+            Expr::JsonAccess { .. } => return self.untranslatable("JSON operators"),
+            Expr::SimilarTo { .. } => return self.untranslatable("SIMILAR TO"),
+            Expr::AnyOp { .. } | Expr::AllOp { .. } => {
+                return self.untranslatable("ANY and ALL comparisons");
+            }
+            Expr::Array(_) | Expr::ArrayIndex { .. } => return self.untranslatable("arrays"),
+            Expr::AtTimeZone { .. } => return self.untranslatable("AT TIME ZONE"),
+            _ => return ControlFlow::Continue(()),
+        };
+
+        *expr = translated;
+        ControlFlow::Continue(())
+    }
+}
+
+/// Whether the SELECTs of the body use `DISTINCT ON`.
+fn has_distinct_on(body: &SetExpr) -> bool {
+    match body {
+        SetExpr::Select(select) => matches!(select.distinct, Some(Distinct::On(_))),
+        SetExpr::SetOperation { left, right, .. } => {
+            has_distinct_on(left) || has_distinct_on(right)
+        }
+        _ => false,
+    }
+}
+
+/// Whether SQLite has the binary operator, with the same meaning.
+fn is_sqlite_operator(op: &BinaryOperator) -> bool {
+    matches!(
+        op,
+        BinaryOperator::Plus
+            | BinaryOperator::Minus
+            | BinaryOperator::Multiply
+            | BinaryOperator::Divide
+            | BinaryOperator::Modulo
+            | BinaryOperator::StringConcat
+            | BinaryOperator::Gt
+            | BinaryOperator::Lt
+            | BinaryOperator::GtEq
+            | BinaryOperator::LtEq
+            | BinaryOperator::Eq
+            | BinaryOperator::NotEq
+            | BinaryOperator::And
+            | BinaryOperator::Or
+            | BinaryOperator::BitwiseOr
+            | BinaryOperator::BitwiseAnd
+            | BinaryOperator::PGBitwiseShiftLeft
+            | BinaryOperator::PGBitwiseShiftRight
+    )
+}
+
+/// Whether the function is the one named, whatever its case.
+fn is_function(f: &Function, name: &str) -> bool {
+    matches!(f.name.0.as_slice(), [ident] if ident.value.eq_ignore_ascii_case(name))
+}
+
+/// Take the expression out of the tree, leaving `NULL` in its place.
+fn take(expr: &mut Expr) -> Expr {
+    mem::replace(expr, Expr::Value(Value::Null))
+}
+
+/// The call of the function with the arguments.
+fn function(name: &str, args: impl IntoIterator<Item = Expr>) -> Expr {
+    Expr::Function(Function {
+        name: ObjectName(vec![Ident::new(name)]),
+        args: args
+            .into_iter()
+            .map(|arg| FunctionArg::Unnamed(FunctionArgExpr::Expr(arg)))
+            .collect(),
+        filter: None,
+        null_treatment: None,
+        over: None,
+        distinct: false,
+        special: false,
+        order_by: Vec::new(),
+    })
+}
+
+/// The string literal.
+fn string(value: &str) -> Expr {
+    Expr::Value(Value::SingleQuotedString(value.to_string()))
+}
+
+/// The number literal.
+fn number(value: &str) -> Expr {
+    Expr::Value(Value::Number(value.to_string(), false))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sql::fmt::format_sql;
+    use rstest::*;
+
+    #[rstest]
+    #[case(
+        Dialect::Postgresql,
+        "SELECT * FROM t WHERE name ILIKE 'a%' AND note NOT ILIKE '%x'",
+        "SELECT * FROM t WHERE lower(name) LIKE lower('a%') AND lower(note) NOT LIKE lower('%x')"
+    )]
+    #[case(
+        Dialect::Postgresql,
+        "SELECT '1'::int, price::numeric(10, 2), name::varchar(5), '2024-01-02'::date",
+        "SELECT CAST('1' AS INTEGER), CAST(price AS NUMERIC), CAST(name AS TEXT), date('2024-01-02')"
+    )]
+    #[case(
+        Dialect::Postgresql,
+        "SELECT TIMESTAMP '2024-01-02 03:04:05', CAST(x AS double precision)",
+        "SELECT datetime('2024-01-02 03:04:05'), CAST(x AS REAL)"
+    )]
+    #[case(
+        Dialect::Postgresql,
+        "SELECT DATE_TRUNC('month', created_at), date_trunc('hour', created_at) FROM t",
+        "SELECT datetime(created_at, 'start of month'), strftime('%Y-%m-%d %H:00:00', created_at) FROM t"
+    )]
+    #[case(
+        Dialect::Postgresql,
+        "SELECT DATE_TRUNC(unit, d) FROM t",
+        "SELECT DATE_TRUNC(unit, d) FROM t"
+    )]
+    #[case(
+        Dialect::Postgresql,
+        "SELECT DATE_TRUNC('QUARTER', d) FROM t",
+        "SELECT DATE_TRUNC('QUARTER', d) FROM t"
+    )]
+    #[case(
+        Dialect::Postgresql,
+        "SELECT EXTRACT(YEAR FROM d), EXTRACT(EPOCH FROM d), NOW()",
+        "SELECT CAST(strftime('%Y', d) AS INTEGER), unixepoch(d), datetime('now')"
+    )]
+    #[case(
+        Dialect::Postgresql,
+        "SELECT * FROM t WHERE name ~ '^a' AND done = TRUE ORDER BY id LIMIT 10 OFFSET 20",
+        "SELECT * FROM t WHERE name REGEXP '^a' AND done = true ORDER BY id LIMIT 10 OFFSET 20"
+    )]
+    #[case(
+        Dialect::Postgresql,
+        "SELECT * FROM t OFFSET 5 ROWS FETCH FIRST 3 ROWS ONLY FOR UPDATE",
+        "SELECT * FROM t LIMIT 3 OFFSET 5"
+    )]
+    #[case(
+        Dialect::Postgresql,
+        "SELECT * FROM t OFFSET 5",
+        "SELECT * FROM t LIMIT -1 OFFSET 5"
+    )]
+    #[case(
+        Dialect::Postgresql,
+        "CREATE TABLE t (id SERIAL PRIMARY KEY, name TEXT)",
+        "CREATE TABLE t (id INTEGER PRIMARY KEY, name TEXT)"
+    )]
+    #[case(
+        Dialect::Mysql,
+        "CREATE TABLE `t` (`id` INT NOT NULL AUTO_INCREMENT, PRIMARY KEY (`id`)) ENGINE=InnoDB",
+        "CREATE TABLE `t` (`id` INTEGER NOT NULL, PRIMARY KEY (`id`))"
+    )]
+    #[case(
+        Dialect::Mysql,
+        "SELECT IFNULL(`name`, \"none\") FROM `t` WHERE `name` RLIKE 'a' AND a <=> b LIMIT 2, 3",
+        "SELECT IFNULL(`name`, 'none') FROM `t` WHERE `name` REGEXP 'a' AND a IS NOT DISTINCT FROM b LIMIT 3 OFFSET 2"
+    )]
+    #[case(
+        Dialect::Sqlite,
+        "SELECT \"name\" FROM t WHERE name LIKE 'a%'",
+        "SELECT \"name\" FROM t WHERE name LIKE 'a%'"
+    )]
+    fn test_translate(#[case] dialect: Dialect, #[case] input: &str, #[case] expected: &str) {
+        assert_eq!(
+            format_sql(input, dialect).expect("should translate"),
+            expected,
+            "Case {input}"
+        );
+    }
+
+    #[rstest]
+    #[case(
+        Dialect::Postgresql,
+        "SELECT DISTINCT ON (a) a, b FROM t",
+        "DISTINCT ON"
+    )]
+    #[case(
+        Dialect::Postgresql,
+        "SELECT DATE_TRUNC('decade', d) FROM t",
+        "DATE_TRUNC by decade"
+    )]
+    #[case(Dialect::Postgresql, "SELECT x::boolean FROM t", "casting to BOOLEAN")]
+    #[case(
+        Dialect::Postgresql,
+        "SELECT * FROM t WHERE a = ANY(b)",
+        "ANY and ALL comparisons"
+    )]
+    #[case(Dialect::Postgresql, "SELECT ARRAY[1, 2]", "arrays")]
+    #[case(Dialect::Postgresql, "SELECT a || b, a # b FROM t", "the # operator")]
+    #[case(Dialect::Postgresql, "SELECT data ->> 'name' FROM t", "JSON operators")]
+    #[case(Dialect::Mysql, "SELECT a XOR b FROM t", "the XOR operator")]
+    #[case(
+        Dialect::Postgresql,
+        "CREATE TABLE t (id SERIAL, name TEXT)",
+        "the SERIAL column id that is not the primary key"
+    )]
+    #[case(
+        Dialect::Mysql,
+        "INSERT INTO t VALUES (1) ON DUPLICATE KEY UPDATE n = n + 1",
+        "ON DUPLICATE KEY UPDATE"
+    )]
+    fn test_untranslatable(#[case] dialect: Dialect, #[case] input: &str, #[case] expected: &str) {
+        let Err(Error::Untranslatable {
+            dialect: reported,
+            construct,
+        }) = format_sql(input, dialect)
+        else {
+            panic!("Case {input}: expected an untranslatable construct");
+        };
+        assert_eq!(reported, dialect, "Case {input}");
+        assert_eq!(construct, expected, "Case {input}");
+    }
+}
@@ -1,25 +1,357 @@
-use tokio::task::JoinError;
+use std::time::Duration;
+
+use rusqlite::ErrorCode;
+use tokio::sync::oneshot::error::RecvError;
+
+use super::{
+    authorizer::ActionCode,
+    dialect::Dialect,
+    fixture::FixtureLine,
+    statements::{Position, StatementLocation},
+};
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("construct SQLite connection: {0}")]
     ConstructConnection(rusqlite::Error),
 
-    #[error("format SQL: {0}")]
-    Format(#[from] sql_insight::error::Error),
+    #[error("format SQL: {source}")]
+    Format {
+        source: sql_insight::error::Error,
+        position: Option<Position>,
+    },
+
+    #[error("{construct} cannot be translated from {dialect} to SQLite")]
+    Untranslatable { dialect: Dialect, construct: String },
+
+    #[error(
+        "execute initial SQL: statement {} at line {}, column {}: {source}",
+        statement.index + 1,
+        statement.line,
+        statement.column
+    )]
+    ExecuteInitialSql {
+        source: rusqlite::Error,
+        statement: Box<StatementLocation>,
+    },
 
-    #[error("execute initial SQL: {0}")]
-    ExecuteInitialSql(rusqlite::Error),
+    #[error(
+        "translate initial SQL: statement {} at line {}, column {}: {source}",
+        statement.index + 1,
+        statement.line,
+        statement.column
+    )]
+    TranslateInitialSql {
+        /// The [`Error::Format`], positioned in the initial SQL, or the
+        /// [`Error::Untranslatable`] error of the statement.
+        source: Box<Error>,
+        statement: Box<StatementLocation>,
+    },
 
-    #[error("execute query: {0}")]
-    ExecuteQuery(rusqlite::Error),
+    #[error(
+        "load fixtures{}: {source}",
+        line.as_ref().map(|line| format!(" at {line}")).unwrap_or_default()
+    )]
+    LoadFixture {
+        source: rusqlite::Error,
+        line: Option<FixtureLine>,
+    },
+
+    #[error("invalid fixture at {line}: {detail}")]
+    InvalidFixture { line: FixtureLine, detail: String },
+
+    #[error("execute query: {source}")]
+    ExecuteQuery {
+        source: rusqlite::Error,
+        position: Option<Position>,
+    },
 
     #[error("query timed out")]
-    QueryTimedOut,
+    QueryTimedOut { phase: Phase },
+
+    #[error("execution cancelled by the caller")]
+    Cancelled,
+
+    #[error("query exceeded the budget of {limit} instructions")]
+    InstructionLimitExceeded { limit: u64, phase: Phase },
+
+    #[error("resource exhausted: {source}")]
+    ResourceExhausted {
+        source: rusqlite::Error,
+        phase: Phase,
+    },
+
+    #[error("forbidden {action}: {detail}")]
+    Forbidden {
+        action: ActionCode,
+        detail: String,
+        phase: Phase,
+    },
 
     #[error("retrieve result: {0}")]
-    RetrieveResult(#[from] JoinError),
+    RetrieveResult(#[from] RecvError),
+
+    #[error("execution queue is full, retry after {} ms", retry_after.as_millis())]
+    QueueFull { retry_after: Duration },
 
     #[error("transform query result: {0}")]
     TransformQueryResult(rusqlite::Error),
+
+    #[error("write not allowed in read-only mode: {statement}")]
+    WriteNotAllowed { statement: String },
+
+    #[error("unknown SQLite profile: {0:?}")]
+    UnknownSqliteProfile(String),
+}
+
+/// The step of an execution an error happened in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// Parsing and formatting the query.
+    Format,
+
+    /// Translating and running the initial SQL.
+    Schema,
+
+    /// Preparing and running the statements of the query, including
+    /// stepping through their rows.
+    Query,
+
+    /// Reading the values of the rows of the statements of the query.
+    Transform,
+}
+
+/// What went wrong, for the clients to handle the errors without parsing
+/// their message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Category {
+    /// The SQL cannot be parsed.
+    Syntax,
+
+    /// The query uses a construct of its dialect SQLite has no equivalent
+    /// for.
+    Unsupported,
+
+    /// A table, or view, does not exist.
+    NoSuchTable,
+
+    /// A column does not exist.
+    NoSuchColumn,
+
+    /// A function does not exist, or not with this number of arguments.
+    NoSuchFunction,
+
+    /// Any other error SQLite reports as `SQLITE_ERROR`.
+    Sql,
+
+    /// A constraint failed, such as `UNIQUE` or a foreign key.
+    Constraint,
+
+    /// A value does not have the type expected, such as a non-integer rowid.
+    DatatypeMismatch,
+
+    /// A parameter has no value.
+    Parameter,
+
+    /// The execution took longer than its timeout.
+    Timeout,
+
+    /// The caller gave up on the execution.
+    Cancelled,
+
+    /// The execution ran more instructions than its budget.
+    InstructionLimit,
+
+    /// The execution ran out of memory, storage or result size.
+    ResourceExhausted,
+
+    /// The authorizer denied an action.
+    Forbidden,
+
+    /// A statement writes in read-only mode.
+    ReadOnly,
+
+    /// The request cannot be served as is, such as with an unknown profile.
+    InvalidRequest,
+
+    /// The service failed, not the query.
+    Internal,
+}
+
+impl Error {
+    /// The step of the execution the error happened in, unless it is not
+    /// tied to one.
+    pub fn phase(&self) -> Option<Phase> {
+        match self {
+            Error::Format { .. } | Error::Untranslatable { .. } => Some(Phase::Format),
+            Error::ExecuteInitialSql { .. }
+            | Error::TranslateInitialSql { .. }
+            | Error::LoadFixture { .. }
+            | Error::InvalidFixture { .. } => Some(Phase::Schema),
+            Error::ExecuteQuery { .. } | Error::WriteNotAllowed { .. } => Some(Phase::Query),
+            Error::TransformQueryResult(_) => Some(Phase::Transform),
+            Error::QueryTimedOut { phase }
+            | Error::InstructionLimitExceeded { phase, .. }
+            | Error::ResourceExhausted { phase, .. }
+            | Error::Forbidden { phase, .. } => Some(*phase),
+            Error::ConstructConnection(_)
+            | Error::Cancelled
+            | Error::RetrieveResult(_)
+            | Error::QueueFull { .. }
+            | Error::UnknownSqliteProfile(_) => None,
+        }
+    }
+
+    /// What went wrong.
+    pub fn category(&self) -> Category {
+        match self {
+            Error::Format { .. } => Category::Syntax,
+            Error::Untranslatable { .. } => Category::Unsupported,
+            Error::TranslateInitialSql { source, .. } => source.category(),
+            Error::ExecuteInitialSql { source, .. }
+            | Error::LoadFixture { source, .. }
+            | Error::ExecuteQuery { source, .. }
+            | Error::TransformQueryResult(source) => sqlite_category(source),
+            Error::QueryTimedOut { .. } => Category::Timeout,
+            Error::Cancelled => Category::Cancelled,
+            Error::InstructionLimitExceeded { .. } => Category::InstructionLimit,
+            Error::ResourceExhausted { .. } | Error::QueueFull { .. } => {
+                Category::ResourceExhausted
+            }
+            Error::Forbidden { .. } => Category::Forbidden,
+            Error::WriteNotAllowed { .. } => Category::ReadOnly,
+            Error::UnknownSqliteProfile(_) | Error::InvalidFixture { .. } => {
+                Category::InvalidRequest
+            }
+            Error::ConstructConnection(_) | Error::RetrieveResult(_) => Category::Internal,
+        }
+    }
+
+    /// The SQLite result code of the error, if it comes from SQLite.
+    pub fn sqlite_error(&self) -> Option<&rusqlite::ffi::Error> {
+        match self {
+            Error::ConstructConnection(source)
+            | Error::ExecuteInitialSql { source, .. }
+            | Error::LoadFixture { source, .. }
+            | Error::ExecuteQuery { source, .. }
+            | Error::ResourceExhausted { source, .. }
+            | Error::TransformQueryResult(source) => sqlite_failure(source).map(|(e, _)| e),
+            _ => None,
+        }
+    }
+}
+
+/// The SQLite error and its message, including those of the statements
+/// failing to prepare, which rusqlite reports apart.
+fn sqlite_failure(e: &rusqlite::Error) -> Option<(&rusqlite::ffi::Error, &str)> {
+    match e {
+        rusqlite::Error::SqliteFailure(error, message) => {
+            Some((error, message.as_deref().unwrap_or_default()))
+        }
+        rusqlite::Error::SqlInputError { error, msg, .. } => Some((error, msg)),
+        _ => None,
+    }
+}
+
+/// The category of the error of SQLite.
+///
+/// SQLite reports most errors of the SQL itself as `SQLITE_ERROR`, which
+/// are told apart by their message.
+fn sqlite_category(e: &rusqlite::Error) -> Category {
+    if let rusqlite::Error::InvalidParameterName(_) | rusqlite::Error::InvalidParameterCount(..) = e
+    {
+        return Category::Parameter;
+    }
+    let Some((error, message)) = sqlite_failure(e) else {
+        return Category::Internal;
+    };
+
+    match error.code {
+        ErrorCode::Unknown => {
+            if message.starts_with("no such table") {
+                Category::NoSuchTable
+            } else if message.starts_with("no such column") {
+                Category::NoSuchColumn
+            } else if message.starts_with("no such function")
+                || message.starts_with("wrong number of arguments to function")
+            {
+                Category::NoSuchFunction
+            } else if message.contains("syntax error")
+                || message.starts_with("incomplete input")
+                || message.starts_with("unrecognized token")
+            {
+                Category::Syntax
+            } else {
+                Category::Sql
+            }
+        }
+        ErrorCode::ConstraintViolation => Category::Constraint,
+        ErrorCode::TypeMismatch => Category::DatatypeMismatch,
+        ErrorCode::ParameterOutOfRange => Category::Parameter,
+        ErrorCode::ReadOnly => Category::ReadOnly,
+        ErrorCode::AuthorizationForStatementDenied => Category::Forbidden,
+        ErrorCode::DiskFull | ErrorCode::OutOfMemory | ErrorCode::TooBig => {
+            Category::ResourceExhausted
+        }
+        _ => Category::Internal,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::*;
+
+    #[rstest]
+    #[case("SELEC 1", Category::Syntax)]
+    #[case("SELECT * FROM t WHERE", Category::Syntax)]
+    #[case("SELECT * FROM missing", Category::NoSuchTable)]
+    #[case("SELECT missing FROM t", Category::NoSuchColumn)]
+    #[case("SELECT missing(1)", Category::NoSuchFunction)]
+    #[case("SELECT abs(1, 2)", Category::NoSuchFunction)]
+    #[case("SELECT id FROM t UNION SELECT 1, 2", Category::Sql)]
+    #[case("INSERT INTO t VALUES (1, 'b')", Category::Constraint)]
+    #[case("INSERT INTO t VALUES ('a', 'b')", Category::DatatypeMismatch)]
+    fn test_sqlite_category(#[case] sql: &str, #[case] expected: Category) {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE t (id INTEGER PRIMARY KEY, v TEXT); INSERT INTO t VALUES (1, 'a');",
+        )
+        .unwrap();
+
+        let e = conn.execute_batch(sql).unwrap_err();
+        assert_eq!(sqlite_category(&e), expected, "Case {sql}: {e}");
+    }
+
+    #[test]
+    fn test_error_details() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        let source = conn
+            .execute_batch(
+                "CREATE TABLE t (id INTEGER PRIMARY KEY); INSERT INTO t VALUES (1), (1);",
+            )
+            .unwrap_err();
+
+        let e = Error::ExecuteQuery {
+            source,
+            position: None,
+        };
+        assert_eq!(e.category(), Category::Constraint);
+        assert_eq!(e.phase(), Some(Phase::Query));
+        assert_eq!(
+            e.sqlite_error().map(|e| e.extended_code),
+            Some(rusqlite::ffi::SQLITE_CONSTRAINT_PRIMARYKEY)
+        );
+
+        let e = Error::QueryTimedOut {
+            phase: Phase::Schema,
+        };
+        assert_eq!(e.category(), Category::Timeout);
+        assert_eq!(e.phase(), Some(Phase::Schema));
+        assert_eq!(e.sqlite_error(), None);
+
+        let e = Error::UnknownSqliteProfile("strict".to_string());
+        assert_eq!(e.category(), Category::InvalidRequest);
+        assert_eq!(e.phase(), None);
+    }
 }
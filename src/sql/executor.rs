@@ -1,76 +1,949 @@
-use std::time::Duration;
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicBool, AtomicU8, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
-use rusqlite::types::Value;
+use tokio::sync::{oneshot, Notify};
 
-use super::{Error, Query, QueryResponse};
+use rusqlite::{
+    config::DbConfig,
+    ffi,
+    hooks::{AuthContext, Authorization},
+    limits::Limit,
+    types::Value,
+    ErrorCode, StatementStatus,
+};
 
-pub async fn execute_query(query: Query) -> Result<QueryResponse, Error> {
-    let formatted_query = query.format()?;
+use super::{
+    authorizer::{Authorizer, Denial, Policy},
+    column,
+    determinism::{self, Nondeterminism},
+    error::Phase,
+    fixture, fmt, functions, plan,
+    pool::{self, QueueFull, WorkerPool},
+    profile::{SqliteProfile, SqliteProfiles},
+    read_only,
+    snapshot::{self, Snapshot, SnapshotCache},
+    statements, uid, Cell, Dialect, Error, Parameters, Query, QueryPlan, QueryResponse, ResultSet,
+    StateCapture, Statistics,
+};
 
-    let handle = tokio::task::spawn_blocking(move || {
-        let conn = rusqlite::Connection::open_in_memory().map_err(Error::ConstructConnection)?;
-        conn.busy_timeout(Duration::from_secs(3))
+/// The number of VM instructions between two progress handler invocations.
+const PROGRESS_HANDLER_OPS: u64 = 1000;
+
+/// The extra time given to the worker to notice its own deadline before it
+/// is interrupted from the outside.
+const TIMEOUT_GRACE: Duration = Duration::from_secs(1);
+
+/// The limits applied to each execution.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// The wall-clock time an execution may take.
+    pub timeout: Duration,
+
+    /// The number of SQLite VM instructions the initial SQL and the query
+    /// may each run before being interrupted.
+    pub max_instructions: u64,
+
+    /// The size the main database may grow to, in bytes.
+    pub max_database_bytes: u64,
+
+    /// The size the temporary database may grow to, in bytes.
+    ///
+    /// Temporary tables and indices are kept in memory instead of spilling
+    /// to disk.
+    pub max_temp_bytes: u64,
+
+    /// The authorizer policy of the initial SQL.
+    pub schema_policy: Policy,
+
+    /// The authorizer policy of the query.
+    pub query_policy: Policy,
+
+    /// The number of threads running the executions.
+    pub workers: usize,
+
+    /// The number of executions that may wait for a free worker.
+    pub queue_size: usize,
+
+    /// The total size of the cached schema snapshots, in bytes.
+    pub snapshot_cache_bytes: usize,
+
+    /// The number of rows kept in all the result sets of the response.
+    pub max_rows: usize,
+
+    /// The SQLite configuration profiles the queries choose from by name.
+    pub sqlite_profiles: SqliteProfiles,
+
+    /// The total size of the cells kept in all the result sets of the
+    /// response, in bytes.
+    pub max_bytes: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(5),
+            max_instructions: 100_000_000,
+            max_database_bytes: 64 * 1024 * 1024,
+            max_temp_bytes: 64 * 1024 * 1024,
+            schema_policy: Policy::schema(),
+            query_policy: Policy::query(),
+            workers: std::thread::available_parallelism().map_or(4, |n| n.get()),
+            queue_size: 64,
+            snapshot_cache_bytes: 256 * 1024 * 1024,
+            max_rows: 10_000,
+            sqlite_profiles: SqliteProfiles::default(),
+            max_bytes: 8 * 1024 * 1024,
+        }
+    }
+}
+
+impl Config {
+    /// The heap limit of SQLite fitting what the executions the workers run
+    /// at once and the snapshot cache may hold: each execution up to its
+    /// database and its temporary database, to which its sorts are counted
+    /// too.
+    pub fn heap_limit(&self) -> u64 {
+        (self.workers.max(1) as u64)
+            .saturating_mul(self.max_database_bytes.saturating_add(self.max_temp_bytes))
+            .saturating_add(self.snapshot_cache_bytes as u64)
+    }
+}
+
+/// Set the heap limit of SQLite, in bytes.
+///
+/// Unlike the limits in [`Config`], this limit is shared by every connection
+/// in the process, so it should be set once on startup. `0` disables it.
+pub fn set_heap_limit(bytes: u64) {
+    // SAFETY: sqlite3_hard_heap_limit64 is thread-safe.
+    unsafe {
+        ffi::sqlite3_hard_heap_limit64(bytes.try_into().unwrap_or(i64::MAX));
+    }
+}
+
+/// Apply the memory limits in the configuration to the connection.
+fn limit_memory(conn: &rusqlite::Connection, config: &Config) -> Result<(), rusqlite::Error> {
+    let page_size: u64 = conn.pragma_query_value(None, "page_size", |row| row.get(0))?;
+
+    conn.pragma_update(None, "temp_store", "MEMORY")?;
+    conn.pragma_update(
+        None,
+        "max_page_count",
+        (config.max_database_bytes / page_size).max(1),
+    )?;
+    conn.pragma_update(
+        Some(rusqlite::DatabaseName::Temp),
+        "max_page_count",
+        (config.max_temp_bytes / page_size).max(1),
+    )?;
+    // A value may not be larger than the database it could be stored in.
+    conn.set_limit(
+        Limit::SQLITE_LIMIT_LENGTH,
+        config.max_database_bytes.min(i32::MAX as u64) as i32,
+    );
+
+    Ok(())
+}
+
+/// The reason why the watchdog interrupted an execution.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[repr(u8)]
+enum Interruption {
+    None = 0,
+    TimedOut = 1,
+    InstructionLimit = 2,
+    Cancelled = 3,
+}
+
+impl From<u8> for Interruption {
+    fn from(value: u8) -> Self {
+        match value {
+            1 => Interruption::TimedOut,
+            2 => Interruption::InstructionLimit,
+            3 => Interruption::Cancelled,
+            _ => Interruption::None,
+        }
+    }
+}
+
+/// Turn off the features that let SQL reach outside of its own database.
+///
+/// These still apply if a policy allows `ATTACH` or `VACUUM INTO`.
+fn restrict(conn: &rusqlite::Connection) -> Result<(), rusqlite::Error> {
+    conn.set_db_config(DbConfig::SQLITE_DBCONFIG_DEFENSIVE, true)?;
+    conn.set_db_config(DbConfig::SQLITE_DBCONFIG_TRUSTED_SCHEMA, false)?;
+    conn.set_limit(Limit::SQLITE_LIMIT_ATTACHED, 0);
+
+    Ok(())
+}
+
+/// Interrupts the running statement once the deadline passes, the
+/// instruction budget runs out or the caller gives up on the execution, and
+/// records why it did so.
+#[derive(Clone)]
+struct Watchdog {
+    deadline: Instant,
+    max_instructions: u64,
+    cancelled: Arc<AtomicBool>,
+    reason: Arc<AtomicU8>,
+}
+
+impl Watchdog {
+    fn new(config: &Config, cancelled: Arc<AtomicBool>) -> Self {
+        Self {
+            deadline: Instant::now() + config.timeout,
+            max_instructions: config.max_instructions,
+            cancelled,
+            reason: Arc::new(AtomicU8::new(Interruption::None as u8)),
+        }
+    }
+
+    /// Install the progress handler on the connection.
+    ///
+    /// The instruction budget starts over each time it is installed.
+    fn install(&self, conn: &rusqlite::Connection) {
+        let Watchdog {
+            deadline,
+            max_instructions,
+            cancelled,
+            reason,
+        } = self.clone();
+        let mut instructions = 0u64;
+
+        conn.progress_handler(
+            PROGRESS_HANDLER_OPS as _,
+            Some(move || {
+                instructions += PROGRESS_HANDLER_OPS;
+
+                let interruption = if cancelled.load(Ordering::Relaxed) {
+                    Interruption::Cancelled
+                } else if instructions > max_instructions {
+                    Interruption::InstructionLimit
+                } else if Instant::now() >= deadline {
+                    Interruption::TimedOut
+                } else {
+                    return false;
+                };
+
+                reason.store(interruption as u8, Ordering::Relaxed);
+                true
+            }),
+        );
+    }
+
+    /// The error describing why the execution was interrupted in the phase,
+    /// if it was.
+    fn interruption(&self, phase: Phase) -> Option<Error> {
+        match Interruption::from(self.reason.load(Ordering::Relaxed)) {
+            Interruption::TimedOut => Some(Error::QueryTimedOut { phase }),
+            Interruption::InstructionLimit => Some(Error::InstructionLimitExceeded {
+                limit: self.max_instructions,
+                phase,
+            }),
+            Interruption::Cancelled => Some(Error::Cancelled),
+            Interruption::None => None,
+        }
+    }
+}
+
+/// Cancels the execution when dropped, which interrupts it if it is still
+/// running.
+///
+/// The future awaiting the execution holds it, so that the execution stops
+/// when the future is dropped, such as when the gRPC client goes away or its
+/// deadline passes.
+struct CancelOnDrop(Arc<AtomicBool>);
+
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Shared by an execution and the future awaiting it, to interrupt it from
+/// the outside once it overruns its deadline.
+#[derive(Clone, Default)]
+struct Supervisor {
+    /// Set when the caller gives up on the execution.
+    cancelled: Arc<AtomicBool>,
+    /// Notified when a worker picks up the execution.
+    started: Arc<Notify>,
+    /// The connection of the execution and the phase it is in, once open.
+    conn: Arc<Mutex<Option<(rusqlite::InterruptHandle, Phase)>>>,
+}
+
+impl Supervisor {
+    /// Record the connection running the phase.
+    fn attach(&self, conn: &rusqlite::Connection, phase: Phase) {
+        *self.conn.lock().unwrap() = Some((conn.get_interrupt_handle(), phase));
+    }
+
+    /// Await the result of the job, interrupting it from the outside once
+    /// it overruns its deadline by [`TIMEOUT_GRACE`], such as when it is
+    /// stuck outside of the VM loop where the progress handler is not
+    /// called.
+    ///
+    /// Like the deadline of the job, the timeout starts when a worker picks
+    /// it up.
+    async fn supervise<T: Send + 'static>(
+        &self,
+        config: &Config,
+        pool: &WorkerPool,
+        mut job: oneshot::Receiver<Result<T, Error>>,
+    ) -> Result<T, Error> {
+        tokio::select! {
+            result = &mut job => return result?,
+            () = self.started.notified() => {}
+        }
+
+        match tokio::time::timeout(config.timeout + TIMEOUT_GRACE, &mut job).await {
+            Ok(result) => result?,
+            Err(_) => {
+                pool.time_out(job);
+                let phase = match &*self.conn.lock().unwrap() {
+                    Some((handle, phase)) => {
+                        handle.interrupt();
+                        *phase
+                    }
+                    None => Phase::Schema,
+                };
+                Err(Error::QueryTimedOut { phase })
+            }
+        }
+    }
+}
+
+/// Guards an execution with the [`Watchdog`] and the [`Authorizer`].
+#[derive(Clone)]
+struct Sandbox {
+    watchdog: Watchdog,
+    authorizer: Authorizer,
+    supervisor: Supervisor,
+    phase: std::cell::Cell<Phase>,
+}
+
+impl Sandbox {
+    fn new(config: &Config, supervisor: Supervisor) -> Self {
+        Self {
+            watchdog: Watchdog::new(config, supervisor.cancelled.clone()),
+            authorizer: Authorizer::default(),
+            supervisor,
+            phase: std::cell::Cell::new(Phase::Schema),
+        }
+    }
+
+    /// Install the guards on the connection with the given policy, for the
+    /// phase the errors are reported in.
+    fn install(&self, conn: &rusqlite::Connection, phase: Phase, policy: Policy) {
+        self.watchdog.install(conn);
+        self.authorizer.install(conn, policy);
+        self.supervisor.attach(conn, phase);
+        self.phase.set(phase);
+    }
+
+    /// Map the SQLite error to the reason why the sandbox stopped the
+    /// statement, if it did.
+    fn error(&self, e: rusqlite::Error, otherwise: impl FnOnce(rusqlite::Error) -> Error) -> Error {
+        let phase = self.phase.get();
+        if let Some(error) = self.watchdog.interruption(phase) {
+            return error;
+        }
+        if let Some(Denial { action, detail }) = self.authorizer.take_denial() {
+            return Error::Forbidden {
+                action,
+                detail,
+                phase,
+            };
+        }
+
+        match e.sqlite_error_code() {
+            Some(ErrorCode::DiskFull | ErrorCode::OutOfMemory | ErrorCode::TooBig) => {
+                Error::ResourceExhausted { source: e, phase }
+            }
+            _ => otherwise(e),
+        }
+    }
+}
+
+/// Run the initial SQL and load the fixtures, or restore the database they
+/// produced earlier.
+fn prepare_database(
+    conn: &mut rusqlite::Connection,
+    query: &Query,
+    profile: &SqliteProfile,
+    config: &Config,
+    sandbox: &Sandbox,
+    session: &determinism::Session,
+    snapshots: &SnapshotCache,
+) -> Result<(), Error> {
+    // The profiles change what the initial SQL produces, such as the rows
+    // deleted by a foreign key or the functions it may call.
+    let mut hasher = blake3::Hasher::new();
+    hasher.update(query.initial_sql.as_bytes());
+    hasher.update(format!("\x00{:?}", query.dialect).as_bytes());
+    uid::hash_fixtures(&mut hasher, &query.fixtures);
+    hasher.update(format!("\x00{profile:?}\x00{:?}", query.function_profile).as_bytes());
+    let schema_uid = hasher.finalize();
+
+    if let Some(snapshot) = snapshots.get(&schema_uid) {
+        snapshot
+            .restore(conn)
+            .and_then(|_| limit_memory(conn, config))
             .map_err(Error::ConstructConnection)?;
+        return Ok(());
+    }
+
+    // The statements are translated and run one by one to report the one
+    // failing as written.
+    sandbox.install(conn, Phase::Schema, config.schema_policy.clone());
+    for statement in statements::split(&query.initial_sql) {
+        let sql = match query.dialect {
+            Dialect::Sqlite => Cow::Borrowed(statement.text.as_str()),
+            dialect => Cow::Owned(fmt::format_statement(
+                &query.initial_sql,
+                &statement,
+                dialect,
+            )?),
+        };
+        conn.execute_batch(&profile.rewrite(&sql)).map_err(|e| {
+            sandbox.error(e, |source| Error::ExecuteInitialSql {
+                source,
+                statement: Box::new(statement),
+            })
+        })?;
+    }
+    fixture::load(conn, &query.fixtures).map_err(|e| match e {
+        Error::LoadFixture { source, line } => {
+            sandbox.error(source, |source| Error::LoadFixture { source, line })
+        }
+        e => e,
+    })?;
+
+    // The database depends on the seed and the clock if the initial SQL read
+    // them, so it cannot be reused by the other queries.
+    if session.used() != Nondeterminism::default() {
+        return Ok(());
+    }
 
-        // run the initial SQL
-        conn.execute_batch(&formatted_query.initial_sql)
-            .map_err(Error::ExecuteInitialSql)?;
+    // A snapshot is only an optimization; do not fail the execution for it.
+    if let Ok(Some(snapshot)) = Snapshot::take(conn, sandbox.authorizer.written_pragmas()) {
+        snapshots.insert(schema_uid, snapshot);
+    }
+
+    Ok(())
+}
+
+/// Open a connection guarded by the sandbox, with the database produced by
+/// the initial SQL of the query.
+fn open_database(
+    query: &Query,
+    profile: &SqliteProfile,
+    config: &Config,
+    sandbox: &Sandbox,
+    session: &determinism::Session,
+    snapshots: &SnapshotCache,
+) -> Result<rusqlite::Connection, Error> {
+    let mut conn = determinism::open_in_memory().map_err(Error::ConstructConnection)?;
+    conn.busy_timeout(Duration::from_secs(3))
+        .map_err(Error::ConstructConnection)?;
+    limit_memory(&conn, config).map_err(Error::ConstructConnection)?;
+    restrict(&conn).map_err(Error::ConstructConnection)?;
+    determinism::install(&conn).map_err(Error::ConstructConnection)?;
+    functions::install(&conn, query.function_profile).map_err(Error::ConstructConnection)?;
+    profile.apply(&conn).map_err(Error::ConstructConnection)?;
+
+    // run the initial SQL and load the fixtures
+    prepare_database(
+        &mut conn, query, profile, config, sandbox, session, snapshots,
+    )?;
+
+    if query.read_only {
+        // The pragma is set by us, not by the initial SQL under its policy.
+        conn.authorizer(None::<fn(AuthContext<'_>) -> Authorization>);
+        conn.pragma_update(None, "query_only", true)
+            .map_err(Error::ConstructConnection)?;
+    }
+
+    Ok(conn)
+}
+
+/// The error of running the query, not located yet.
+fn execute_query(source: rusqlite::Error) -> Error {
+    Error::ExecuteQuery {
+        source,
+        position: None,
+    }
+}
+
+/// Locate the error of running the statement at `index` of the query in the
+/// original query, which was formatted before running.
+///
+/// SQLite reports the offset of the token it failed at when preparing the
+/// statement, and the other errors are located at the statement.
+fn locate(e: Error, original_query: &str, index: usize) -> Error {
+    let Error::ExecuteQuery {
+        source,
+        position: None,
+    } = e
+    else {
+        return e;
+    };
+
+    let position = match &source {
+        rusqlite::Error::SqlInputError { sql, offset, .. } => {
+            statements::locate(original_query, index, sql, usize::try_from(*offset).ok())
+        }
+        _ => statements::locate(original_query, index, "", None),
+    };
+    Error::ExecuteQuery { source, position }
+}
+
+/// The rows and bytes the result sets of a query may still keep.
+struct Budget {
+    rows: usize,
+    bytes: usize,
+}
+
+/// Bind the values of the parameters of the statement.
+///
+/// Fails if a parameter has no value, rather than silently binding NULL.
+fn bind_parameters(
+    stmt: &mut rusqlite::Statement<'_>,
+    parameters: &Parameters,
+) -> Result<(), rusqlite::Error> {
+    for index in 1..=stmt.parameter_count() {
+        let name = stmt.parameter_name(index);
+        let Some(value) = parameters.get(index, name) else {
+            let name = name.map_or_else(|| format!("?{index}"), String::from);
+            return Err(rusqlite::Error::InvalidParameterName(name));
+        };
+        stmt.raw_bind_parameter(index, value)?;
+    }
+
+    Ok(())
+}
+
+/// Run the statement and collect its result set within the budget.
+fn run_statement(
+    conn: &rusqlite::Connection,
+    stmt: &mut rusqlite::Statement<'_>,
+    parameters: &Parameters,
+    sandbox: &Sandbox,
+    budget: &mut Budget,
+) -> Result<ResultSet, Error> {
+    let header = stmt
+        .column_names()
+        .into_iter()
+        .map(String::from)
+        .collect::<Vec<String>>();
+    let column_count = stmt.column_count();
+    let columns = match stmt.expanded_sql() {
+        Some(sql) if column_count > 0 => {
+            column::describe(conn, &sql).map_err(|e| sandbox.error(e, execute_query))?
+        }
+        _ => Vec::new(),
+    };
+    let changes_before = conn.total_changes();
+
+    let mut rows = Vec::new();
+    let mut total_rows = 0;
+    let mut truncated = false;
+
+    bind_parameters(stmt, parameters).map_err(execute_query)?;
+    let mut result = stmt.raw_query();
+    // Stepping runs the statement, so its errors, such as a constraint
+    // violation, are errors of the query.
+    while let Some(row) = result.next().map_err(|e| sandbox.error(e, execute_query))? {
+        total_rows += 1;
+
+        let mut row_data = Vec::with_capacity(column_count);
+        for i in 0..column_count {
+            let cell = row
+                .get::<_, Value>(i)
+                .map_err(|e| sandbox.error(e, Error::TransformQueryResult))?;
+            row_data.push(Cell::from(cell));
+        }
+
+        let row_bytes = row_data.iter().map(Cell::size).sum::<usize>();
+        // Stop stepping once truncated, so the rest of the rows are neither
+        // produced nor counted.
+        if budget.rows == 0 || row_bytes > budget.bytes {
+            truncated = true;
+            break;
+        }
+
+        budget.rows -= 1;
+        budget.bytes -= row_bytes;
+        rows.push(row_data);
+    }
+    drop(result);
+
+    Ok(ResultSet {
+        header,
+        columns,
+        rows,
+        truncated,
+        total_rows,
+        affected_rows: conn.total_changes() - changes_before,
+        ..Default::default()
+    })
+}
+
+/// Quote the identifier for SQL.
+pub(super) fn quote_identifier(name: &str) -> String {
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+/// Capture the contents of the tables after the query, within the budget.
+///
+/// The rows are sorted by all their columns, so the same contents are
+/// captured whatever the order the rows were written in.
+fn capture_state(
+    conn: &rusqlite::Connection,
+    capture: &StateCapture,
+    sandbox: &Sandbox,
+    budget: &mut Budget,
+) -> Result<BTreeMap<String, ResultSet>, Error> {
+    let mut tables = capture
+        .tables
+        .iter()
+        .map(|table| (table.clone(), quote_identifier(table)))
+        .collect::<BTreeMap<_, _>>();
+
+    if capture.written_tables {
+        for (database, table) in sandbox.authorizer.written_tables() {
+            // Skip the schema tables, and the tables dropped by the query.
+            let schema = format!("{}.sqlite_schema", quote_identifier(&database));
+            let exists = !table.starts_with("sqlite_")
+                && conn
+                    .query_row(
+                        &format!("SELECT count(*) FROM {schema} WHERE type = 'table' AND name = ?"),
+                        [&table],
+                        |row| row.get::<_, i64>(0),
+                    )
+                    .map_err(|e| sandbox.error(e, execute_query))?
+                    > 0;
+            if !exists {
+                continue;
+            }
+
+            let name = match database.as_str() {
+                "main" => table.clone(),
+                _ => format!("{database}.{table}"),
+            };
+            let source = format!(
+                "{}.{}",
+                quote_identifier(&database),
+                quote_identifier(&table)
+            );
+            tables.insert(name, source);
+        }
+    }
+
+    let mut state = BTreeMap::new();
+    for (name, source) in tables {
+        let column_count = conn
+            .prepare(&format!("SELECT * FROM {source}"))
+            .map_err(|e| sandbox.error(e, execute_query))?
+            .column_count();
+        let order = (1..=column_count)
+            .map(|i| i.to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
 
-        // run the query
         let mut stmt = conn
-            .prepare(&formatted_query.query)
-            .map_err(Error::ExecuteQuery)?;
-        let column_count = stmt.column_count();
-        let rows = stmt
-            .query_map((), |row| {
-                let mut row_data = Vec::with_capacity(column_count);
-                for i in 0..column_count {
-                    let cell = row.get::<_, Value>(i)?;
-                    match cell {
-                        Value::Null => row_data.push(None),
-                        Value::Integer(i) => row_data.push(Some(i.to_string())),
-                        Value::Real(f) => row_data.push(Some(f.to_string())),
-                        Value::Text(s) => row_data.push(Some(s)),
-                        Value::Blob(b) => {
-                            row_data.push(Some(String::from_utf8_lossy(&b).to_string()))
-                        }
-                    }
+            .prepare(&format!("SELECT * FROM {source} ORDER BY {order}"))
+            .map_err(|e| sandbox.error(e, execute_query))?;
+        let contents = run_statement(conn, &mut stmt, &Parameters::default(), sandbox, budget)?;
+        state.insert(name, contents);
+    }
+
+    Ok(state)
+}
+
+/// Add the cost of the statement which produced the result set.
+fn count_statement(
+    statistics: &mut Statistics,
+    stmt: &rusqlite::Statement<'_>,
+    result_set: &ResultSet,
+) {
+    // The counters are unsigned in SQLite.
+    let counter = |status| u64::from(stmt.get_status(status) as u32);
+
+    statistics.vm_steps += counter(StatementStatus::VmStep);
+    statistics.fullscan_steps += counter(StatementStatus::FullscanStep);
+    statistics.sorts += counter(StatementStatus::Sort);
+    statistics.autoindexes += counter(StatementStatus::AutoIndex);
+    statistics.rows += result_set.total_rows;
+}
+
+/// A query formatted, checked and with its profile resolved, ready to be
+/// run.
+#[derive(Clone, Debug)]
+pub struct ResolvedQuery {
+    /// The query as sent, to locate its errors in.
+    original_query: String,
+
+    /// The query, formatted.
+    pub(super) query: Query,
+
+    /// The SQLite profile the query chose.
+    pub(super) profile: SqliteProfile,
+}
+
+/// Executes queries in fresh in-memory SQLite databases.
+#[derive(Debug)]
+pub struct Executor {
+    config: Config,
+    snapshots: Arc<SnapshotCache>,
+    pool: WorkerPool,
+}
+
+impl Executor {
+    pub fn new(config: Config) -> Self {
+        let snapshots = Arc::new(SnapshotCache::new(config.snapshot_cache_bytes));
+        let pool = WorkerPool::new(config.workers, config.queue_size);
+
+        Self {
+            config,
+            snapshots,
+            pool,
+        }
+    }
+
+    /// The queue depth and wait time of the worker pool.
+    pub fn pool_stats(&self) -> pool::Stats {
+        self.pool.stats()
+    }
+
+    /// The counters of the schema snapshot cache.
+    pub fn snapshot_stats(&self) -> snapshot::Stats {
+        self.snapshots.stats()
+    }
+
+    /// Format the query and resolve its profile, failing before it is
+    /// queued.
+    pub fn resolve(&self, query: Query) -> Result<ResolvedQuery, Error> {
+        let original_query = query.query.clone();
+        let formatted_query = query.format()?;
+        if formatted_query.read_only {
+            read_only::check(&formatted_query.query)?;
+        }
+
+        let profile = self
+            .config
+            .sqlite_profiles
+            .get(&formatted_query.sqlite_profile)
+            .ok_or_else(|| Error::UnknownSqliteProfile(formatted_query.sqlite_profile.clone()))?;
+
+        Ok(ResolvedQuery {
+            original_query,
+            query: formatted_query,
+            profile,
+        })
+    }
+
+    pub async fn execute_query(&self, query: Query) -> Result<QueryResponse, Error> {
+        self.execute_resolved(self.resolve(query)?).await
+    }
+
+    /// Execute the query resolved by [`Executor::resolve`].
+    pub async fn execute_resolved(&self, query: ResolvedQuery) -> Result<QueryResponse, Error> {
+        let ResolvedQuery {
+            original_query,
+            query: formatted_query,
+            profile,
+        } = query;
+        let config = self.config.clone();
+        let snapshots = self.snapshots.clone();
+        let supervisor = Supervisor::default();
+        let _cancel = CancelOnDrop(supervisor.cancelled.clone());
+        let job_supervisor = supervisor.clone();
+
+        let job = self.pool.submit(move || {
+            // The deadline starts when a worker picks up the job rather
+            // than when it was queued.
+            job_supervisor.started.notify_one();
+            let sandbox = Sandbox::new(&config, job_supervisor);
+            let session =
+                determinism::Session::enter(formatted_query.seed, formatted_query.clock_ms);
+            let conn = open_database(
+                &formatted_query,
+                &profile,
+                &config,
+                &sandbox,
+                &session,
+                &snapshots,
+            )?;
+
+            // run the query
+            sandbox.install(&conn, Phase::Query, config.query_policy.clone());
+            let mut budget = Budget {
+                rows: config.max_rows,
+                bytes: config.max_bytes,
+            };
+            let mut result_sets = Vec::new();
+            let mut statistics = Statistics::default();
+            let started_at = Instant::now();
+
+            // The counters are reported from the start of the query, since
+            // those of the initial SQL are lost when restoring a snapshot.
+            let total_changes_before = conn.total_changes();
+            // SAFETY: the handle is only used on this thread while `conn` is
+            // borrowed.
+            unsafe { ffi::sqlite3_set_last_insert_rowid(conn.handle(), 0) };
+
+            let sql = profile.rewrite(&formatted_query.query);
+            let mut batch = rusqlite::Batch::new(&conn, &sql);
+            while let Some(mut stmt) = batch.next().map_err(|e| {
+                locate(
+                    sandbox.error(e, execute_query),
+                    &original_query,
+                    result_sets.len(),
+                )
+            })? {
+                let mut result_set = run_statement(
+                    &conn,
+                    &mut stmt,
+                    &formatted_query.parameters,
+                    &sandbox,
+                    &mut budget,
+                )
+                .map_err(|e| locate(e, &original_query, result_sets.len()))?;
+                // Only the statements changing rows update `changes()`.
+                if result_set.affected_rows > 0 {
+                    result_set.changes = conn.changes();
                 }
-                Ok(row_data)
-            })
-            .map_err(Error::ExecuteQuery)?
-            .collect::<Result<Vec<Vec<Option<String>>>, rusqlite::Error>>()
-            .or_else(|e| match e {
-                rusqlite::Error::SqliteFailure(_, Some(error_message))
-                    if error_message == "not an error" =>
-                {
-                    Ok(Vec::new())
+                result_set.total_changes = conn.total_changes() - total_changes_before;
+                result_set.last_insert_rowid = conn.last_insert_rowid();
+                count_statement(&mut statistics, &stmt, &result_set);
+                result_sets.push(result_set);
+            }
+            statistics.wall_time = started_at.elapsed();
+
+            let state = capture_state(&conn, &formatted_query.capture, &sandbox, &mut budget)?;
+
+            Ok::<_, Error>(QueryResponse {
+                result_sets,
+                blob_rendering: formatted_query.blob_rendering,
+                nondeterminism: session.used(),
+                state,
+                captured: formatted_query.capture.is_requested(),
+                statistics,
+            })
+        });
+
+        let job = job.map_err(|QueueFull { retry_after }| Error::QueueFull { retry_after })?;
+        supervisor.supervise(&self.config, &self.pool, job).await
+    }
+
+    /// Explain the query plan of each statement in the query.
+    ///
+    /// The statements writing to the database are run after being explained,
+    /// so the plans of the next ones see their changes.
+    pub async fn explain_query_plan(&self, query: Query) -> Result<QueryPlan, Error> {
+        self.explain_resolved(self.resolve(query)?).await
+    }
+
+    /// Explain the query resolved by [`Executor::resolve`], as
+    /// [`Executor::explain_query_plan`] does.
+    pub async fn explain_resolved(&self, query: ResolvedQuery) -> Result<QueryPlan, Error> {
+        let ResolvedQuery {
+            original_query,
+            query: formatted_query,
+            profile,
+        } = query;
+        let config = self.config.clone();
+        let snapshots = self.snapshots.clone();
+        let supervisor = Supervisor::default();
+        let _cancel = CancelOnDrop(supervisor.cancelled.clone());
+        let job_supervisor = supervisor.clone();
+
+        let job = self.pool.submit(move || {
+            job_supervisor.started.notify_one();
+            let sandbox = Sandbox::new(&config, job_supervisor);
+            let session =
+                determinism::Session::enter(formatted_query.seed, formatted_query.clock_ms);
+            let conn = open_database(
+                &formatted_query,
+                &profile,
+                &config,
+                &sandbox,
+                &session,
+                &snapshots,
+            )?;
+
+            // explain the query
+            sandbox.install(&conn, Phase::Query, config.query_policy.clone());
+            let mut statements = Vec::new();
+
+            let sql = profile.rewrite(&formatted_query.query);
+            let mut batch = rusqlite::Batch::new(&conn, &sql);
+            while let Some(mut stmt) = batch.next().map_err(|e| {
+                locate(
+                    sandbox.error(e, execute_query),
+                    &original_query,
+                    statements.len(),
+                )
+            })? {
+                let located = |e| locate(e, &original_query, statements.len());
+
+                // The plan may depend on the values of the parameters, such
+                // as a LIKE pattern, so they are inlined in the explained SQL.
+                bind_parameters(&mut stmt, &formatted_query.parameters)
+                    .map_err(|e| located(execute_query(e)))?;
+                // SQLite fails to expand the SQL mostly when the values of
+                // the parameters make it exceed the length limit.
+                let sql = stmt.expanded_sql().ok_or_else(|| {
+                    let e = rusqlite::Error::SqliteFailure(
+                        ffi::Error::new(ffi::SQLITE_TOOBIG),
+                        Some("the expanded statement exceeds the length limit".to_string()),
+                    );
+                    located(sandbox.error(e, execute_query))
+                })?;
+                let plan = plan::explain(&conn, &sql)
+                    .map_err(|e| located(sandbox.error(e, execute_query)))?;
+
+                if !stmt.readonly() {
+                    let mut rows = stmt.raw_query();
+                    while rows
+                        .next()
+                        .map_err(|e| located(sandbox.error(e, execute_query)))?
+                        .is_some()
+                    {}
                 }
-                _ => Err(Error::TransformQueryResult(e)),
-            })?;
-        let header = stmt
-            .column_names()
-            .into_iter()
-            .map(String::from)
-            .collect::<Vec<String>>();
-
-        Ok::<_, Error>(QueryResponse { header, rows })
-    });
-    let timeout_result = tokio::time::timeout(Duration::from_secs(5), handle).await;
-
-    match timeout_result {
-        Err(_) => Err(Error::QueryTimedOut),
-        Ok(Err(e)) => Err(Error::RetrieveResult(e)),
-        Ok(Ok(Err(e))) => Err(e),
-        Ok(Ok(Ok(response))) => Ok(response),
+                statements.push(plan);
+            }
+
+            Ok::<_, Error>(QueryPlan { statements })
+        });
+
+        let job = job.map_err(|QueueFull { retry_after }| Error::QueueFull { retry_after })?;
+        supervisor.supervise(&self.config, &self.pool, job).await
+    }
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Self::new(Config::default())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::assert_matches::assert_matches;
+    use std::assert_matches;
 
     use super::*;
+    use crate::sql::{
+        authorizer::ActionCode, error::Category, statements::StatementLocation, ColumnMetadata,
+        Fixture, FixtureFormat, FunctionProfile, UidGetter,
+    };
 
     #[tokio::test]
     async fn test_with_valid_query() {
@@ -86,16 +959,25 @@ mod tests {
             "#
             .to_string(),
             query: "SELECT * FROM test;".to_string(),
+            ..Default::default()
         };
-        let response = execute_query(query).await.expect("no error");
-        assert_eq!(response.header, vec!["id", "name"]);
+        let response = Executor::default()
+            .execute_query(query)
+            .await
+            .expect("no error");
+        assert_eq!(response.result_sets[0].header, vec!["id", "name"]);
         assert_eq!(
-            response.rows,
+            response.result_sets[0].rows,
             vec![
-                vec![Some("1".to_string()), Some("Alice".to_string())],
-                vec![Some("2".to_string()), Some("Bob".to_string())]
+                vec![Cell::Integer(1), Cell::Text("Alice".to_string())],
+                vec![Cell::Integer(2), Cell::Text("Bob".to_string())]
             ]
         );
+        assert!(
+            !response.result_sets[0].truncated,
+            "response should not be truncated"
+        );
+        assert_eq!(response.result_sets[0].total_rows, 2);
     }
 
     #[tokio::test]
@@ -112,10 +994,13 @@ mod tests {
             "#
             .to_string(),
             query: "".to_string(),
+            ..Default::default()
         };
-        let response = execute_query(query).await.expect("no error");
-        assert_eq!(response.header.len(), 0, "header should be empty");
-        assert_eq!(response.rows.len(), 0, "rows should be empty");
+        let response = Executor::default()
+            .execute_query(query)
+            .await
+            .expect("no error");
+        assert_eq!(response.result_sets.len(), 0, "result sets should be empty");
     }
 
     #[tokio::test]
@@ -132,10 +1017,125 @@ mod tests {
             "#
             .to_string(),
             query: "UPDATE test SET name = 'Charlie' WHERE id = 1;".to_string(),
+            ..Default::default()
+        };
+        let response = Executor::default()
+            .execute_query(query)
+            .await
+            .expect("no error");
+        assert_eq!(
+            response.result_sets[0].header.len(),
+            0,
+            "header should be empty"
+        );
+        assert_eq!(
+            response.result_sets[0].rows.len(),
+            0,
+            "rows should be empty"
+        );
+        assert_eq!(response.result_sets[0].affected_rows, 1);
+    }
+
+    #[tokio::test]
+    async fn test_with_multiple_statements() {
+        let query = Query {
+            initial_sql: r#"
+                CREATE TABLE students (id INTEGER PRIMARY KEY, name TEXT);
+                CREATE TABLE teachers (id INTEGER PRIMARY KEY, name TEXT);
+
+                INSERT INTO students (name) VALUES ('Alice');
+                INSERT INTO teachers (name) VALUES ('Bob');
+            "#
+            .to_string(),
+            query: r#"
+                SELECT name FROM students;
+                INSERT INTO teachers (name) VALUES ('Charlie'), ('Dave');
+                SELECT name FROM teachers;
+            "#
+            .to_string(),
+            ..Default::default()
+        };
+
+        let response = Executor::default()
+            .execute_query(query)
+            .await
+            .expect("no error");
+        assert_eq!(response.result_sets.len(), 3);
+        assert_eq!(
+            response.result_sets[0].rows,
+            vec![vec![Cell::Text("Alice".to_string())]]
+        );
+        assert_eq!(response.result_sets[1].header.len(), 0);
+        assert_eq!(response.result_sets[1].affected_rows, 2);
+        assert_eq!(response.result_sets[2].total_rows, 3);
+        assert_eq!(response.result_sets[2].affected_rows, 0);
+    }
+
+    #[tokio::test]
+    async fn test_with_change_counters() {
+        let query = Query {
+            initial_sql: r#"
+                CREATE TABLE test (id INTEGER PRIMARY KEY, name TEXT);
+                CREATE TABLE log (name TEXT);
+                CREATE TRIGGER test_log AFTER DELETE ON test BEGIN
+                    INSERT INTO log VALUES (OLD.name);
+                END;
+
+                INSERT INTO test (name) VALUES ('Alice'), ('Bob');
+            "#
+            .to_string(),
+            query: r#"
+                SELECT * FROM test;
+                INSERT INTO test (name) VALUES ('Charlie'), ('Dave');
+                UPDATE test SET name = upper(name);
+                DELETE FROM test WHERE id = 1;
+                SELECT * FROM test;
+            "#
+            .to_string(),
+            ..Default::default()
         };
-        let response = execute_query(query).await.expect("no error");
-        assert_eq!(response.header.len(), 0, "header should be empty");
-        assert_eq!(response.rows.len(), 0, "rows should be empty");
+        let executor = Executor::default();
+
+        // The second execution restores the initial SQL from its snapshot.
+        for _ in 0..2 {
+            let response = executor
+                .execute_query(query.clone())
+                .await
+                .expect("no error");
+            let counters = response
+                .result_sets
+                .iter()
+                .map(|r| (r.changes, r.total_changes, r.last_insert_rowid))
+                .collect::<Vec<_>>();
+            assert_eq!(
+                counters,
+                vec![(0, 0, 0), (2, 2, 4), (4, 6, 4), (1, 8, 4), (0, 8, 4)],
+                "the rowid inserted by the trigger should not be reported"
+            );
+        }
+        assert_eq!(executor.snapshot_stats().hits, 1);
+    }
+
+    #[tokio::test]
+    async fn test_with_row_limit_across_statements() {
+        let query = Query {
+            initial_sql: "CREATE TABLE test (id INTEGER PRIMARY KEY);".to_string(),
+            query: "SELECT 1 UNION ALL SELECT 2; SELECT 3 UNION ALL SELECT 4;".to_string(),
+            ..Default::default()
+        };
+        let config = Config {
+            max_rows: 3,
+            ..Default::default()
+        };
+
+        let response = Executor::new(config)
+            .execute_query(query)
+            .await
+            .expect("no error");
+        assert!(!response.result_sets[0].truncated);
+        assert_eq!(response.result_sets[1].rows, vec![vec![Cell::Integer(3)]]);
+        assert!(response.result_sets[1].truncated);
+        assert_eq!(response.result_sets[1].total_rows, 2);
     }
 
     #[tokio::test]
@@ -151,18 +1151,618 @@ mod tests {
                 INSERT INTO test (name) VALUES ('Bob');
             "#
             .to_string(),
-            query: "UPDATE test SET name = 'Charlie' WHERE id = 1 RETURNING *;".to_string(),
+            query: "UPDATE test SET name = 'Charlie' WHERE id = 1 RETURNING *;".to_string(),
+            ..Default::default()
+        };
+        let response = Executor::default()
+            .execute_query(query)
+            .await
+            .expect("no error");
+        assert_eq!(response.result_sets[0].header, vec!["id", "name"]);
+        assert_eq!(
+            response.result_sets[0].rows,
+            vec![vec![Cell::Integer(1), Cell::Text("Charlie".to_string())]]
+        );
+    }
+
+    const DOS_QUERY: &str = r#"
+        WITH RECURSIVE cte (n) AS (
+            SELECT 1
+            UNION ALL
+            SELECT n + 1 FROM cte
+        )
+        SELECT count(*) FROM cte;
+    "#;
+
+    #[tokio::test]
+    async fn test_with_dos_query() {
+        let query = Query {
+            initial_sql: r#"
+                CREATE TABLE test (
+                    id INTEGER PRIMARY KEY,
+                    name TEXT
+                );
+
+                INSERT INTO test (name) VALUES ('Alice');
+                INSERT INTO test (name) VALUES ('Bob');
+            "#
+            .to_string(),
+            query: DOS_QUERY.to_string(),
+            ..Default::default()
+        };
+        let config = Config {
+            timeout: Duration::from_millis(500),
+            max_instructions: u64::MAX,
+            ..Default::default()
+        };
+
+        let started_at = Instant::now();
+        let response = Executor::new(config.clone()).execute_query(query).await;
+
+        assert_matches!(
+            response,
+            Err(Error::QueryTimedOut {
+                phase: Phase::Query
+            })
+        );
+        assert!(
+            started_at.elapsed() < config.timeout + Duration::from_secs(1),
+            "the query should be interrupted by the progress handler"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_with_stuck_execution() {
+        let config = Config {
+            timeout: Duration::from_millis(100),
+            ..Default::default()
+        };
+        let pool = WorkerPool::new(1, 1);
+        let supervisor = Supervisor::default();
+        let job_supervisor = supervisor.clone();
+
+        // Without the progress handler, only the outside can interrupt it.
+        let started_at = Instant::now();
+        let job = pool
+            .submit(move || {
+                job_supervisor.started.notify_one();
+                let conn = rusqlite::Connection::open_in_memory().unwrap();
+                job_supervisor.attach(&conn, Phase::Query);
+                conn.query_row(DOS_QUERY, [], |_| Ok(()))
+                    .map_err(execute_query)
+            })
+            .expect("submit");
+        let response = supervisor.supervise(&config, &pool, job).await;
+
+        assert_matches!(
+            response,
+            Err(Error::QueryTimedOut {
+                phase: Phase::Query
+            })
+        );
+        assert!(started_at.elapsed() < config.timeout + TIMEOUT_GRACE * 2);
+
+        let result = pool.submit(|| 1).expect("submit");
+        assert_eq!(result.await.expect("the worker should be freed"), 1);
+        let stats = pool.stats();
+        assert_eq!(
+            (stats.cancelled, stats.timed_out),
+            (0, 1),
+            "the overrunning job should not be counted as cancelled"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_with_cancelled_query() {
+        let query = Query {
+            query: DOS_QUERY.to_string(),
+            ..Default::default()
+        };
+        let executor = Executor::new(Config {
+            timeout: Duration::from_secs(60),
+            max_instructions: u64::MAX,
+            ..Default::default()
+        });
+
+        // The caller gives up on the execution, as when the client goes away.
+        let started_at = Instant::now();
+        let response =
+            tokio::time::timeout(Duration::from_millis(200), executor.execute_query(query)).await;
+        assert!(response.is_err(), "the query should still be running");
+
+        while executor.pool_stats().cancelled == 0 {
+            assert!(
+                started_at.elapsed() < Duration::from_secs(10),
+                "the query should be interrupted once cancelled"
+            );
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn test_with_instruction_limit() {
+        let query = Query {
+            initial_sql: "CREATE TABLE test (id INTEGER PRIMARY KEY);".to_string(),
+            query: DOS_QUERY.to_string(),
+            ..Default::default()
+        };
+        let config = Config {
+            timeout: Duration::from_secs(60),
+            max_instructions: 1_000_000,
+            ..Default::default()
+        };
+
+        let response = Executor::new(config.clone()).execute_query(query).await;
+        assert_matches!(
+            response,
+            Err(Error::InstructionLimitExceeded {
+                limit: 1_000_000,
+                phase: Phase::Query
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_with_instruction_limit_in_initial_sql() {
+        let query = Query {
+            initial_sql: format!("CREATE TABLE test AS {DOS_QUERY}"),
+            query: "SELECT * FROM test;".to_string(),
+            ..Default::default()
+        };
+        let config = Config {
+            timeout: Duration::from_secs(60),
+            max_instructions: 1_000_000,
+            ..Default::default()
+        };
+
+        let response = Executor::new(config.clone()).execute_query(query).await;
+        assert_matches!(
+            response,
+            Err(Error::InstructionLimitExceeded {
+                limit: 1_000_000,
+                phase: Phase::Schema
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_with_database_size_limit() {
+        let query = Query {
+            initial_sql: r#"
+                CREATE TABLE test (id INTEGER PRIMARY KEY, payload TEXT);
+
+                WITH RECURSIVE cte (n) AS (
+                    SELECT 1
+                    UNION ALL
+                    SELECT n + 1 FROM cte WHERE n < 10000
+                )
+                INSERT INTO test (payload) SELECT printf('%.1000c', 'x') FROM cte;
+            "#
+            .to_string(),
+            query: "SELECT COUNT(*) FROM test;".to_string(),
+            ..Default::default()
+        };
+        let config = Config {
+            max_database_bytes: 1024 * 1024,
+            ..Default::default()
+        };
+
+        let response = Executor::new(config.clone()).execute_query(query).await;
+        assert_matches!(response, Err(Error::ResourceExhausted { .. }));
+    }
+
+    #[tokio::test]
+    async fn test_with_temp_size_limit() {
+        let query = Query {
+            initial_sql: "CREATE TABLE test (id INTEGER PRIMARY KEY);".to_string(),
+            query: r#"
+                CREATE TEMP TABLE big AS
+                WITH RECURSIVE cte (n) AS (
+                    SELECT 1
+                    UNION ALL
+                    SELECT n + 1 FROM cte WHERE n < 10000
+                )
+                SELECT printf('%.1000c', 'x') AS payload FROM cte;
+            "#
+            .to_string(),
+            ..Default::default()
+        };
+        let config = Config {
+            max_temp_bytes: 1024 * 1024,
+            ..Default::default()
+        };
+
+        let response = Executor::new(config.clone()).execute_query(query).await;
+        assert_matches!(response, Err(Error::ResourceExhausted { .. }));
+    }
+
+    #[tokio::test]
+    async fn test_with_heap_limit() {
+        // The heap limit is shared by the process, so the test runs again
+        // alone in a process of its own to set it.
+        if std::env::var_os("DBRUNNER_TEST_HEAP_LIMIT").is_none() {
+            let status = std::process::Command::new(std::env::current_exe().unwrap())
+                .args([
+                    "--exact",
+                    "--quiet",
+                    "sql::executor::tests::test_with_heap_limit",
+                ])
+                .env("DBRUNNER_TEST_HEAP_LIMIT", "1")
+                .status()
+                .expect("run the test alone");
+            assert!(status.success(), "the test should pass alone");
+            return;
+        }
+        set_heap_limit(16 * 1024 * 1024);
+
+        // The sorts are kept in memory, beyond the size limits of the
+        // databases.
+        for query in [
+            "SELECT n FROM cte ORDER BY randomblob(1000)",
+            "SELECT printf('%.1000c', n), count(*) FROM cte GROUP BY 1",
+        ] {
+            let query = Query {
+                query: format!(
+                    r#"
+                    WITH RECURSIVE cte (n) AS (
+                        SELECT 1
+                        UNION ALL
+                        SELECT n + 1 FROM cte WHERE n < 100000
+                    )
+                    {query};
+                    "#
+                ),
+                ..Default::default()
+            };
+
+            let response = Executor::default().execute_query(query).await;
+            assert_matches!(
+                response,
+                Err(Error::ResourceExhausted {
+                    phase: Phase::Query,
+                    ..
+                })
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_with_row_limit() {
+        let query = Query {
+            initial_sql: "CREATE TABLE test (id INTEGER PRIMARY KEY);".to_string(),
+            query: r#"
+                WITH RECURSIVE cte (n) AS (
+                    SELECT 1
+                    UNION ALL
+                    SELECT n + 1 FROM cte WHERE n < 100
+                )
+                SELECT n FROM cte;
+            "#
+            .to_string(),
+            ..Default::default()
+        };
+        let config = Config {
+            max_rows: 10,
+            ..Default::default()
+        };
+
+        let response = Executor::new(config.clone())
+            .execute_query(query)
+            .await
+            .expect("no error");
+        assert_eq!(response.result_sets[0].rows.len(), 10);
+        assert_eq!(response.result_sets[0].rows[9], vec![Cell::Integer(10)]);
+        assert!(
+            response.result_sets[0].truncated,
+            "response should be truncated"
+        );
+        assert_eq!(
+            response.result_sets[0].total_rows, 11,
+            "the rows after the first truncated one should not be counted"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_with_byte_limit() {
+        let query = Query {
+            initial_sql: "CREATE TABLE test (id INTEGER PRIMARY KEY);".to_string(),
+            query: r#"
+                WITH RECURSIVE cte (n) AS (
+                    SELECT 1
+                    UNION ALL
+                    SELECT n + 1 FROM cte WHERE n < 100
+                )
+                SELECT printf('%.100c', 'x') FROM cte;
+            "#
+            .to_string(),
+            ..Default::default()
+        };
+        let config = Config {
+            max_bytes: 1050,
+            ..Default::default()
+        };
+
+        let response = Executor::new(config.clone())
+            .execute_query(query)
+            .await
+            .expect("no error");
+        assert_eq!(response.result_sets[0].rows.len(), 10);
+        assert!(
+            response.result_sets[0].truncated,
+            "response should be truncated"
+        );
+        assert_eq!(response.result_sets[0].total_rows, 11);
+    }
+
+    #[tokio::test]
+    async fn test_with_attach_query() {
+        let query = Query {
+            initial_sql: "CREATE TABLE test (id INTEGER PRIMARY KEY);".to_string(),
+            query: "ATTACH DATABASE '/etc/passwd' AS passwd;".to_string(),
+            ..Default::default()
+        };
+
+        let response = Executor::default().execute_query(query).await;
+        assert_matches!(
+            response,
+            Err(Error::Forbidden {
+                action: ActionCode::Attach,
+                ..
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_with_attach_schema() {
+        let query = Query {
+            initial_sql: "ATTACH DATABASE '/tmp/dbrunner.db' AS other;".to_string(),
+            query: "SELECT 1;".to_string(),
+            ..Default::default()
+        };
+
+        let response = Executor::default().execute_query(query).await;
+        assert_matches!(
+            response,
+            Err(Error::Forbidden {
+                action: ActionCode::Attach,
+                phase: Phase::Schema,
+                ..
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_with_vacuum_into_schema() {
+        let query = Query {
+            initial_sql:
+                "CREATE TABLE test (id INTEGER PRIMARY KEY); VACUUM INTO '/tmp/dbrunner-vacuum.db';"
+                    .to_string(),
+            query: "SELECT 1;".to_string(),
+            ..Default::default()
+        };
+
+        let response = Executor::default().execute_query(query).await;
+        assert_matches!(
+            response,
+            Err(Error::Forbidden {
+                action: ActionCode::Attach,
+                ..
+            })
+        );
+        assert!(!std::path::Path::new("/tmp/dbrunner-vacuum.db").exists());
+    }
+
+    #[tokio::test]
+    async fn test_with_load_extension_query() {
+        let query = Query {
+            initial_sql: "CREATE TABLE test (id INTEGER PRIMARY KEY);".to_string(),
+            query: "SELECT load_extension('/tmp/evil.so');".to_string(),
+            ..Default::default()
+        };
+
+        let response = Executor::default().execute_query(query).await;
+        assert_matches!(
+            response,
+            Err(Error::Forbidden {
+                action: ActionCode::Function,
+                ..
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_with_pragma_query() {
+        let query = Query {
+            initial_sql: "CREATE TABLE test (id INTEGER PRIMARY KEY, name TEXT);".to_string(),
+            query: "PRAGMA max_page_count;".to_string(),
+            ..Default::default()
+        };
+        let response = Executor::default().execute_query(query).await;
+        assert_matches!(response, Ok(_));
+
+        let query = Query {
+            initial_sql: "CREATE TABLE test (id INTEGER PRIMARY KEY, name TEXT);".to_string(),
+            query: "PRAGMA table_info('test');".to_string(),
+            ..Default::default()
+        };
+        let response = Executor::default()
+            .execute_query(query)
+            .await
+            .expect("the pragma should only read the database");
+        assert_eq!(response.result_sets[0].rows.len(), 2);
+
+        let query = Query {
+            initial_sql: "CREATE TABLE test (id INTEGER PRIMARY KEY, name TEXT);".to_string(),
+            query: "PRAGMA max_page_count = 4294967294;".to_string(),
+            ..Default::default()
+        };
+        let response = Executor::default().execute_query(query).await;
+        assert_matches!(
+            response,
+            Err(Error::Forbidden {
+                action: ActionCode::Pragma,
+                ..
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_with_pragma_schema() {
+        let query = Query {
+            initial_sql: r#"
+                PRAGMA foreign_keys = ON;
+                CREATE TABLE test (id INTEGER PRIMARY KEY);
+            "#
+            .to_string(),
+            query: "PRAGMA foreign_keys;".to_string(),
+            ..Default::default()
+        };
+
+        let response = Executor::default()
+            .execute_query(query)
+            .await
+            .expect("no error");
+        assert_eq!(response.result_sets[0].rows, vec![vec![Cell::Integer(1)]]);
+    }
+
+    #[tokio::test]
+    async fn test_with_schema_snapshot() {
+        let executor = Executor::default();
+        let initial_sql = r#"
+            PRAGMA foreign_keys = ON;
+
+            CREATE TABLE test (
+                id INTEGER PRIMARY KEY,
+                name TEXT
+            );
+
+            INSERT INTO test (name) VALUES ('Alice');
+            INSERT INTO test (name) VALUES ('Bob');
+        "#;
+
+        let response = executor
+            .execute_query(Query {
+                initial_sql: initial_sql.to_string(),
+                query: "DELETE FROM test WHERE id = 1 RETURNING name;".to_string(),
+                ..Default::default()
+            })
+            .await
+            .expect("no error");
+        assert_eq!(
+            response.result_sets[0].rows,
+            vec![vec![Cell::Text("Alice".to_string())]]
+        );
+
+        // the deletion must not leak into the snapshot.
+        let response = executor
+            .execute_query(Query {
+                initial_sql: initial_sql.to_string(),
+                query: "SELECT name FROM test;".to_string(),
+                ..Default::default()
+            })
+            .await
+            .expect("no error");
+        assert_eq!(
+            response.result_sets[0].rows,
+            vec![
+                vec![Cell::Text("Alice".to_string())],
+                vec![Cell::Text("Bob".to_string())]
+            ]
+        );
+
+        let response = executor
+            .execute_query(Query {
+                initial_sql: initial_sql.to_string(),
+                query: "PRAGMA foreign_keys;".to_string(),
+                ..Default::default()
+            })
+            .await
+            .expect("no error");
+        assert_eq!(response.result_sets[0].rows, vec![vec![Cell::Integer(1)]]);
+
+        let stats = executor.snapshot_stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (2, 1, 1));
+    }
+
+    #[tokio::test]
+    async fn test_with_function_profile_snapshot() {
+        let executor = Executor::default();
+        let query = Query {
+            initial_sql: "CREATE TABLE test AS SELECT reverse('ab') AS name;".to_string(),
+            query: "SELECT name FROM test;".to_string(),
+            ..Default::default()
+        };
+
+        executor
+            .execute_query(query.clone())
+            .await
+            .expect("no error");
+        let response = executor
+            .execute_query(Query {
+                function_profile: FunctionProfile::Sqlite,
+                ..query
+            })
+            .await;
+        assert_matches!(
+            response,
+            Err(Error::ExecuteInitialSql { .. }),
+            "the snapshot of another function profile should not be restored"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_with_temp_schema_snapshot() {
+        let executor = Executor::default();
+        let query = Query {
+            initial_sql: "CREATE TEMP TABLE test (id INTEGER PRIMARY KEY);".to_string(),
+            query: "SELECT * FROM test;".to_string(),
+            ..Default::default()
+        };
+
+        executor
+            .execute_query(query.clone())
+            .await
+            .expect("no error");
+        executor.execute_query(query).await.expect("no error");
+
+        let stats = executor.snapshot_stats();
+        assert_eq!((stats.hits, stats.misses, stats.entries), (0, 2, 0));
+    }
+
+    #[tokio::test]
+    async fn test_with_snapshot_size_limit() {
+        let query = Query {
+            initial_sql: r#"
+                CREATE TABLE test (id INTEGER PRIMARY KEY, payload TEXT);
+
+                WITH RECURSIVE cte (n) AS (
+                    SELECT 1
+                    UNION ALL
+                    SELECT n + 1 FROM cte WHERE n < 100
+                )
+                INSERT INTO test (payload) SELECT printf('%.1000c', 'x') FROM cte;
+            "#
+            .to_string(),
+            query: "INSERT INTO test (payload) SELECT payload FROM test;".to_string(),
+            ..Default::default()
         };
-        let response = execute_query(query).await.expect("no error");
-        assert_eq!(response.header, vec!["id", "name"]);
-        assert_eq!(
-            response.rows,
-            vec![vec![Some("1".to_string()), Some("Charlie".to_string())]]
-        );
+
+        let executor = Executor::new(Config {
+            max_database_bytes: 150 * 1024,
+            ..Default::default()
+        });
+
+        let response = executor.execute_query(query.clone()).await;
+        assert_matches!(response, Err(Error::ResourceExhausted { .. }));
+
+        // the restored database is still bounded by the size limit.
+        let response = executor.execute_query(query).await;
+        assert_matches!(response, Err(Error::ResourceExhausted { .. }));
+        assert_eq!(executor.snapshot_stats().hits, 1);
     }
 
-    #[test]
-    fn test_with_dos_query() {
+    #[tokio::test]
+    async fn test_with_malformed_query() {
         let query = Query {
             initial_sql: r#"
                 CREATE TABLE test (
@@ -174,30 +1774,16 @@ mod tests {
                 INSERT INTO test (name) VALUES ('Bob');
             "#
             .to_string(),
-            query: r#"
-                WITH RECURSIVE cte (n) AS (
-                    SELECT 1
-                    UNION ALL
-                    SELECT n + 1 FROM cte
-                )
-                SELECT * FROM cte;
-            "#
-            .to_string(),
+            query: "SELECT * FROM unknown_table;".to_string(),
+            ..Default::default()
         };
+        let response = Executor::default().execute_query(query).await;
 
-        let rt = tokio::runtime::Builder::new_current_thread()
-            .enable_all()
-            .build()
-            .expect("runtime build");
-        let response = rt.block_on(execute_query(query));
-
-        assert_matches!(response, Err(Error::QueryTimedOut));
-        // kill runtime
-        rt.shutdown_background();
+        assert_matches!(response, Err(Error::ExecuteQuery { .. }));
     }
 
     #[tokio::test]
-    async fn test_with_malformed_query() {
+    async fn test_with_malformed_query_2() {
         let query = Query {
             initial_sql: r#"
                 CREATE TABLE test (
@@ -209,15 +1795,56 @@ mod tests {
                 INSERT INTO test (name) VALUES ('Bob');
             "#
             .to_string(),
-            query: "SELECT * FROM unknown_table;".to_string(),
+            query: "SELECT * FROM test WHERE id = @1;".to_string(),
+            ..Default::default()
         };
-        let response = execute_query(query).await;
+        let response = Executor::default().execute_query(query).await;
 
-        assert_matches!(response, Err(Error::ExecuteQuery(_)));
+        assert_matches!(response, Err(Error::ExecuteQuery { .. }));
     }
 
     #[tokio::test]
-    async fn test_with_malformed_query_2() {
+    async fn test_with_located_query_error() {
+        let query = |query: &str| Query {
+            initial_sql: "CREATE TABLE test (id INTEGER PRIMARY KEY, name TEXT);".to_string(),
+            query: query.to_string(),
+            ..Default::default()
+        };
+        let position = |response: Result<(), Error>| match response {
+            Err(Error::ExecuteQuery { position, .. }) => position.map(|p| (p.line, p.column)),
+            other => panic!("unexpected {other:?}"),
+        };
+
+        // SQLite reports the token it failed at in the formatted query.
+        let response = Executor::default()
+            .execute_query(query(
+                "select 1;\n\nSELECT name\n  from  test where nme = 1",
+            ))
+            .await;
+        assert_eq!(position(response.map(|_| ())), Some((4, 20)));
+
+        // The other errors are located at their statement.
+        let response = Executor::default()
+            .execute_query(query("SELECT 1;\n  SELECT name FROM test WHERE id = ?"))
+            .await;
+        assert_eq!(position(response.map(|_| ())), Some((2, 3)));
+
+        let response = Executor::default()
+            .explain_query_plan(query("SELECT 1; SELECT nme FROM test"))
+            .await;
+        assert_eq!(position(response.map(|_| ())), Some((1, 18)));
+
+        // So are the errors of stepping the statement.
+        let response = Executor::default()
+            .execute_query(query(
+                "SELECT 1;\nINSERT INTO test VALUES (1, 'a'), (1, 'b')",
+            ))
+            .await;
+        assert_eq!(position(response.map(|_| ())), Some((2, 1)));
+    }
+
+    #[tokio::test]
+    async fn test_with_parameters() {
         let query = Query {
             initial_sql: r#"
                 CREATE TABLE test (
@@ -229,11 +1856,37 @@ mod tests {
                 INSERT INTO test (name) VALUES ('Bob');
             "#
             .to_string(),
-            query: "SELECT * FROM test WHERE id = @1;".to_string(),
+            query: "SELECT name FROM test WHERE id = ?; SELECT id FROM test WHERE name = :name;"
+                .to_string(),
+            parameters: Parameters {
+                positional: vec![Cell::Integer(2)],
+                named: [("name".to_string(), Cell::Text("Alice".to_string()))].into(),
+            },
+            ..Default::default()
         };
-        let response = execute_query(query).await;
+        let response = Executor::default()
+            .execute_query(query.clone())
+            .await
+            .expect("no error");
+        assert_eq!(
+            response.result_sets[0].rows,
+            vec![vec![Cell::Text("Bob".to_string())]]
+        );
+        assert_eq!(response.result_sets[1].rows, vec![vec![Cell::Integer(1)]]);
 
-        assert_matches!(response, Err(Error::ExecuteQuery(_)));
+        let missing = Executor::default()
+            .execute_query(Query {
+                parameters: Parameters::default(),
+                ..query
+            })
+            .await;
+        assert_matches!(
+            missing,
+            Err(Error::ExecuteQuery {
+                source: rusqlite::Error::InvalidParameterName(name),
+                ..
+            }) if name == "?1"
+        );
     }
 
     #[tokio::test]
@@ -250,9 +1903,10 @@ mod tests {
             "#
             .to_string(),
             query: "SELECT * FROM test WHERE id = ':D)D)D))D)D)D)D)D;".to_string(),
+            ..Default::default()
         };
-        let response = execute_query(query).await;
-        assert_matches!(response, Err(Error::Format(_)));
+        let response = Executor::default().execute_query(query).await;
+        assert_matches!(response, Err(Error::Format { .. }));
     }
 
     #[tokio::test]
@@ -268,9 +1922,19 @@ mod tests {
                 "#
             .to_string(),
             query: "SELECT * FROM test;".to_string(),
+            ..Default::default()
         };
-        let response = execute_query(query).await;
-        assert_matches!(response, Err(Error::ExecuteInitialSql(_)));
+        let response = Executor::default().execute_query(query).await;
+        assert_matches!(
+            response,
+            Err(Error::ExecuteInitialSql { statement, .. }) if *statement == StatementLocation {
+                index: 1,
+                offset: 147,
+                line: 7,
+                column: 17,
+                text: "ABCDEFG;".to_string(),
+            }
+        );
     }
 
     #[tokio::test]
@@ -286,11 +1950,15 @@ mod tests {
                 "#
             .to_string(),
             query: "SELECT * FROM test;".to_string(),
+            ..Default::default()
         };
-        let response = execute_query(query).await.expect("no error");
+        let response = Executor::default()
+            .execute_query(query)
+            .await
+            .expect("no error");
         assert_eq!(
-            response.rows,
-            vec![vec![Some("1".to_string()), None]],
+            response.result_sets[0].rows,
+            vec![vec![Cell::Integer(1), Cell::Null]],
             "cell should be <nil>"
         );
     }
@@ -301,18 +1969,22 @@ mod tests {
             initial_sql: r#"
                 CREATE TABLE test (
                     id INTEGER PRIMARY KEY,
-                    name TEXT
+                    value REAL
                 );
 
                 INSERT INTO test VALUES (1, 1.23);
                 "#
             .to_string(),
             query: "SELECT * FROM test;".to_string(),
+            ..Default::default()
         };
-        let response = execute_query(query).await.expect("no error");
+        let response = Executor::default()
+            .execute_query(query)
+            .await
+            .expect("no error");
         assert_eq!(
-            response.rows,
-            vec![vec![Some("1".to_string()), Some("1.23".to_string())]],
+            response.result_sets[0].rows,
+            vec![vec![Cell::Integer(1), Cell::Real(1.23)]],
             "cell should be 1.23"
         );
     }
@@ -330,12 +2002,730 @@ mod tests {
                 "#
             .to_string(),
             query: "SELECT * FROM test;".to_string(),
+            ..Default::default()
         };
-        let response = execute_query(query).await.expect("no error");
+        let response = Executor::default()
+            .execute_query(query)
+            .await
+            .expect("no error");
         assert_eq!(
-            response.rows,
-            vec![vec![Some("1".to_string()), Some("hello".to_string())]],
+            response.result_sets[0].rows,
+            vec![vec![Cell::Integer(1), Cell::Blob(b"hello".to_vec())]],
             "cell should be 'hello'"
         );
     }
+
+    #[tokio::test]
+    async fn test_with_column_metadata() {
+        let query = Query {
+            initial_sql: r#"
+                CREATE TABLE test (
+                    id INTEGER PRIMARY KEY,
+                    name TEXT
+                );
+            "#
+            .to_string(),
+            query: "SELECT t.id, t.name AS n, COUNT(*) AS c FROM test AS t GROUP BY t.id;"
+                .to_string(),
+            ..Default::default()
+        };
+        let response = Executor::default()
+            .execute_query(query)
+            .await
+            .expect("no error");
+        assert_eq!(response.result_sets[0].header, vec!["id", "n", "c"]);
+        assert_eq!(
+            response.result_sets[0].columns,
+            vec![
+                ColumnMetadata {
+                    decl_type: Some("INTEGER".to_string()),
+                    origin_table: Some("test".to_string()),
+                    origin_column: Some("id".to_string()),
+                    is_expression: false,
+                },
+                ColumnMetadata {
+                    decl_type: Some("TEXT".to_string()),
+                    origin_table: Some("test".to_string()),
+                    origin_column: Some("name".to_string()),
+                    is_expression: false,
+                },
+                ColumnMetadata {
+                    is_expression: true,
+                    ..Default::default()
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_with_nondeterministic_functions() {
+        let query = Query {
+            initial_sql: "CREATE TABLE test (id INTEGER PRIMARY KEY);".to_string(),
+            query: "SELECT datetime('now'), CURRENT_DATE, random(), length(randomblob(3));"
+                .to_string(),
+            seed: 42,
+            clock_ms: 86_400_000,
+            ..Default::default()
+        };
+        let executor = Executor::default();
+
+        let response = executor
+            .execute_query(query.clone())
+            .await
+            .expect("no error");
+        let rows = &response.result_sets[0].rows;
+        assert_eq!(rows[0][0], Cell::Text("1970-01-02 00:00:00".to_string()));
+        assert_eq!(rows[0][1], Cell::Text("1970-01-02".to_string()));
+        assert_eq!(rows[0][3], Cell::Integer(3));
+        assert_eq!(
+            response.nondeterminism,
+            Nondeterminism {
+                clock: true,
+                random: true
+            }
+        );
+
+        let same_seed = executor
+            .execute_query(query.clone())
+            .await
+            .expect("no error");
+        assert_eq!(
+            response.result_sets, same_seed.result_sets,
+            "same seed should give the same result"
+        );
+
+        let other_seed = executor
+            .execute_query(Query { seed: 43, ..query })
+            .await
+            .expect("no error");
+        assert_ne!(
+            response.result_sets[0].rows[0][2],
+            other_seed.result_sets[0].rows[0][2]
+        );
+
+        let deterministic = executor
+            .execute_query(Query {
+                initial_sql: "CREATE TABLE test (id INTEGER PRIMARY KEY);".to_string(),
+                query: "SELECT date('2024-01-01'), abs(-1);".to_string(),
+                ..Default::default()
+            })
+            .await
+            .expect("no error");
+        assert_eq!(deterministic.nondeterminism, Nondeterminism::default());
+    }
+
+    #[tokio::test]
+    async fn test_with_nondeterministic_initial_sql() {
+        let query = Query {
+            initial_sql: r#"
+                CREATE TABLE test (id INTEGER PRIMARY KEY, value INTEGER);
+                INSERT INTO test (value) VALUES (random());
+            "#
+            .to_string(),
+            query: "SELECT value FROM test;".to_string(),
+            ..Default::default()
+        };
+        let executor = Executor::default();
+
+        let seeded_1 = executor
+            .execute_query(Query {
+                seed: 1,
+                ..query.clone()
+            })
+            .await
+            .expect("no error");
+        let seeded_2 = executor
+            .execute_query(Query { seed: 2, ..query })
+            .await
+            .expect("no error");
+        assert!(seeded_1.nondeterminism.random);
+        assert_ne!(
+            seeded_1.result_sets[0].rows, seeded_2.result_sets[0].rows,
+            "the initial SQL should not be reused across seeds"
+        );
+        assert_eq!(executor.snapshot_stats().entries, 0);
+    }
+
+    #[tokio::test]
+    async fn test_with_storage_classes() {
+        let query = Query {
+            initial_sql: "CREATE TABLE test (id INTEGER PRIMARY KEY);".to_string(),
+            query: "SELECT 1, 1.0, '1', NULL, x'31';".to_string(),
+            ..Default::default()
+        };
+        let response = Executor::default()
+            .execute_query(query)
+            .await
+            .expect("no error");
+        assert_eq!(
+            response.result_sets[0].rows,
+            vec![vec![
+                Cell::Integer(1),
+                Cell::Real(1.0),
+                Cell::Text("1".to_string()),
+                Cell::Null,
+                Cell::Blob(b"1".to_vec()),
+            ]],
+            "cells should keep their storage class"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_explain_query_plan() {
+        let query = Query {
+            initial_sql: r#"
+                CREATE TABLE test (
+                    id INTEGER PRIMARY KEY,
+                    name TEXT
+                );
+            "#
+            .to_string(),
+            query: r#"
+                CREATE INDEX test_name ON test (name);
+                SELECT id FROM test WHERE name = ?;
+                SELECT * FROM test ORDER BY name DESC;
+            "#
+            .to_string(),
+            parameters: Parameters {
+                positional: vec![Cell::Text("Alice".to_string())],
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let plan = Executor::default()
+            .explain_query_plan(query)
+            .await
+            .expect("no error");
+
+        let details = plan
+            .statements
+            .iter()
+            .map(|nodes| nodes.iter().map(|n| n.detail.as_str()).collect::<Vec<_>>())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            details,
+            vec![
+                vec![],
+                vec!["SEARCH test USING COVERING INDEX test_name (name=?)"],
+                vec!["SCAN test USING COVERING INDEX test_name"],
+            ],
+            "the plans should see the index created by the query"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_explain_query_plan_with_invalid_query() {
+        let query = Query {
+            initial_sql: "CREATE TABLE test (id INTEGER PRIMARY KEY);".to_string(),
+            query: "SELECT * FROM unknown;".to_string(),
+            ..Default::default()
+        };
+        let result = Executor::default().explain_query_plan(query).await;
+        assert_matches!(result, Err(Error::ExecuteQuery { .. }));
+    }
+
+    #[tokio::test]
+    async fn test_with_statistics() {
+        let query = Query {
+            initial_sql: r#"
+                CREATE TABLE a (id INTEGER PRIMARY KEY, value INTEGER);
+                CREATE TABLE b (id INTEGER PRIMARY KEY, value INTEGER);
+                INSERT INTO a (value) VALUES (1), (2), (3);
+                INSERT INTO b (value) VALUES (3), (2), (1);
+            "#
+            .to_string(),
+            ..Default::default()
+        };
+        let executor = Executor::default();
+
+        let scan = executor
+            .execute_query(Query {
+                query: "SELECT * FROM a ORDER BY value DESC; SELECT * FROM b;".to_string(),
+                ..query.clone()
+            })
+            .await
+            .expect("no error");
+        assert_eq!(scan.statistics.rows, 6);
+        assert_eq!(scan.statistics.sorts, 1);
+        assert!(scan.statistics.fullscan_steps > 0);
+        assert_eq!(scan.statistics.autoindexes, 0);
+        assert!(scan.statistics.vm_steps > 0);
+
+        let join = executor
+            .execute_query(Query {
+                query: "SELECT * FROM a JOIN b ON a.value = b.value;".to_string(),
+                ..query
+            })
+            .await
+            .expect("no error");
+        assert_eq!(join.statistics.rows, 3);
+        assert!(
+            join.statistics.autoindexes > 0,
+            "the join should build an automatic index"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_with_state_capture() {
+        let query = Query {
+            initial_sql: r#"
+                CREATE TABLE test (
+                    id INTEGER PRIMARY KEY,
+                    name TEXT
+                );
+                CREATE TABLE log (name TEXT);
+                CREATE TABLE other (id INTEGER);
+                CREATE TRIGGER test_log AFTER UPDATE ON test BEGIN
+                    INSERT INTO log VALUES (NEW.name);
+                END;
+
+                INSERT INTO test (name) VALUES ('Alice');
+                INSERT INTO test (name) VALUES ('Bob');
+            "#
+            .to_string(),
+            capture: StateCapture {
+                written_tables: true,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let executor = Executor::default();
+
+        let update = executor
+            .execute_query(Query {
+                query: r#"
+                    UPDATE test SET name = 'Charlie' WHERE id = 1;
+                    CREATE TEMP TABLE scratch (id INTEGER);
+                    INSERT INTO scratch VALUES (1);
+                    CREATE TABLE dropped (id INTEGER);
+                    INSERT INTO dropped VALUES (1);
+                    DROP TABLE dropped;
+                "#
+                .to_string(),
+                ..query.clone()
+            })
+            .await
+            .expect("no error");
+        assert_eq!(
+            update.state.keys().collect::<Vec<_>>(),
+            vec!["log", "temp.scratch", "test"],
+            "the written tables should be captured, including by triggers"
+        );
+        assert_eq!(update.state["test"].header, vec!["id", "name"]);
+        assert_eq!(
+            update.state["test"].rows,
+            vec![
+                vec![Cell::Integer(1), Cell::Text("Charlie".to_string())],
+                vec![Cell::Integer(2), Cell::Text("Bob".to_string())],
+            ]
+        );
+        assert_eq!(
+            update.state["log"].rows,
+            vec![vec![Cell::Text("Charlie".to_string())]]
+        );
+
+        let delete_insert = executor
+            .execute_query(Query {
+                query: r#"
+                    INSERT INTO test (id, name) VALUES (3, 'Charlie');
+                    DELETE FROM test WHERE id = 1;
+                    UPDATE test SET id = 1 WHERE id = 3;
+                "#
+                .to_string(),
+                capture: StateCapture {
+                    tables: ["test".to_string()].into(),
+                    ..Default::default()
+                },
+                ..query.clone()
+            })
+            .await
+            .expect("no error");
+        assert_eq!(
+            delete_insert.state.keys().collect::<Vec<_>>(),
+            vec!["test"],
+            "only the specified tables should be captured"
+        );
+        assert_eq!(
+            delete_insert.state["test"].rows, update.state["test"].rows,
+            "the same contents should be captured"
+        );
+
+        let by_id = executor
+            .execute_query(Query {
+                query: r#"
+                    UPDATE test SET name = 'Charlie' WHERE id = 1;
+                    UPDATE test SET name = 'Charlie' WHERE id = 2;
+                "#
+                .to_string(),
+                ..query.clone()
+            })
+            .await
+            .expect("no error");
+        let by_ids = executor
+            .execute_query(Query {
+                query: "UPDATE test SET name = 'Charlie' WHERE id IN (1, 2);".to_string(),
+                ..query
+            })
+            .await
+            .expect("no error");
+        assert_eq!(
+            by_id.get_uid(),
+            by_ids.get_uid(),
+            "the same state should be the same output"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_with_read_only_query() {
+        let query = Query {
+            initial_sql: r#"
+                CREATE TABLE test (id INTEGER PRIMARY KEY);
+                INSERT INTO test (id) VALUES (1);
+            "#
+            .to_string(),
+            read_only: true,
+            ..Default::default()
+        };
+        let executor = Executor::default();
+
+        let response = executor
+            .execute_query(Query {
+                query: "SELECT count(*) FROM test; PRAGMA query_only;".to_string(),
+                ..query.clone()
+            })
+            .await
+            .expect("no error");
+        assert_eq!(response.result_sets[0].rows, vec![vec![Cell::Integer(1)]]);
+        assert_eq!(
+            response.result_sets[1].rows,
+            vec![vec![Cell::Integer(1)]],
+            "the connection should be query-only"
+        );
+
+        let response = executor
+            .execute_query(Query {
+                query: "SELECT * FROM test; DROP TABLE test;".to_string(),
+                ..query.clone()
+            })
+            .await;
+        assert_matches!(
+            response,
+            Err(Error::WriteNotAllowed { statement }) if statement == "DROP TABLE test"
+        );
+
+        let plan = executor
+            .explain_query_plan(Query {
+                query: "DELETE FROM test".to_string(),
+                ..query
+            })
+            .await;
+        assert_matches!(plan, Err(Error::WriteNotAllowed { .. }));
+    }
+
+    #[tokio::test]
+    async fn test_with_sqlite_profiles() {
+        let executor = Executor::new(Config {
+            sqlite_profiles: r#"{
+                "legacy": {"foreign_keys": false, "case_sensitive_like": true},
+                "strict": {"strict_tables": true, "double_quoted_strings": false}
+            }"#
+            .parse()
+            .expect("valid profiles"),
+            ..Default::default()
+        });
+        let query = Query {
+            initial_sql: r#"
+                CREATE TABLE parent (id INTEGER PRIMARY KEY);
+                CREATE TABLE child (id INTEGER PRIMARY KEY, parent_id INTEGER REFERENCES parent (id));
+                INSERT INTO child VALUES (1, 42);
+            "#
+            .to_string(),
+            query: "SELECT 'a' LIKE 'A', \"text\" FROM child;".to_string(),
+            ..Default::default()
+        };
+
+        let response = executor.execute_query(query.clone()).await;
+        assert_matches!(
+            response,
+            Err(Error::ExecuteInitialSql { .. }),
+            "the foreign keys should be enforced by default"
+        );
+
+        let response = executor
+            .execute_query(Query {
+                sqlite_profile: "legacy".to_string(),
+                ..query.clone()
+            })
+            .await
+            .expect("no error");
+        assert_eq!(
+            response.result_sets[0].rows,
+            vec![vec![Cell::Integer(0), Cell::Text("text".to_string())]]
+        );
+
+        let response = executor
+            .execute_query(Query {
+                initial_sql: "CREATE TABLE test (id INTEGER PRIMARY KEY, value INTEGER);"
+                    .to_string(),
+                query: "INSERT INTO test (value) VALUES ('1');".to_string(),
+                sqlite_profile: "strict".to_string(),
+                ..Default::default()
+            })
+            .await
+            .expect("no error");
+        assert_eq!(response.result_sets[0].affected_rows, 1);
+
+        let response = executor
+            .execute_query(Query {
+                initial_sql: "CREATE TABLE test (id INTEGER PRIMARY KEY, value INTEGER);"
+                    .to_string(),
+                query: "INSERT INTO test (value) VALUES ('one');".to_string(),
+                sqlite_profile: "strict".to_string(),
+                ..Default::default()
+            })
+            .await;
+        assert_matches!(
+            response,
+            Err(Error::ExecuteQuery {
+                position: Some(_),
+                ..
+            }),
+            "the tables should be strict"
+        );
+
+        let response = executor
+            .execute_query(Query {
+                sqlite_profile: "strict".to_string(),
+                query: "SELECT \"text\";".to_string(),
+                initial_sql: String::new(),
+                ..query.clone()
+            })
+            .await;
+        assert_matches!(
+            response,
+            Err(Error::ExecuteQuery { .. }),
+            "the double-quoted strings should be rejected"
+        );
+
+        let response = executor
+            .execute_query(Query {
+                sqlite_profile: "unknown".to_string(),
+                ..query
+            })
+            .await;
+        assert_matches!(response, Err(Error::UnknownSqliteProfile(name)) if name == "unknown");
+    }
+
+    #[tokio::test]
+    async fn test_with_dialects() {
+        let executor = Executor::new(Config::default());
+        let initial_sql = r#"
+            CREATE TABLE orders (id INTEGER PRIMARY KEY, customer TEXT, total TEXT, created_at TEXT);
+            INSERT INTO orders VALUES
+                (1, 'Alice', '10', '2024-01-15 10:30:00'),
+                (2, 'alina', '20', '2024-01-20 08:00:00'),
+                (3, 'Bob', '30', '2024-02-01 12:00:00');
+        "#
+        .to_string();
+
+        let response = executor
+            .execute_query(Query {
+                initial_sql: initial_sql.clone(),
+                query: "SELECT DATE_TRUNC('month', created_at) AS month, SUM(total::int) \
+                        FROM orders WHERE customer ILIKE 'al%' \
+                        GROUP BY 1 ORDER BY 1 OFFSET 0 ROWS FETCH FIRST 1 ROW ONLY"
+                    .to_string(),
+                dialect: Dialect::Postgresql,
+                read_only: true,
+                ..Default::default()
+            })
+            .await
+            .expect("no error");
+        assert_eq!(
+            response.result_sets[0].rows,
+            vec![vec![
+                Cell::Text("2024-01-01 00:00:00".to_string()),
+                Cell::Integer(30)
+            ]]
+        );
+
+        let response = executor
+            .execute_query(Query {
+                query: "CREATE TABLE `tags` (`id` INT AUTO_INCREMENT PRIMARY KEY, `name` TEXT) \
+                        ENGINE=InnoDB DEFAULT CHARSET=utf8mb4; \
+                        INSERT INTO `tags` (`name`) VALUES (\"a\"), (\"b\"); \
+                        SELECT `id`, IFNULL(`name`, 'none') FROM `tags` LIMIT 1, 1;"
+                    .to_string(),
+                dialect: Dialect::Mysql,
+                ..Default::default()
+            })
+            .await
+            .expect("no error");
+        assert_eq!(
+            response.result_sets[2].rows,
+            vec![vec![Cell::Integer(2), Cell::Text("b".to_string())]]
+        );
+
+        // the initial SQL is translated from the same dialect.
+        let response = executor
+            .execute_query(Query {
+                initial_sql: "CREATE TABLE tags (id SERIAL PRIMARY KEY, name TEXT); \
+                              INSERT INTO tags (name) VALUES ('a'), ('b');"
+                    .to_string(),
+                query: "SELECT id, name::text FROM tags ORDER BY id".to_string(),
+                dialect: Dialect::Postgresql,
+                ..Default::default()
+            })
+            .await
+            .expect("no error");
+        assert_eq!(
+            response.result_sets[0].rows,
+            vec![
+                vec![Cell::Integer(1), Cell::Text("a".to_string())],
+                vec![Cell::Integer(2), Cell::Text("b".to_string())]
+            ]
+        );
+
+        // the quarters are left to the native date_trunc().
+        let response = executor
+            .execute_query(Query {
+                query: "SELECT DATE_TRUNC('quarter', TIMESTAMP '2024-05-17 10:00:00')".to_string(),
+                dialect: Dialect::Postgresql,
+                ..Default::default()
+            })
+            .await
+            .expect("no error");
+        assert_eq!(
+            response.result_sets[0].rows,
+            vec![vec![Cell::Text("2024-04-01 00:00:00".to_string())]]
+        );
+
+        // the errors of the initial SQL are located in it as written.
+        let schema_error = |initial_sql: &str| {
+            executor.execute_query(Query {
+                initial_sql: initial_sql.to_string(),
+                query: "SELECT 1".to_string(),
+                dialect: Dialect::Postgresql,
+                ..Default::default()
+            })
+        };
+        let response = schema_error(
+            "CREATE TABLE tags (name TEXT);\n  SELECT DISTINCT ON (name) * FROM tags;",
+        )
+        .await;
+        assert_matches!(
+            &response,
+            Err(e @ Error::TranslateInitialSql { source, statement })
+                if matches!(
+                    &**source,
+                    Error::Untranslatable { dialect: Dialect::Postgresql, construct }
+                        if construct == "DISTINCT ON"
+                )
+                && (statement.index, statement.line, statement.column) == (1, 2, 3)
+                && e.phase() == Some(Phase::Schema)
+                && e.category() == Category::Unsupported
+        );
+
+        let response =
+            schema_error("CREATE TABLE tags (name TEXT);\nSELECT name FROM tags WHERE;").await;
+        assert_matches!(
+            &response,
+            Err(e @ Error::TranslateInitialSql { source, statement })
+                if matches!(
+                    &**source,
+                    Error::Format { position: Some(position), .. }
+                        if (position.line, position.column) == (2, 28)
+                )
+                && statement.text == "SELECT name FROM tags WHERE;"
+                && e.phase() == Some(Phase::Schema)
+                && e.category() == Category::Syntax
+        );
+
+        let response = schema_error(
+            "CREATE TABLE tags (id SERIAL PRIMARY KEY);\n\nINSERT INTO missing VALUES (1);",
+        )
+        .await;
+        assert_matches!(
+            response,
+            Err(Error::ExecuteInitialSql { statement, .. })
+                if *statement == StatementLocation {
+                    index: 1,
+                    offset: 44,
+                    line: 3,
+                    column: 1,
+                    text: "INSERT INTO missing VALUES (1);".to_string(),
+                }
+        );
+
+        let response = executor
+            .execute_query(Query {
+                initial_sql,
+                query: "SELECT DISTINCT ON (customer) * FROM orders".to_string(),
+                dialect: Dialect::Postgresql,
+                ..Default::default()
+            })
+            .await;
+        assert_matches!(
+            response,
+            Err(Error::Untranslatable { dialect: Dialect::Postgresql, construct })
+                if construct == "DISTINCT ON"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_with_fixtures() {
+        let executor = Executor::new(Config::default());
+        let query = |csv: &[u8]| Query {
+            initial_sql: "CREATE TABLE students (id INTEGER PRIMARY KEY, name TEXT NOT NULL);"
+                .to_string(),
+            fixtures: vec![
+                Fixture {
+                    table: "students".to_string(),
+                    format: FixtureFormat::Csv,
+                    data: csv.to_vec(),
+                },
+                Fixture {
+                    table: "students".to_string(),
+                    format: FixtureFormat::JsonLines,
+                    data: br#"{"id": 3, "name": "Carol"}"#.to_vec(),
+                },
+            ],
+            query: "SELECT id, name FROM students ORDER BY id".to_string(),
+            ..Default::default()
+        };
+
+        let response = executor
+            .execute_query(query(b"id,name\n1,Alice\n2,Bob\n"))
+            .await
+            .expect("no error");
+        assert_eq!(
+            response.result_sets[0].rows,
+            vec![
+                vec![Cell::Integer(1), Cell::Text("Alice".to_string())],
+                vec![Cell::Integer(2), Cell::Text("Bob".to_string())],
+                vec![Cell::Integer(3), Cell::Text("Carol".to_string())],
+            ]
+        );
+
+        // The snapshot of the database is keyed by the fixtures as well.
+        let response = executor
+            .execute_query(query(b"id,name\n1,Alice\n"))
+            .await
+            .expect("no error");
+        assert_eq!(response.result_sets[0].rows.len(), 2);
+        assert_eq!(executor.snapshot_stats().hits, 0);
+
+        let response = executor.execute_query(query(b"id,name\n1,\n")).await;
+        assert_matches!(
+            &response,
+            Err(e @ Error::LoadFixture { line: Some(line), .. })
+                if line.line == 2 && e.phase() == Some(Phase::Schema)
+        );
+
+        let response = executor.execute_query(query(b"id,name\n1,\"Alice\n")).await;
+        assert_matches!(
+            response,
+            Err(Error::InvalidFixture { line, .. }) if line.line == 2
+        );
+    }
 }
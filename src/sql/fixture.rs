@@ -0,0 +1,467 @@
+//! The rows of the tables of the initial SQL, loaded from CSV or JSON lines
+//! rather than from `INSERT` statements.
+
+use std::{fmt, iter::Peekable, str::Chars};
+
+use rusqlite::types::Value;
+
+use super::{executor::quote_identifier, Error};
+
+/// The rows to insert into a table once the initial SQL ran.
+#[derive(Clone, Debug, Default, Hash, PartialEq, Eq)]
+pub struct Fixture {
+    /// The table to insert the rows into.
+    pub table: String,
+
+    /// The format of the data.
+    pub format: FixtureFormat,
+
+    /// The rows, as UTF-8 text.
+    pub data: Vec<u8>,
+}
+
+/// The format of the rows of a fixture.
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq)]
+pub enum FixtureFormat {
+    /// CSV, as in RFC 4180, with the names of the columns on the first line.
+    ///
+    /// The values are inserted as text, converted by the affinity of their
+    /// column. The empty fields are `NULL`, unless they are quoted.
+    #[default]
+    Csv,
+
+    /// One JSON object per line, keyed by the names of the columns.
+    ///
+    /// The numbers are inserted as integers or reals, the booleans as 1 or
+    /// 0, and the arrays and the objects as their JSON text.
+    JsonLines,
+}
+
+/// A line of the data of a fixture.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FixtureLine {
+    /// The table of the fixture.
+    pub table: String,
+
+    /// The line in the data, starting from 1.
+    pub line: usize,
+}
+
+impl fmt::Display for FixtureLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "table {}, line {}", self.table, self.line)
+    }
+}
+
+/// Insert the rows of the fixtures, in order, all or none of them.
+///
+/// The rows are inserted with prepared statements in a savepoint, which is a
+/// transaction unless the initial SQL left one open.
+pub fn load(conn: &mut rusqlite::Connection, fixtures: &[Fixture]) -> Result<(), Error> {
+    if fixtures.is_empty() {
+        return Ok(());
+    }
+
+    let savepoint = conn
+        .savepoint()
+        .map_err(|source| Error::LoadFixture { source, line: None })?;
+    for fixture in fixtures {
+        let at = |line| FixtureLine {
+            table: fixture.table.clone(),
+            line,
+        };
+        let data = std::str::from_utf8(&fixture.data).map_err(|e| Error::InvalidFixture {
+            line: at(line_of(&fixture.data, e.valid_up_to())),
+            detail: "the data is not UTF-8".to_string(),
+        })?;
+        match fixture.format {
+            FixtureFormat::Csv => load_csv(&savepoint, &fixture.table, data),
+            FixtureFormat::JsonLines => load_json_lines(&savepoint, &fixture.table, data),
+        }
+        .map_err(|e| match e {
+            LineError::Invalid(line, detail) => Error::InvalidFixture {
+                line: at(line),
+                detail,
+            },
+            LineError::Insert(line, source) => Error::LoadFixture {
+                source,
+                line: Some(at(line)),
+            },
+        })?;
+    }
+
+    savepoint
+        .commit()
+        .map_err(|source| Error::LoadFixture { source, line: None })
+}
+
+/// The error at a line of the data of a fixture.
+enum LineError {
+    /// The data is not in the format of the fixture.
+    Invalid(usize, String),
+
+    /// The row could not be inserted.
+    Insert(usize, rusqlite::Error),
+}
+
+impl From<(usize, String)> for LineError {
+    fn from((line, detail): (usize, String)) -> Self {
+        Self::Invalid(line, detail)
+    }
+}
+
+/// A record of a CSV, with its line and its fields.
+type Record = (usize, Vec<Option<String>>);
+
+/// The `INSERT` statement of the values of the columns into the table.
+fn insert_sql(table: &str, columns: &[String]) -> String {
+    if columns.is_empty() {
+        format!("INSERT INTO {} DEFAULT VALUES", quote_identifier(table))
+    } else {
+        format!(
+            "INSERT INTO {} ({}) VALUES ({})",
+            quote_identifier(table),
+            columns
+                .iter()
+                .map(|column| quote_identifier(column))
+                .collect::<Vec<_>>()
+                .join(", "),
+            vec!["?"; columns.len()].join(", ")
+        )
+    }
+}
+
+/// Insert the records of the CSV into the columns named by its header, with
+/// the statement prepared once for all of them.
+fn load_csv(conn: &rusqlite::Connection, table: &str, data: &str) -> Result<(), LineError> {
+    let mut records = CsvRecords::new(data.strip_prefix('\u{feff}').unwrap_or(data));
+    let Some((header_line, header)) = records.next().transpose()? else {
+        return Ok(());
+    };
+    let columns = header
+        .into_iter()
+        .map(|name| name.ok_or((header_line, "a column has no name".to_string())))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut stmt = conn
+        .prepare(&insert_sql(table, &columns))
+        .map_err(|e| LineError::Insert(header_line, e))?;
+
+    for record in records {
+        let (line, fields) = record?;
+        if fields.len() != columns.len() {
+            return Err(LineError::Invalid(
+                line,
+                format!(
+                    "expected {} fields as in the header, got {}",
+                    columns.len(),
+                    fields.len()
+                ),
+            ));
+        }
+        let values = fields
+            .into_iter()
+            .map(|field| field.map_or(Value::Null, Value::Text));
+        stmt.execute(rusqlite::params_from_iter(values))
+            .map_err(|e| LineError::Insert(line, e))?;
+    }
+
+    Ok(())
+}
+
+/// The records of the CSV with the line they start at, skipping the empty
+/// lines. The empty fields are `None`, unless they are quoted.
+///
+/// The records are parsed as they are iterated, and none after an invalid
+/// one.
+struct CsvRecords<'a> {
+    chars: Peekable<Chars<'a>>,
+    line: usize,
+}
+
+impl<'a> CsvRecords<'a> {
+    fn new(data: &'a str) -> Self {
+        Self {
+            chars: data.chars().peekable(),
+            line: 1,
+        }
+    }
+
+    /// The next record, which is `[None]` for an empty line.
+    fn record(&mut self) -> Result<Record, (usize, String)> {
+        let chars = &mut self.chars;
+        let start = self.line;
+        let mut record = Vec::new();
+        loop {
+            let field = if chars.next_if_eq(&'"').is_some() {
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') if chars.next_if_eq(&'"').is_some() => value.push('"'),
+                        Some('"') => break,
+                        Some(c) => {
+                            self.line += usize::from(c == '\n');
+                            value.push(c);
+                        }
+                        None => return Err((start, "a quoted field is not closed".to_string())),
+                    }
+                }
+                Some(value)
+            } else {
+                let mut value = String::new();
+                while let Some(c) = chars.next_if(|c| !matches!(c, ',' | '\r' | '\n')) {
+                    value.push(c);
+                }
+                (!value.is_empty()).then_some(value)
+            };
+            record.push(field);
+
+            match chars.next() {
+                Some(',') => {}
+                Some('\r') => {
+                    // CRLF ends the record once.
+                    chars.next_if_eq(&'\n');
+                    break;
+                }
+                Some('\n') | None => break,
+                Some(c) => {
+                    return Err((self.line, format!("unexpected {c:?} after a quoted field")));
+                }
+            }
+        }
+        self.line += 1;
+
+        Ok((start, record))
+    }
+}
+
+impl Iterator for CsvRecords<'_> {
+    type Item = Result<Record, (usize, String)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.chars.peek().is_some() {
+            match self.record() {
+                Ok((_, record)) if record == [None] => {}
+                Err(e) => {
+                    self.chars = "".chars().peekable();
+                    return Some(Err(e));
+                }
+                record => return Some(record),
+            }
+        }
+
+        None
+    }
+}
+
+/// Insert the rows of the JSON lines, skipping the empty lines.
+///
+/// The objects may have different keys, so the statements are cached by
+/// their columns.
+fn load_json_lines(conn: &rusqlite::Connection, table: &str, data: &str) -> Result<(), LineError> {
+    for (i, text) in data.lines().enumerate() {
+        if text.trim().is_empty() {
+            continue;
+        }
+        let line = i + 1;
+        let object = match serde_json::from_str(text) {
+            Ok(serde_json::Value::Object(object)) => object,
+            Ok(_) => {
+                return Err(LineError::Invalid(
+                    line,
+                    "expected a JSON object".to_string(),
+                ))
+            }
+            Err(e) => return Err(LineError::Invalid(line, e.to_string())),
+        };
+        let (columns, values): (Vec<_>, Vec<_>) = object
+            .into_iter()
+            .map(|(column, value)| (column, json_value(value)))
+            .unzip();
+
+        conn.prepare_cached(&insert_sql(table, &columns))
+            .and_then(|mut stmt| stmt.execute(rusqlite::params_from_iter(values)))
+            .map_err(|e| LineError::Insert(line, e))?;
+    }
+
+    Ok(())
+}
+
+/// The SQLite value of the JSON value.
+fn json_value(value: serde_json::Value) -> Value {
+    match value {
+        serde_json::Value::Null => Value::Null,
+        serde_json::Value::Bool(b) => Value::Integer(b.into()),
+        serde_json::Value::Number(n) => match n.as_i64() {
+            Some(i) => Value::Integer(i),
+            None => Value::Real(n.as_f64().unwrap_or(f64::NAN)),
+        },
+        serde_json::Value::String(s) => Value::Text(s),
+        value @ (serde_json::Value::Array(_) | serde_json::Value::Object(_)) => {
+            Value::Text(value.to_string())
+        }
+    }
+}
+
+/// The line of the byte offset in the data, starting from 1.
+fn line_of(data: &[u8], offset: usize) -> usize {
+    data[..offset].iter().filter(|&&b| b == b'\n').count() + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::*;
+
+    #[rstest]
+    #[case("", Ok(vec![]))]
+    #[case(
+        "a,b\r\n1,\"x, \"\"y\"\"\"\r\n\n,\"\"\n",
+        Ok(vec![
+            r#"1: [Some("a"), Some("b")]"#,
+            r#"2: [Some("1"), Some("x, \"y\"")]"#,
+            r#"4: [None, Some("")]"#,
+        ])
+    )]
+    #[case(
+        "a,b\n\"multi\nline\",2\n\n\n3,4",
+        Ok(vec![
+            r#"1: [Some("a"), Some("b")]"#,
+            r#"2: [Some("multi\nline"), Some("2")]"#,
+            r#"6: [Some("3"), Some("4")]"#,
+        ])
+    )]
+    #[case("a\n\"open\n", Err((2, "a quoted field is not closed")))]
+    #[case("a\n\"x\"y\n", Err((2, "unexpected 'y' after a quoted field")))]
+    fn test_csv_records(#[case] data: &str, #[case] expected: Result<Vec<&str>, (usize, &str)>) {
+        let records = CsvRecords::new(data)
+            .map(|record| record.map(|(line, fields)| format!("{line}: {fields:?}")))
+            .collect::<Result<Vec<_>, _>>();
+        assert_eq!(
+            records,
+            expected
+                .map(|records| records.into_iter().map(String::from).collect())
+                .map_err(|(line, detail)| (line, detail.to_string())),
+            "Case {data:?}"
+        );
+    }
+
+    #[test]
+    fn test_load() {
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE students (id INTEGER PRIMARY KEY, name TEXT, score REAL, tags TEXT);",
+        )
+        .unwrap();
+
+        load(
+            &mut conn,
+            &[
+                Fixture {
+                    table: "students".to_string(),
+                    format: FixtureFormat::Csv,
+                    data: b"\xef\xbb\xbfid,name,score\n1,Alice,90.5\n2,,\n".to_vec(),
+                },
+                Fixture {
+                    table: "students".to_string(),
+                    format: FixtureFormat::JsonLines,
+                    data: br#"{"id": 3, "name": "Bob", "tags": ["a"]}
+
+{"score": 70, "id": 4}"#
+                        .to_vec(),
+                },
+            ],
+        )
+        .expect("no error");
+
+        let rows = conn
+            .prepare("SELECT id, name, score, tags FROM students ORDER BY id")
+            .unwrap()
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, Option<String>>(1)?,
+                    row.get::<_, Option<f64>>(2)?,
+                    row.get::<_, Option<String>>(3)?,
+                ))
+            })
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                (1, Some("Alice".to_string()), Some(90.5), None),
+                (2, None, None, None),
+                (
+                    3,
+                    Some("Bob".to_string()),
+                    None,
+                    Some("[\"a\"]".to_string())
+                ),
+                (4, None, Some(70.0), None),
+            ]
+        );
+
+        let e = load(
+            &mut conn,
+            &[
+                Fixture {
+                    table: "students".to_string(),
+                    format: FixtureFormat::Csv,
+                    data: b"id\n5\n".to_vec(),
+                },
+                Fixture {
+                    table: "students".to_string(),
+                    format: FixtureFormat::JsonLines,
+                    data: b"{\"id\": 6}\n{\"id\": 1}\n".to_vec(),
+                },
+            ],
+        )
+        .unwrap_err();
+        assert!(
+            matches!(
+                &e,
+                Error::LoadFixture { line: Some(line), .. }
+                    if *line == FixtureLine { table: "students".to_string(), line: 2 }
+            ),
+            "{e:?}"
+        );
+        let count: i64 = conn
+            .query_row("SELECT COUNT(*) FROM students", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(count, 4, "the fixtures should be loaded all or none");
+
+        let e = load(
+            &mut conn,
+            &[Fixture {
+                table: "students".to_string(),
+                format: FixtureFormat::Csv,
+                data: b"id,name\n7\n".to_vec(),
+            }],
+        )
+        .unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "invalid fixture at table students, line 2: expected 2 fields as in the header, got 1"
+        );
+
+        // The statement of the CSV is prepared once, from its header.
+        let e = load(
+            &mut conn,
+            &[Fixture {
+                table: "students".to_string(),
+                format: FixtureFormat::Csv,
+                data: b"id,grade\n".to_vec(),
+            }],
+        )
+        .unwrap_err();
+        assert!(
+            matches!(
+                &e,
+                Error::LoadFixture { line: Some(line), .. }
+                    if *line == FixtureLine { table: "students".to_string(), line: 1 }
+            ),
+            "{e:?}"
+        );
+    }
+}
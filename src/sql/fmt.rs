@@ -1,16 +1,72 @@
-use super::Error;
-use sql_insight::sqlparser::dialect::SQLiteDialect;
+use super::{
+    dialect::{self, Dialect},
+    statements::{Position, StatementLocation},
+    Error,
+};
+use sql_insight::sqlparser::{parser::Parser, tokenizer::Location};
 
-pub fn format_sql(sql: &str) -> Result<String, Error> {
-    let dialect = SQLiteDialect {};
-    let formatted_sql = sql_insight::format(&dialect, sql)?;
+/// Format the SQL written in the dialect, translated to SQLite.
+pub fn format_sql(sql: &str, dialect: Dialect) -> Result<String, Error> {
+    let mut statements =
+        Parser::parse_sql(dialect.parser_dialect().as_ref(), sql).map_err(|e| {
+            let source = sql_insight::error::Error::from(e);
+            Error::Format {
+                position: error_position(sql, &source),
+                source,
+            }
+        })?;
+    dialect::translate(dialect, &mut statements)?;
 
+    let formatted_sql = statements
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
     Ok(formatted_sql.join("; "))
 }
 
+/// Format the statement of the initial SQL written in the dialect,
+/// translated to SQLite, with its errors located in the initial SQL.
+pub fn format_statement(
+    initial_sql: &str,
+    statement: &StatementLocation,
+    dialect: Dialect,
+) -> Result<String, Error> {
+    format_sql(&statement.text, dialect).map_err(|e| {
+        let source = match e {
+            Error::Format { source, position } => Error::Format {
+                source,
+                position: position.map(|p| Position::at(initial_sql, statement.offset + p.offset)),
+            },
+            e => e,
+        };
+        Error::TranslateInitialSql {
+            source: Box::new(source),
+            statement: Box::new(statement.clone()),
+        }
+    })
+}
+
+/// The position in the SQL of the error of the parser, which only reports it
+/// in its message, as `at Line: 1, Column 8`.
+fn error_position(sql: &str, e: &sql_insight::error::Error) -> Option<Position> {
+    let message = e.to_string();
+    let (_, location) = message.rsplit_once(" at Line: ")?;
+    let (line, column) = location.split_once(", Column ")?;
+    let column = column
+        .split(|c: char| !c.is_ascii_digit())
+        .next()
+        .unwrap_or_default();
+
+    let location = Location {
+        line: line.parse().ok()?,
+        column: column.parse().ok()?,
+    };
+    Position::from_location(sql, location)
+}
+
 #[cfg(test)]
 mod tests {
-    use super::format_sql;
+    use super::*;
     use rstest::*;
 
     #[rstest]
@@ -37,11 +93,30 @@ mod tests {
     )]
     #[case("SELECT *     FROM   students", "SELECT * FROM students")]
     #[case("seLect * fRom students", "SELECT * FROM students")]
+    #[case(
+        "select * from t where a = ? and b = ?2 and c = :c and d = @d and e = $e",
+        "SELECT * FROM t WHERE a = ? AND b = ?2 AND c = :c AND d = @d AND e = $e"
+    )]
     fn test_format(#[case] input: &str, #[case] expected: &str) {
-        let formatted = format_sql(input).unwrap();
+        let formatted = format_sql(input, Dialect::Sqlite).unwrap();
         assert_eq!(
             *expected, formatted,
             "Case {input}: Expected '{expected}', got '{formatted}'"
         );
     }
+
+    #[rstest]
+    #[case("SELECT * FROM students WHERE", None)]
+    #[case("SELECT *\n  FORM students", Some((2, 3)))]
+    #[case("SELECT 1;\nSELECT 'é' FROM t WHERE name = 'x", Some((2, 32)))]
+    fn test_format_error_position(#[case] input: &str, #[case] expected: Option<(usize, usize)>) {
+        let Err(Error::Format { position, .. }) = format_sql(input, Dialect::Sqlite) else {
+            panic!("Case {input}: expected a format error");
+        };
+        assert_eq!(
+            position.map(|p| (p.line, p.column)),
+            expected,
+            "Case {input}"
+        );
+    }
 }
@@ -0,0 +1,843 @@
+//! The SQL functions the executor provides on top of the SQLite ones.
+
+use std::{ffi::CStr, sync::Arc};
+
+use regex::{Regex, RegexBuilder};
+use rusqlite::{
+    ffi,
+    functions::{Aggregate, Context, FunctionFlags},
+    limits::Limit,
+    types::{Value, ValueRef},
+};
+use serde::{Deserialize, Serialize};
+
+/// The largest compiled size of a regular expression, in bytes.
+const MAX_REGEX_BYTES: usize = 1 << 20;
+
+/// The set of functions installed on the connections of the queries.
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FunctionProfile {
+    /// The SQLite functions and every function of this module.
+    #[default]
+    Extended,
+    /// The SQLite functions only.
+    Sqlite,
+}
+
+impl FunctionProfile {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FunctionProfile::Extended => "extended",
+            FunctionProfile::Sqlite => "sqlite",
+        }
+    }
+
+    /// Check if the profile includes the functions of this module, of every
+    /// library.
+    pub fn includes_extensions(&self) -> bool {
+        match self {
+            FunctionProfile::Extended => true,
+            FunctionProfile::Sqlite => false,
+        }
+    }
+}
+
+/// The group a function belongs to.
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Library {
+    Math,
+    String,
+    Regexp,
+    Date,
+    Statistics,
+}
+
+impl Library {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Library::Math => "math",
+            Library::String => "string",
+            Library::Regexp => "regexp",
+            Library::Date => "date",
+            Library::Statistics => "statistics",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FunctionKind {
+    Scalar,
+    Aggregate,
+}
+
+/// The description of a function of the registry.
+#[derive(Clone, Copy, Debug)]
+pub struct FunctionInfo {
+    pub name: &'static str,
+    pub kind: FunctionKind,
+    pub library: Library,
+    pub min_args: usize,
+    pub max_args: usize,
+    /// The signature and the behavior of the function.
+    pub description: &'static str,
+}
+
+/// The limits the functions enforce on their results.
+#[derive(Clone, Copy, Debug)]
+struct Limits {
+    /// The longest string or blob, in bytes.
+    max_length: usize,
+}
+
+type ScalarFn = fn(&Context<'_>, Limits) -> rusqlite::Result<Value>;
+
+#[derive(Clone, Copy)]
+enum Implementation {
+    Scalar(ScalarFn),
+    Statistic(fn(&Moments) -> Option<f64>),
+}
+
+struct Entry {
+    info: FunctionInfo,
+    implementation: Implementation,
+}
+
+macro_rules! scalar {
+    ($library:ident, $name:literal, $args:expr, $description:literal, $f:expr) => {
+        Entry {
+            info: FunctionInfo {
+                name: $name,
+                kind: FunctionKind::Scalar,
+                library: Library::$library,
+                min_args: *$args.start(),
+                max_args: *$args.end(),
+                description: $description,
+            },
+            implementation: Implementation::Scalar($f),
+        }
+    };
+}
+
+macro_rules! statistic {
+    ($name:literal, $description:literal, $f:expr) => {
+        Entry {
+            info: FunctionInfo {
+                name: $name,
+                kind: FunctionKind::Aggregate,
+                library: Library::Statistics,
+                min_args: 1,
+                max_args: 1,
+                description: $description,
+            },
+            implementation: Implementation::Statistic($f),
+        }
+    };
+}
+
+static REGISTRY: &[Entry] = &[
+    // math
+    scalar!(Math, "power", 2..=2, "power(x, y): x raised to the power of y.", |ctx, _| {
+        math2(ctx, f64::powf)
+    }),
+    scalar!(Math, "pow", 2..=2, "pow(x, y): alias of power(x, y).", |ctx, _| {
+        math2(ctx, f64::powf)
+    }),
+    scalar!(Math, "sqrt", 1..=1, "sqrt(x): the square root of x.", |ctx, _| {
+        math1(ctx, f64::sqrt)
+    }),
+    scalar!(Math, "exp", 1..=1, "exp(x): e raised to the power of x.", |ctx, _| {
+        math1(ctx, f64::exp)
+    }),
+    scalar!(Math, "ln", 1..=1, "ln(x): the natural logarithm of x.", |ctx, _| {
+        math1(ctx, f64::ln)
+    }),
+    scalar!(
+        Math,
+        "log",
+        1..=2,
+        "log(x) or log(b, x): the base-10 or base-b logarithm of x.",
+        |ctx, _| match ctx.len() {
+            1 => math1(ctx, f64::log10),
+            _ => math2(ctx, |b, x| x.log(b)),
+        }
+    ),
+    scalar!(Math, "log10", 1..=1, "log10(x): the base-10 logarithm of x.", |ctx, _| {
+        math1(ctx, f64::log10)
+    }),
+    scalar!(Math, "log2", 1..=1, "log2(x): the base-2 logarithm of x.", |ctx, _| {
+        math1(ctx, f64::log2)
+    }),
+    scalar!(Math, "floor", 1..=1, "floor(x): the largest integer not greater than x.", |ctx, _| {
+        rounding(ctx, f64::floor)
+    }),
+    scalar!(Math, "ceil", 1..=1, "ceil(x): the smallest integer not less than x.", |ctx, _| {
+        rounding(ctx, f64::ceil)
+    }),
+    scalar!(Math, "ceiling", 1..=1, "ceiling(x): alias of ceil(x).", |ctx, _| {
+        rounding(ctx, f64::ceil)
+    }),
+    scalar!(Math, "trunc", 1..=1, "trunc(x): x rounded toward zero.", |ctx, _| {
+        rounding(ctx, f64::trunc)
+    }),
+    scalar!(Math, "mod", 2..=2, "mod(x, y): the remainder of x divided by y.", |ctx, _| {
+        match (ctx.get_raw(0), ctx.get_raw(1)) {
+            (ValueRef::Integer(x), ValueRef::Integer(y)) => {
+                Ok(x.checked_rem(y).map_or(Value::Null, Value::Integer))
+            }
+            _ => math2(ctx, |x, y| x % y),
+        }
+    }),
+    scalar!(Math, "pi", 0..=0, "pi(): the constant π.", |_, _| {
+        Ok(Value::Real(std::f64::consts::PI))
+    }),
+    scalar!(Math, "degrees", 1..=1, "degrees(x): x radians in degrees.", |ctx, _| {
+        math1(ctx, f64::to_degrees)
+    }),
+    scalar!(Math, "radians", 1..=1, "radians(x): x degrees in radians.", |ctx, _| {
+        math1(ctx, f64::to_radians)
+    }),
+    scalar!(Math, "sin", 1..=1, "sin(x): the sine of x radians.", |ctx, _| {
+        math1(ctx, f64::sin)
+    }),
+    scalar!(Math, "cos", 1..=1, "cos(x): the cosine of x radians.", |ctx, _| {
+        math1(ctx, f64::cos)
+    }),
+    scalar!(Math, "tan", 1..=1, "tan(x): the tangent of x radians.", |ctx, _| {
+        math1(ctx, f64::tan)
+    }),
+    scalar!(Math, "asin", 1..=1, "asin(x): the arc sine of x, in radians.", |ctx, _| {
+        math1(ctx, f64::asin)
+    }),
+    scalar!(Math, "acos", 1..=1, "acos(x): the arc cosine of x, in radians.", |ctx, _| {
+        math1(ctx, f64::acos)
+    }),
+    scalar!(Math, "atan", 1..=1, "atan(x): the arc tangent of x, in radians.", |ctx, _| {
+        math1(ctx, f64::atan)
+    }),
+    scalar!(Math, "atan2", 2..=2, "atan2(y, x): the arc tangent of y/x, in radians.", |ctx, _| {
+        math2(ctx, f64::atan2)
+    }),
+    // string
+    scalar!(
+        String,
+        "lpad",
+        2..=3,
+        "lpad(s, n[, fill]): s left-padded with fill (a space by default) or truncated to n characters.",
+        |ctx, limits| pad(ctx, limits, Side::Left)
+    ),
+    scalar!(
+        String,
+        "rpad",
+        2..=3,
+        "rpad(s, n[, fill]): s right-padded with fill (a space by default) or truncated to n characters.",
+        |ctx, limits| pad(ctx, limits, Side::Right)
+    ),
+    scalar!(
+        String,
+        "left",
+        2..=2,
+        "left(s, n): the first n characters of s, or all but the last -n ones.",
+        |ctx, _| substring(ctx, Side::Left)
+    ),
+    scalar!(
+        String,
+        "right",
+        2..=2,
+        "right(s, n): the last n characters of s, or all but the first -n ones.",
+        |ctx, _| substring(ctx, Side::Right)
+    ),
+    scalar!(String, "repeat", 2..=2, "repeat(s, n): s repeated n times.", |ctx, limits| {
+        let (s, n) = (text(ctx.get_raw(0)), ctx.get::<i64>(1)?);
+        let n = n.max(0) as usize;
+        check_length(s.len().saturating_mul(n), limits)?;
+        Ok(Value::Text(s.repeat(n)))
+    }),
+    scalar!(String, "reverse", 1..=1, "reverse(s): the characters of s in reverse order.", |ctx, _| {
+        Ok(Value::Text(text(ctx.get_raw(0)).chars().rev().collect()))
+    }),
+    scalar!(
+        String,
+        "split_part",
+        3..=3,
+        "split_part(s, delimiter, n): the n-th field of s split by delimiter, counting from the end if n is negative.",
+        |ctx, _| {
+            let (s, delimiter, n) = (text(ctx.get_raw(0)), text(ctx.get_raw(1)), ctx.get::<i64>(2)?);
+            let fields = match delimiter.is_empty() {
+                true => vec![s.as_str()],
+                false => s.split(delimiter.as_str()).collect::<Vec<_>>(),
+            };
+            let index = match n {
+                0 => return Err(user_error("split_part(): field position must not be zero")),
+                n if n > 0 => (n - 1) as usize,
+                n => fields.len().wrapping_sub(n.unsigned_abs() as usize),
+            };
+            Ok(Value::Text(fields.get(index).copied().unwrap_or_default().to_string()))
+        }
+    ),
+    scalar!(String, "starts_with", 2..=2, "starts_with(s, prefix): 1 if s starts with prefix, else 0.", |ctx, _| {
+        let (s, prefix) = (text(ctx.get_raw(0)), text(ctx.get_raw(1)));
+        Ok(Value::Integer(s.starts_with(&prefix) as i64))
+    }),
+    // regexp
+    scalar!(
+        Regexp,
+        "regexp",
+        2..=2,
+        "regexp(pattern, s): 1 if s matches pattern, else 0; used by `s REGEXP pattern`.",
+        |ctx, _| {
+            let regex = regex(ctx, 0)?;
+            Ok(Value::Integer(regex.is_match(&text(ctx.get_raw(1))) as i64))
+        }
+    ),
+    scalar!(
+        Regexp,
+        "regexp_like",
+        2..=2,
+        "regexp_like(s, pattern): 1 if s matches pattern, else 0.",
+        |ctx, _| {
+            let regex = regex(ctx, 1)?;
+            Ok(Value::Integer(regex.is_match(&text(ctx.get_raw(0))) as i64))
+        }
+    ),
+    scalar!(
+        Regexp,
+        "regexp_substr",
+        2..=2,
+        "regexp_substr(s, pattern): the first match of pattern in s, or NULL.",
+        |ctx, _| {
+            let regex = regex(ctx, 1)?;
+            Ok(regex
+                .find(&text(ctx.get_raw(0)))
+                .map_or(Value::Null, |m| Value::Text(m.as_str().to_string())))
+        }
+    ),
+    scalar!(
+        Regexp,
+        "regexp_replace",
+        3..=3,
+        "regexp_replace(s, pattern, replacement): s with every match of pattern replaced; $1 refers to a group.",
+        |ctx, limits| {
+            let regex = regex(ctx, 1)?;
+            let (s, replacement) = (text(ctx.get_raw(0)), text(ctx.get_raw(2)));
+            // A group is part of its match, so each reference in the
+            // replacement expands to at most the length of the match.
+            let references = replacement.matches('$').count();
+
+            // The result is checked before each match is expanded, so it is
+            // not allocated past the limit.
+            let mut replaced = String::new();
+            let mut last = 0;
+            for captures in regex.captures_iter(&s) {
+                let m = captures.get(0).expect("the whole match");
+                let expanded = replacement.len().saturating_add(references.saturating_mul(m.len()));
+                check_length(
+                    (replaced.len() + m.start() - last).saturating_add(expanded),
+                    limits,
+                )?;
+                replaced.push_str(&s[last..m.start()]);
+                captures.expand(&replacement, &mut replaced);
+                last = m.end();
+            }
+            replaced.push_str(&s[last..]);
+            check_length(replaced.len(), limits)?;
+            Ok(Value::Text(replaced))
+        }
+    ),
+    // date
+    scalar!(
+        Date,
+        "date_trunc",
+        2..=2,
+        "date_trunc(unit, time): time truncated to the year, quarter, month, week, day, hour, minute or second, as YYYY-MM-DD HH:MM:SS.",
+        |ctx, _| date_trunc(&text(ctx.get_raw(0)), &text(ctx.get_raw(1)))
+    ),
+    // statistics
+    statistic!("stddev", "stddev(x): alias of stddev_samp(x).", Moments::stddev_samp),
+    statistic!("stddev_samp", "stddev_samp(x): the sample standard deviation of x.", Moments::stddev_samp),
+    statistic!("stddev_pop", "stddev_pop(x): the population standard deviation of x.", Moments::stddev_pop),
+    statistic!("variance", "variance(x): alias of var_samp(x).", Moments::var_samp),
+    statistic!("var_samp", "var_samp(x): the sample variance of x.", Moments::var_samp),
+    statistic!("var_pop", "var_pop(x): the population variance of x.", Moments::var_pop),
+];
+
+/// The functions of the profile, in the order of the registry.
+pub fn list(profile: FunctionProfile) -> impl Iterator<Item = &'static FunctionInfo> {
+    REGISTRY
+        .iter()
+        .map(|entry| &entry.info)
+        .filter(move |_| profile.includes_extensions())
+}
+
+/// Install the functions of the profile on the connection.
+///
+/// The results of the functions are limited to the length limit of the
+/// connection, which should be set beforehand.
+pub fn install(
+    conn: &rusqlite::Connection,
+    profile: FunctionProfile,
+) -> Result<(), rusqlite::Error> {
+    if !profile.includes_extensions() {
+        return Ok(());
+    }
+
+    let limits = Limits {
+        max_length: conn.limit(Limit::SQLITE_LIMIT_LENGTH).max(0) as usize,
+    };
+    let flags = FunctionFlags::SQLITE_UTF8
+        | FunctionFlags::SQLITE_DETERMINISTIC
+        | FunctionFlags::SQLITE_INNOCUOUS;
+
+    for entry in REGISTRY {
+        let info = entry.info;
+        match entry.implementation {
+            Implementation::Scalar(f) => {
+                conn.create_scalar_function(info.name, -1, flags, move |ctx| {
+                    check_args(ctx, &info)?;
+                    // Every function returns NULL for a NULL argument.
+                    if (0..ctx.len()).any(|i| ctx.get_raw(i) == ValueRef::Null) {
+                        return Ok(Value::Null);
+                    }
+                    f(ctx, limits)
+                })?;
+            }
+            Implementation::Statistic(finalize) => {
+                conn.create_aggregate_function(info.name, 1, flags, Statistic { finalize })?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn check_args(ctx: &Context<'_>, info: &FunctionInfo) -> rusqlite::Result<()> {
+    if (info.min_args..=info.max_args).contains(&ctx.len()) {
+        return Ok(());
+    }
+
+    Err(user_error(format!(
+        "wrong number of arguments to function {}()",
+        info.name
+    )))
+}
+
+fn check_length(length: usize, limits: Limits) -> rusqlite::Result<()> {
+    if length <= limits.max_length {
+        return Ok(());
+    }
+
+    Err(rusqlite::Error::SqliteFailure(
+        ffi::Error::new(ffi::SQLITE_TOOBIG),
+        // A message would replace the code by SQLITE_ERROR.
+        None,
+    ))
+}
+
+fn user_error(message: impl Into<String>) -> rusqlite::Error {
+    rusqlite::Error::UserFunctionError(message.into().into())
+}
+
+/// The numeric value of the argument, or `None` if it is not a number.
+fn number(value: ValueRef<'_>) -> Option<f64> {
+    match value {
+        ValueRef::Integer(i) => Some(i as f64),
+        ValueRef::Real(f) => Some(f),
+        ValueRef::Text(t) => std::str::from_utf8(t).ok()?.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+/// The text value of the argument, as SQLite converts it.
+fn text(value: ValueRef<'_>) -> String {
+    match value {
+        ValueRef::Null => String::new(),
+        ValueRef::Integer(i) => i.to_string(),
+        ValueRef::Real(f) => real_text(f),
+        ValueRef::Text(t) | ValueRef::Blob(t) => String::from_utf8_lossy(t).into_owned(),
+    }
+}
+
+/// The text of the real as SQLite converts it, with 15 significant digits
+/// and at least one after the decimal point, such as `1.0` or `1.0e+20`.
+fn real_text(f: f64) -> String {
+    let mut buf = [0u8; 32];
+    // SAFETY: SQLite writes at most the length of the buffer, including the
+    // NUL terminator, and the format takes the one double given.
+    unsafe {
+        ffi::sqlite3_snprintf(
+            buf.len() as _,
+            buf.as_mut_ptr().cast(),
+            c"%!.15g".as_ptr(),
+            f,
+        );
+    }
+    CStr::from_bytes_until_nul(&buf).map_or_else(
+        |_| f.to_string(),
+        |text| text.to_string_lossy().into_owned(),
+    )
+}
+
+/// Wrap a real result, which is NULL if it is out of the domain.
+fn real(result: f64) -> Value {
+    match result.is_nan() {
+        true => Value::Null,
+        false => Value::Real(result),
+    }
+}
+
+fn math1(ctx: &Context<'_>, f: fn(f64) -> f64) -> rusqlite::Result<Value> {
+    Ok(number(ctx.get_raw(0)).map_or(Value::Null, |x| real(f(x))))
+}
+
+fn math2(ctx: &Context<'_>, f: impl Fn(f64, f64) -> f64) -> rusqlite::Result<Value> {
+    match (number(ctx.get_raw(0)), number(ctx.get_raw(1))) {
+        (Some(x), Some(y)) => Ok(real(f(x, y))),
+        _ => Ok(Value::Null),
+    }
+}
+
+/// Round the argument, keeping integers as they are.
+fn rounding(ctx: &Context<'_>, f: fn(f64) -> f64) -> rusqlite::Result<Value> {
+    match ctx.get_raw(0) {
+        ValueRef::Integer(i) => Ok(Value::Integer(i)),
+        _ => math1(ctx, f),
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Side {
+    Left,
+    Right,
+}
+
+fn pad(ctx: &Context<'_>, limits: Limits, side: Side) -> rusqlite::Result<Value> {
+    let s = text(ctx.get_raw(0));
+    let n = ctx.get::<i64>(1)?.max(0) as usize;
+    let fill = match ctx.len() {
+        3 => text(ctx.get_raw(2)),
+        _ => " ".to_string(),
+    };
+
+    let length = s.chars().count();
+    if length >= n || fill.is_empty() {
+        return Ok(Value::Text(s.chars().take(n).collect()));
+    }
+
+    let fill_length = n - length;
+    check_length(
+        s.len() + fill_length.saturating_mul(fill.len().div_ceil(fill.chars().count())),
+        limits,
+    )?;
+    let padding = fill.chars().cycle().take(fill_length).collect::<String>();
+
+    Ok(Value::Text(match side {
+        Side::Left => padding + &s,
+        Side::Right => s + &padding,
+    }))
+}
+
+fn substring(ctx: &Context<'_>, side: Side) -> rusqlite::Result<Value> {
+    let s = text(ctx.get_raw(0));
+    let n = ctx.get::<i64>(1)?;
+
+    let length = s.chars().count();
+    let taken = match n {
+        n if n >= 0 => (n as usize).min(length),
+        n => length.saturating_sub(n.unsigned_abs() as usize),
+    };
+
+    Ok(Value::Text(match side {
+        Side::Left => s.chars().take(taken).collect(),
+        Side::Right => s.chars().skip(length - taken).collect(),
+    }))
+}
+
+/// Compile the pattern in the argument, reusing the compiled one for the
+/// other rows of the statement.
+fn regex(ctx: &Context<'_>, arg: usize) -> rusqlite::Result<Arc<Regex>> {
+    ctx.get_or_create_aux(arg as i32, |pattern| {
+        RegexBuilder::new(&text(pattern))
+            .size_limit(MAX_REGEX_BYTES)
+            .build()
+    })
+}
+
+/// Truncate the time to the unit, as `date_trunc` of PostgreSQL does.
+///
+/// Returns NULL if the time is not in a format SQLite understands.
+fn date_trunc(unit: &str, time: &str) -> rusqlite::Result<Value> {
+    let Some((year, month, day, hour, minute, second)) = parse_time(time) else {
+        return Ok(Value::Null);
+    };
+
+    let (year, month, day, hour, minute, second) = match unit.to_lowercase().as_str() {
+        "year" => (year, 1, 1, 0, 0, 0),
+        "quarter" => (year, (month - 1) / 3 * 3 + 1, 1, 0, 0, 0),
+        "month" => (year, month, 1, 0, 0, 0),
+        "week" => {
+            // Weeks start on Monday, as in ISO 8601.
+            let days = days_from_civil(year, month, day);
+            let monday = days - (days + 3).rem_euclid(7);
+            let (year, month, day) = civil_from_days(monday);
+            (year, month, day, 0, 0, 0)
+        }
+        "day" => (year, month, day, 0, 0, 0),
+        "hour" => (year, month, day, hour, 0, 0),
+        "minute" => (year, month, day, hour, minute, 0),
+        "second" => (year, month, day, hour, minute, second),
+        _ => return Err(user_error(format!("date_trunc(): unknown unit '{unit}'"))),
+    };
+
+    Ok(Value::Text(format!(
+        "{year:04}-{month:02}-{day:02} {hour:02}:{minute:02}:{second:02}"
+    )))
+}
+
+/// Parse `YYYY-MM-DD`, optionally followed by ` HH:MM[:SS[.SSS]]` or with
+/// `T` as the separator.
+fn parse_time(time: &str) -> Option<(i64, i64, i64, i64, i64, i64)> {
+    let time = time.trim();
+    let field = |s: &str, digits: usize| -> Option<i64> {
+        (s.len() == digits && s.bytes().all(|b| b.is_ascii_digit())).then(|| s.parse().ok())?
+    };
+
+    let (date, clock) = match time.find([' ', 'T']) {
+        Some(i) => (&time[..i], Some(&time[i + 1..])),
+        None => (time, None),
+    };
+
+    let mut date = date.split('-');
+    let year = field(date.next()?, 4)?;
+    let month = field(date.next()?, 2)?;
+    let day = field(date.next()?, 2)?;
+    if date.next().is_some() || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+
+    let (hour, minute, second) = match clock {
+        None => (0, 0, 0),
+        Some(clock) => {
+            let clock = clock.split_once('.').map_or(clock, |(clock, _)| clock);
+            let mut clock = clock.split(':');
+            let hour = field(clock.next()?, 2)?;
+            let minute = field(clock.next()?, 2)?;
+            let second = clock.next().map_or(Some(0), |s| field(s, 2))?;
+            if clock.next().is_some() || hour > 24 || minute > 59 || second > 59 {
+                return None;
+            }
+            (hour, minute, second)
+        }
+    };
+
+    Some((year, month, day, hour, minute, second))
+}
+
+/// The days since 1970-01-01 of the date in the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// The date of the days since 1970-01-01 in the proleptic Gregorian calendar.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+/// The running count, mean and sum of squared deviations of the values, by
+/// the algorithm of Welford.
+#[derive(Debug, Default)]
+struct Moments {
+    count: u64,
+    mean: f64,
+    m2: f64,
+}
+
+impl Moments {
+    fn push(&mut self, x: f64) {
+        self.count += 1;
+        let delta = x - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (x - self.mean);
+    }
+
+    fn var_samp(&self) -> Option<f64> {
+        (self.count > 1).then(|| self.m2 / (self.count - 1) as f64)
+    }
+
+    fn var_pop(&self) -> Option<f64> {
+        (self.count > 0).then(|| self.m2 / self.count as f64)
+    }
+
+    fn stddev_samp(&self) -> Option<f64> {
+        self.var_samp().map(f64::sqrt)
+    }
+
+    fn stddev_pop(&self) -> Option<f64> {
+        self.var_pop().map(f64::sqrt)
+    }
+}
+
+/// An aggregate computing a statistic of the non-NULL numeric values.
+struct Statistic {
+    finalize: fn(&Moments) -> Option<f64>,
+}
+
+impl Aggregate<Moments, Option<f64>> for Statistic {
+    fn init(&self, _: &mut Context<'_>) -> rusqlite::Result<Moments> {
+        Ok(Moments::default())
+    }
+
+    fn step(&self, ctx: &mut Context<'_>, moments: &mut Moments) -> rusqlite::Result<()> {
+        if let Some(x) = number(ctx.get_raw(0)) {
+            moments.push(x);
+        }
+        Ok(())
+    }
+
+    fn finalize(
+        &self,
+        _: &mut Context<'_>,
+        moments: Option<Moments>,
+    ) -> rusqlite::Result<Option<f64>> {
+        Ok(moments.as_ref().and_then(self.finalize))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(conn: &rusqlite::Connection, sql: &str) -> Value {
+        conn.query_row(sql, (), |row| row.get(0))
+            .unwrap_or_else(|e| panic!("{sql}: {e}"))
+    }
+
+    #[test]
+    fn test_functions() {
+        let conn = rusqlite::Connection::open_in_memory().expect("open");
+        install(&conn, FunctionProfile::Extended).expect("install");
+
+        let cases = [
+            ("SELECT power(2, 10)", Value::Real(1024.0)),
+            ("SELECT sqrt(-1)", Value::Null),
+            ("SELECT floor(7)", Value::Integer(7)),
+            ("SELECT mod(7, 3)", Value::Integer(1)),
+            ("SELECT log(100)", Value::Real(2.0)),
+            ("SELECT lpad('7', 3, '0')", Value::Text("007".to_string())),
+            ("SELECT rpad('abcd', 2)", Value::Text("ab".to_string())),
+            // The reals are converted to text as SQLite does.
+            ("SELECT lpad(1.0, 5, '0')", Value::Text("001.0".to_string())),
+            ("SELECT right(1e20, 4)", Value::Text("e+20".to_string())),
+            ("SELECT left('hello', -2)", Value::Text("hel".to_string())),
+            ("SELECT right('hello', 2)", Value::Text("lo".to_string())),
+            (
+                "SELECT split_part('a,b,c', ',', -1)",
+                Value::Text("c".to_string()),
+            ),
+            ("SELECT 'abc' REGEXP '^a.c$'", Value::Integer(1)),
+            (
+                "SELECT regexp_replace('2024-01-31', '(\\d+)-(\\d+)-(\\d+)', '$3/$2/$1')",
+                Value::Text("31/01/2024".to_string()),
+            ),
+            (
+                "SELECT date_trunc('month', '2024-02-29 13:14:15')",
+                Value::Text("2024-02-01 00:00:00".to_string()),
+            ),
+            (
+                "SELECT date_trunc('week', '2024-03-03')",
+                Value::Text("2024-02-26 00:00:00".to_string()),
+            ),
+            ("SELECT date_trunc('day', 'yesterday')", Value::Null),
+            (
+                "SELECT var_samp(x) FROM (SELECT 1 AS x UNION ALL SELECT 2 UNION ALL SELECT 3)",
+                Value::Real(1.0),
+            ),
+            ("SELECT stddev(x) FROM (SELECT 1 AS x)", Value::Null),
+            ("SELECT upper(lpad(NULL, 3))", Value::Null),
+        ];
+        for (sql, expected) in cases {
+            assert_eq!(query(&conn, sql), expected, "{sql}");
+        }
+    }
+
+    #[test]
+    fn test_limits() {
+        let conn = rusqlite::Connection::open_in_memory().expect("open");
+        conn.set_limit(Limit::SQLITE_LIMIT_LENGTH, 100);
+        install(&conn, FunctionProfile::Extended).expect("install");
+
+        let error = conn
+            .query_row("SELECT repeat('ab', 51)", (), |row| row.get::<_, String>(0))
+            .expect_err("result should be too big");
+        assert_eq!(error.sqlite_error_code(), Some(rusqlite::ErrorCode::TooBig));
+
+        let error = conn
+            .query_row(
+                "SELECT regexp_replace(printf('%.60c', 'a'), 'a', '$0$0')",
+                (),
+                |row| row.get::<_, String>(0),
+            )
+            .expect_err("result should be too big");
+        assert_eq!(error.sqlite_error_code(), Some(rusqlite::ErrorCode::TooBig));
+        let replaced = conn
+            .query_row(
+                "SELECT regexp_replace(printf('%.50c', 'a'), 'a', 'bb')",
+                (),
+                |row| row.get::<_, String>(0),
+            )
+            .expect("result should fit");
+        assert_eq!(replaced.len(), 100);
+
+        let error = conn
+            .query_row("SELECT lpad('a')", (), |row| row.get::<_, String>(0))
+            .expect_err("arguments should be checked");
+        assert!(error.to_string().contains("wrong number of arguments"));
+    }
+
+    #[test]
+    fn test_sqlite_profile() {
+        let conn = rusqlite::Connection::open_in_memory().expect("open");
+        install(&conn, FunctionProfile::Sqlite).expect("install");
+
+        assert!(conn
+            .query_row("SELECT power(2, 2)", (), |_| Ok(()))
+            .is_err());
+        assert_eq!(list(FunctionProfile::Sqlite).count(), 0);
+    }
+
+    #[test]
+    fn test_no_builtin_overridden() {
+        let conn = rusqlite::Connection::open_in_memory().expect("open");
+        let mut stmt = conn
+            .prepare("SELECT name FROM pragma_function_list")
+            .expect("prepare");
+        let builtins = stmt
+            .query_map((), |row| row.get::<_, String>(0))
+            .expect("query")
+            .collect::<Result<Vec<_>, _>>()
+            .expect("collect");
+
+        for info in list(FunctionProfile::Extended) {
+            assert!(!builtins.iter().any(|b| b == info.name), "{}", info.name);
+        }
+    }
+}
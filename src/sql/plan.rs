@@ -0,0 +1,136 @@
+//! The query plan of a statement, as a tree.
+
+use super::PlanNode;
+
+/// Explain the query plan of the statement in `sql`.
+///
+/// `EXPLAIN QUERY PLAN` reports the steps as rows referencing their parent,
+/// which are nested into a tree.
+pub fn explain(conn: &rusqlite::Connection, sql: &str) -> Result<Vec<PlanNode>, rusqlite::Error> {
+    let mut stmt = conn.prepare(&format!("EXPLAIN QUERY PLAN {sql}"))?;
+    let rows = stmt
+        .query_map([], |row| {
+            Ok((
+                row.get::<_, i64>("id")?,
+                row.get::<_, i64>("parent")?,
+                row.get::<_, String>("detail")?,
+            ))
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(nest(rows))
+}
+
+/// Nest the `(id, parent, detail)` rows into a tree.
+///
+/// SQLite lists a step right after its parent or its previous sibling, so
+/// the path from the root to the last step is enough to place the next one.
+/// A step whose parent is not on the path becomes a root.
+fn nest(rows: impl IntoIterator<Item = (i64, i64, String)>) -> Vec<PlanNode> {
+    let mut roots = Vec::new();
+    let mut path = Vec::<PlanNode>::new();
+
+    for (id, parent, detail) in rows {
+        while path.last().is_some_and(|node| node.id != parent) {
+            close(&mut path, &mut roots);
+        }
+        path.push(PlanNode {
+            id,
+            parent,
+            detail,
+            children: Vec::new(),
+        });
+    }
+    while !path.is_empty() {
+        close(&mut path, &mut roots);
+    }
+
+    roots
+}
+
+/// Move the last step of the path into its parent.
+fn close(path: &mut Vec<PlanNode>, roots: &mut Vec<PlanNode>) {
+    let Some(node) = path.pop() else {
+        return;
+    };
+
+    match path.last_mut() {
+        Some(parent) => parent.children.push(node),
+        None => roots.push(node),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node(id: i64, parent: i64, detail: &str, children: Vec<PlanNode>) -> PlanNode {
+        PlanNode {
+            id,
+            parent,
+            detail: detail.to_string(),
+            children,
+        }
+    }
+
+    #[test]
+    fn test_nest() {
+        let rows = [
+            (2, 0, "COMPOUND QUERY"),
+            (3, 2, "LEFT-MOST SUBQUERY"),
+            (5, 3, "SCAN a"),
+            (8, 2, "UNION ALL"),
+            (10, 8, "SCAN b"),
+            (20, 0, "USE TEMP B-TREE FOR ORDER BY"),
+        ]
+        .map(|(id, parent, detail)| (id, parent, detail.to_string()));
+
+        assert_eq!(
+            nest(rows),
+            vec![
+                node(
+                    2,
+                    0,
+                    "COMPOUND QUERY",
+                    vec![
+                        node(
+                            3,
+                            2,
+                            "LEFT-MOST SUBQUERY",
+                            vec![node(5, 3, "SCAN a", vec![])]
+                        ),
+                        node(8, 2, "UNION ALL", vec![node(10, 8, "SCAN b", vec![])]),
+                    ]
+                ),
+                node(20, 0, "USE TEMP B-TREE FOR ORDER BY", vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_explain() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE a (id INTEGER PRIMARY KEY, b_id INTEGER);
+             CREATE TABLE b (id INTEGER PRIMARY KEY, name TEXT);
+             CREATE INDEX b_name ON b (name);",
+        )
+        .unwrap();
+
+        let plan = explain(
+            &conn,
+            "SELECT * FROM a JOIN b ON a.b_id = b.id WHERE b.name = 'x'",
+        )
+        .unwrap();
+        let details = plan.iter().map(|n| n.detail.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            details,
+            vec!["SCAN a", "SEARCH b USING INTEGER PRIMARY KEY (rowid=?)"]
+        );
+
+        assert_eq!(
+            explain(&conn, "CREATE TABLE c (id INTEGER)").unwrap(),
+            vec![]
+        );
+    }
+}
@@ -0,0 +1,280 @@
+//! The dedicated threads running the SQLite executions.
+
+use std::{
+    fmt,
+    panic::{catch_unwind, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use tokio::sync::oneshot;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// The queue of the pool is full.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct QueueFull {
+    /// The estimated time before a slot in the queue frees up.
+    pub retry_after: Duration,
+}
+
+/// The counters of a [`WorkerPool`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// The number of jobs waiting for a worker.
+    pub queue_depth: usize,
+    /// The number of jobs picked up by a worker.
+    pub started: u64,
+    /// The number of jobs rejected because the queue was full.
+    pub rejected: u64,
+    /// The number of jobs whose caller gave up on the result, which were
+    /// skipped if they had not started yet.
+    pub cancelled: u64,
+    /// The number of jobs whose result was no longer awaited once they
+    /// overran their timeout.
+    pub timed_out: u64,
+    /// The total time the started jobs waited in the queue.
+    pub total_wait: Duration,
+    /// The longest time a started job waited in the queue.
+    pub max_wait: Duration,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let average_wait = Duration::from_micros(
+            (self.total_wait.as_micros() as u64)
+                .checked_div(self.started)
+                .unwrap_or(0),
+        );
+
+        write!(
+            f,
+            "queue_depth={} started={} rejected={} cancelled={} timed_out={} average_wait={:?} max_wait={:?}",
+            self.queue_depth,
+            self.started,
+            self.rejected,
+            self.cancelled,
+            self.timed_out,
+            average_wait,
+            self.max_wait
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    queue_depth: AtomicUsize,
+    started: AtomicU64,
+    rejected: AtomicU64,
+    cancelled: AtomicU64,
+    timed_out: AtomicU64,
+    total_wait_micros: AtomicU64,
+    max_wait_micros: AtomicU64,
+}
+
+impl Counters {
+    fn record_wait(&self, wait: Duration) {
+        let wait_micros = wait.as_micros() as u64;
+
+        self.queue_depth.fetch_sub(1, Ordering::Relaxed);
+        self.started.fetch_add(1, Ordering::Relaxed);
+        self.total_wait_micros
+            .fetch_add(wait_micros, Ordering::Relaxed);
+        self.max_wait_micros
+            .fetch_max(wait_micros, Ordering::Relaxed);
+    }
+}
+
+/// A fixed number of worker threads fed by a bounded queue.
+#[derive(Debug)]
+pub struct WorkerPool {
+    sender: mpsc::SyncSender<(Instant, Job)>,
+    counters: Arc<Counters>,
+}
+
+impl WorkerPool {
+    /// Spawn `workers` threads sharing a queue of `queue_size` jobs.
+    pub fn new(workers: usize, queue_size: usize) -> Self {
+        let workers = workers.max(1);
+        let (sender, receiver) = mpsc::sync_channel::<(Instant, Job)>(queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let counters = Arc::new(Counters::default());
+
+        for i in 0..workers {
+            let receiver = receiver.clone();
+            let counters = counters.clone();
+
+            std::thread::Builder::new()
+                .name(format!("dbrunner-sqlite-{i}"))
+                .spawn(move || loop {
+                    // The lock is released before the job runs.
+                    let Ok((queued_at, job)) = receiver.lock().unwrap().recv() else {
+                        // The pool has been dropped.
+                        return;
+                    };
+                    counters.record_wait(queued_at.elapsed());
+
+                    // A panicking job drops its result sender, which is
+                    // reported to the caller; the worker keeps serving.
+                    let _ = catch_unwind(AssertUnwindSafe(job));
+                })
+                .expect("spawn SQLite worker");
+        }
+
+        Self { sender, counters }
+    }
+
+    /// Queue the job and return the receiver of its result.
+    pub fn submit<T: Send + 'static>(
+        &self,
+        job: impl FnOnce() -> T + Send + 'static,
+    ) -> Result<oneshot::Receiver<T>, QueueFull> {
+        let (tx, rx) = oneshot::channel();
+        let counters = self.counters.clone();
+        let job: Job = Box::new(move || {
+            // The caller may have given up on the result, before or while the
+            // job ran.
+            if tx.is_closed() || tx.send(job()).is_err() {
+                counters.cancelled.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+
+        self.counters.queue_depth.fetch_add(1, Ordering::Relaxed);
+        match self.sender.try_send((Instant::now(), job)) {
+            Ok(()) => Ok(rx),
+            Err(_) => {
+                self.counters.queue_depth.fetch_sub(1, Ordering::Relaxed);
+                self.counters.rejected.fetch_add(1, Ordering::Relaxed);
+                Err(QueueFull {
+                    retry_after: self.estimate_wait(),
+                })
+            }
+        }
+    }
+
+    /// Stop awaiting the result of a job that overran its timeout.
+    ///
+    /// The result is still received, and dropped, so that the job is not
+    /// counted as cancelled by its caller.
+    pub fn time_out<T: Send + 'static>(&self, result: oneshot::Receiver<T>) {
+        self.counters.timed_out.fetch_add(1, Ordering::Relaxed);
+        tokio::spawn(async move {
+            let _ = result.await;
+        });
+    }
+
+    /// Estimate how long a new job would wait, from the average wait so far.
+    fn estimate_wait(&self) -> Duration {
+        const MIN_RETRY_AFTER: Duration = Duration::from_millis(100);
+
+        let started = self.counters.started.load(Ordering::Relaxed);
+        let total_wait_micros = self.counters.total_wait_micros.load(Ordering::Relaxed);
+        let average_wait =
+            Duration::from_micros(total_wait_micros.checked_div(started).unwrap_or(0));
+
+        average_wait.max(MIN_RETRY_AFTER)
+    }
+
+    pub fn stats(&self) -> Stats {
+        Stats {
+            queue_depth: self.counters.queue_depth.load(Ordering::Relaxed),
+            started: self.counters.started.load(Ordering::Relaxed),
+            rejected: self.counters.rejected.load(Ordering::Relaxed),
+            cancelled: self.counters.cancelled.load(Ordering::Relaxed),
+            timed_out: self.counters.timed_out.load(Ordering::Relaxed),
+            total_wait: Duration::from_micros(
+                self.counters.total_wait_micros.load(Ordering::Relaxed),
+            ),
+            max_wait: Duration::from_micros(self.counters.max_wait_micros.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Barrier;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_queue_full() {
+        let pool = WorkerPool::new(1, 1);
+        let barrier = Arc::new(Barrier::new(2));
+
+        // occupies the worker until the barrier is released.
+        let blocker = {
+            let barrier = barrier.clone();
+            pool.submit(move || {
+                barrier.wait();
+            })
+            .expect("submit the blocker")
+        };
+        // wait for the worker to pick up the blocker.
+        while pool.stats().started == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        let queued = pool.submit(|| 42).expect("submit to the queue");
+        let rejected = pool.submit(|| 0);
+        assert!(rejected.is_err(), "queue should be full");
+
+        barrier.wait();
+        blocker.await.expect("blocker result");
+        assert_eq!(queued.await.expect("queued result"), 42);
+
+        let stats = pool.stats();
+        assert_eq!(stats.queue_depth, 0);
+        assert_eq!(stats.started, 2);
+        assert_eq!(stats.rejected, 1);
+        assert!(
+            stats.to_string().starts_with(
+                "queue_depth=0 started=2 rejected=1 cancelled=0 timed_out=0 average_wait="
+            ),
+            "{stats}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_jobs() {
+        let pool = WorkerPool::new(1, 2);
+        let barrier = Arc::new(Barrier::new(2));
+
+        let running = {
+            let barrier = barrier.clone();
+            pool.submit(move || {
+                barrier.wait();
+            })
+            .expect("submit the running job")
+        };
+        while pool.stats().started == 0 {
+            tokio::task::yield_now().await;
+        }
+        let queued = pool.submit(|| panic!("should be skipped")).expect("submit");
+
+        // Give up on both results.
+        drop(running);
+        drop(queued);
+        barrier.wait();
+
+        while pool.stats().started < 2 {
+            tokio::task::yield_now().await;
+        }
+        let result = pool.submit(|| 1).expect("submit");
+        assert_eq!(result.await.expect("result"), 1);
+        assert_eq!(pool.stats().cancelled, 2);
+    }
+
+    #[tokio::test]
+    async fn test_panicking_job() {
+        let pool = WorkerPool::new(1, 1);
+
+        let result = pool.submit(|| panic!("boom")).expect("submit");
+        assert!(result.await.is_err(), "result should not be sent");
+
+        let result = pool.submit(|| 1).expect("submit");
+        assert_eq!(result.await.expect("worker should survive"), 1);
+    }
+}
@@ -0,0 +1,233 @@
+//! The SQLite configuration profiles, chosen by name per query.
+
+use std::{collections::BTreeMap, str::FromStr};
+
+use rusqlite::config::DbConfig;
+use serde::{Deserialize, Serialize};
+use sql_insight::sqlparser::{
+    dialect::SQLiteDialect,
+    keywords::Keyword,
+    tokenizer::{Location, Token, TokenWithLocation, Tokenizer},
+};
+
+use super::statements::Position;
+
+/// The semantics of SQLite an execution runs with.
+///
+/// The settings left unset keep the defaults of the bundled SQLite, which
+/// enforces the foreign keys.
+#[derive(Clone, Debug, Default, Hash, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SqliteProfile {
+    /// Enforce the foreign key constraints.
+    pub foreign_keys: Option<bool>,
+
+    /// Make `LIKE` case sensitive for ASCII characters.
+    pub case_sensitive_like: Option<bool>,
+
+    /// Let the triggers fire other triggers, and themselves.
+    pub recursive_triggers: Option<bool>,
+
+    /// Keep the references to a renamed table or column in the triggers and
+    /// views as they are, as SQLite did before 3.26.
+    pub legacy_alter_table: Option<bool>,
+
+    /// Accept the double-quoted string literals, such as `"text"`, when they
+    /// do not match an identifier.
+    pub double_quoted_strings: Option<bool>,
+
+    /// Create the tables as `STRICT` unless they are created from a `SELECT`.
+    pub strict_tables: bool,
+}
+
+impl SqliteProfile {
+    /// Apply the settings to the connection, before running any SQL.
+    pub fn apply(&self, conn: &rusqlite::Connection) -> Result<(), rusqlite::Error> {
+        if let Some(enabled) = self.foreign_keys {
+            conn.set_db_config(DbConfig::SQLITE_DBCONFIG_ENABLE_FKEY, enabled)?;
+        }
+        if let Some(enabled) = self.case_sensitive_like {
+            conn.pragma_update(None, "case_sensitive_like", enabled)?;
+        }
+        if let Some(enabled) = self.recursive_triggers {
+            conn.pragma_update(None, "recursive_triggers", enabled)?;
+        }
+        if let Some(enabled) = self.legacy_alter_table {
+            conn.set_db_config(DbConfig::SQLITE_DBCONFIG_LEGACY_ALTER_TABLE, enabled)?;
+        }
+        if let Some(enabled) = self.double_quoted_strings {
+            conn.set_db_config(DbConfig::SQLITE_DBCONFIG_DQS_DML, enabled)?;
+            conn.set_db_config(DbConfig::SQLITE_DBCONFIG_DQS_DDL, enabled)?;
+        }
+
+        Ok(())
+    }
+
+    /// Rewrite the SQL for the settings SQLite has no option for.
+    pub fn rewrite(&self, sql: &str) -> String {
+        if self.strict_tables {
+            strict_tables(sql)
+        } else {
+            sql.to_string()
+        }
+    }
+}
+
+/// The profiles by name, parsed from their JSON representation.
+///
+/// The profile named by the empty string is the default one, which keeps
+/// the defaults of the bundled SQLite unless it is configured.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct SqliteProfiles(pub BTreeMap<String, SqliteProfile>);
+
+impl SqliteProfiles {
+    /// The profile with the name, if any.
+    pub fn get(&self, name: &str) -> Option<SqliteProfile> {
+        match self.0.get(name) {
+            Some(profile) => Some(profile.clone()),
+            None if name.is_empty() => Some(SqliteProfile::default()),
+            None => None,
+        }
+    }
+}
+
+impl FromStr for SqliteProfiles {
+    type Err = serde_json::Error;
+
+    /// Parse the profiles from their JSON representation.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_str(s)
+    }
+}
+
+/// Add `STRICT` to the options of the `CREATE TABLE` statements.
+///
+/// The statements are found with the tokenizer, so the rest of the SQL is
+/// kept as is. SQL that cannot be tokenized is returned as is, for SQLite to
+/// report the error.
+fn strict_tables(sql: &str) -> String {
+    let Ok(tokens) = Tokenizer::new(&SQLiteDialect {}, sql).tokenize_with_location() else {
+        return sql.to_string();
+    };
+    let tokens = tokens
+        .into_iter()
+        .filter(|t| !matches!(t.token, Token::Whitespace(_)))
+        .collect::<Vec<_>>();
+
+    let mut insertions = Vec::new();
+    for statement in tokens.split(|t| t.token == Token::SemiColon) {
+        if let Some((location, has_options)) = table_options(statement) {
+            let insertion = if has_options { " STRICT," } else { " STRICT" };
+            if let Some(position) = Position::from_location(sql, location) {
+                insertions.push((position.offset + 1, insertion));
+            }
+        }
+    }
+
+    let mut rewritten = sql.to_string();
+    for (offset, insertion) in insertions.into_iter().rev() {
+        rewritten.insert_str(offset, insertion);
+    }
+    rewritten
+}
+
+/// The location of the parenthesis closing the columns of the `CREATE
+/// TABLE` statement, and whether table options follow it, unless it is not
+/// such a statement or is already `STRICT`.
+fn table_options(statement: &[TokenWithLocation]) -> Option<(Location, bool)> {
+    let keyword = |i: usize| match statement.get(i).map(|t| &t.token) {
+        Some(Token::Word(word)) => Some(word.keyword),
+        _ => None,
+    };
+
+    if keyword(0)? != Keyword::CREATE {
+        return None;
+    }
+    let table = match keyword(1)? {
+        Keyword::TEMP | Keyword::TEMPORARY => 2,
+        _ => 1,
+    };
+    if keyword(table)? != Keyword::TABLE {
+        return None;
+    }
+
+    // The columns start at the first parenthesis, unless the table is
+    // created from a SELECT.
+    let start = statement.iter().position(|t| match &t.token {
+        Token::LParen => true,
+        Token::Word(word) => word.keyword == Keyword::AS,
+        _ => false,
+    })?;
+    if statement[start].token != Token::LParen {
+        return None;
+    }
+
+    let mut depth = 0;
+    for (i, t) in statement.iter().enumerate().skip(start) {
+        match t.token {
+            Token::LParen => depth += 1,
+            Token::RParen => depth -= 1,
+            _ => continue,
+        }
+        if depth == 0 {
+            let options = &statement[i + 1..];
+            let is_strict = options.iter().any(|t| {
+                matches!(&t.token, Token::Word(word) if word.value.eq_ignore_ascii_case("strict"))
+            });
+            return (!is_strict).then_some((t.location, !options.is_empty()));
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::*;
+
+    #[rstest]
+    #[case("CREATE TABLE t (id INTEGER)", "CREATE TABLE t (id INTEGER) STRICT")]
+    #[case(
+        "create temp table if not exists \"t\" (id INTEGER, v TEXT CHECK (v <> ''));",
+        "create temp table if not exists \"t\" (id INTEGER, v TEXT CHECK (v <> '')) STRICT;"
+    )]
+    #[case(
+        "CREATE TABLE t (id INTEGER PRIMARY KEY) WITHOUT ROWID",
+        "CREATE TABLE t (id INTEGER PRIMARY KEY) STRICT, WITHOUT ROWID"
+    )]
+    #[case(
+        "CREATE TABLE t (id INTEGER) STRICT",
+        "CREATE TABLE t (id INTEGER) STRICT"
+    )]
+    #[case("CREATE TABLE t AS SELECT 1", "CREATE TABLE t AS SELECT 1")]
+    #[case("CREATE INDEX i ON t (id)", "CREATE INDEX i ON t (id)")]
+    #[case(
+        "-- 表\nCREATE TABLE a (id INT);\nINSERT INTO a VALUES ('(');\nCREATE TABLE b (id INT)",
+        "-- 表\nCREATE TABLE a (id INT) STRICT;\nINSERT INTO a VALUES ('(');\nCREATE TABLE b (id INT) STRICT"
+    )]
+    #[case("CREATE TABLE t (id INTEGER", "CREATE TABLE t (id INTEGER")]
+    fn test_strict_tables(#[case] sql: &str, #[case] expected: &str) {
+        assert_eq!(strict_tables(sql), expected, "Case {sql}");
+    }
+
+    #[test]
+    fn test_parse_profiles() {
+        let profiles: SqliteProfiles =
+            r#"{"legacy": {"foreign_keys": false, "legacy_alter_table": true}}"#
+                .parse()
+                .expect("valid profiles");
+
+        assert_eq!(
+            profiles.get("legacy"),
+            Some(SqliteProfile {
+                foreign_keys: Some(false),
+                legacy_alter_table: Some(true),
+                ..Default::default()
+            })
+        );
+        assert_eq!(profiles.get(""), Some(SqliteProfile::default()));
+        assert_eq!(profiles.get("unknown"), None);
+    }
+}
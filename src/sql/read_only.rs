@@ -0,0 +1,84 @@
+//! The check of the queries run in read-only mode.
+
+use sql_insight::sqlparser::{ast::Statement, dialect::SQLiteDialect, parser::Parser};
+
+use super::{authorizer::is_read_only_pragma, Error};
+
+/// Check that every statement of the query only reads the database.
+///
+/// The connection is also switched to query-only, so this check is there to
+/// report the offending statement before running any of them.
+pub fn check(sql: &str) -> Result<(), Error> {
+    // The SQL was formatted from the query, so a position in it would not
+    // point into the query.
+    let statements = Parser::parse_sql(&SQLiteDialect {}, sql).map_err(|e| Error::Format {
+        source: sql_insight::error::Error::from(e),
+        position: None,
+    })?;
+
+    match statements.iter().find(|statement| !is_read(statement)) {
+        Some(statement) => Err(Error::WriteNotAllowed {
+            statement: statement.to_string(),
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Whether the statement only reads the database.
+fn is_read(statement: &Statement) -> bool {
+    match statement {
+        Statement::Query(_) | Statement::ExplainTable { .. } => true,
+        Statement::Explain { statement, .. } => is_read(statement),
+        Statement::Pragma { name, value, .. } => {
+            value.is_none()
+                || name
+                    .0
+                    .last()
+                    .is_some_and(|name| is_read_only_pragma(&name.value))
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::*;
+
+    #[rstest]
+    #[case("SELECT * FROM students", None)]
+    #[case("WITH t AS (SELECT 1) SELECT * FROM t", None)]
+    #[case("SELECT 1; VALUES (1)", None)]
+    #[case("EXPLAIN SELECT * FROM students", None)]
+    #[case("PRAGMA user_version", None)]
+    #[case("PRAGMA user_version = 1", Some("PRAGMA user_version = 1"))]
+    #[case("PRAGMA table_info('students')", None)]
+    #[case("PRAGMA main.index_list('students')", None)]
+    #[case("PRAGMA foreign_keys = 1", Some("PRAGMA foreign_keys = 1"))]
+    #[case(
+        "SELECT 1; DELETE FROM students WHERE id = 1",
+        Some("DELETE FROM students WHERE id = 1")
+    )]
+    #[case("DROP TABLE students", Some("DROP TABLE students"))]
+    #[case(
+        "INSERT INTO students VALUES (1)",
+        Some("INSERT INTO students VALUES (1)")
+    )]
+    #[case(
+        "CREATE TEMP TABLE t (id INTEGER)",
+        Some("CREATE TEMPORARY TABLE t (id INTEGER)")
+    )]
+    #[case(
+        "EXPLAIN UPDATE students SET id = 2",
+        Some("EXPLAIN UPDATE students SET id = 2")
+    )]
+    fn test_check(#[case] sql: &str, #[case] offending: Option<&str>) {
+        match (check(sql), offending) {
+            (Ok(()), None) => {}
+            (Err(Error::WriteNotAllowed { statement }), Some(offending)) => {
+                assert_eq!(statement, offending, "Case {sql}")
+            }
+            (result, _) => panic!("Case {sql}: unexpected {result:?}"),
+        }
+    }
+}
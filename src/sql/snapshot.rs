@@ -0,0 +1,256 @@
+//! The in-process cache of the databases produced by the initial SQL.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    ptr::NonNull,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use rusqlite::{ffi, serialize::OwnedData, DatabaseName};
+
+use super::Blake3Hash;
+
+/// A serialized database with the connection state that is not part of it.
+#[derive(Debug)]
+pub struct Snapshot {
+    /// The serialized main database.
+    data: Vec<u8>,
+
+    /// The pragmas set by the initial SQL, in the order they were set.
+    pragmas: Vec<(String, String)>,
+}
+
+impl Snapshot {
+    /// Take the snapshot of the main database.
+    ///
+    /// Returns [`None`] if the connection has temporary objects, which are
+    /// not part of the main database.
+    pub fn take(
+        conn: &rusqlite::Connection,
+        pragmas: Vec<(String, String)>,
+    ) -> Result<Option<Self>, rusqlite::Error> {
+        let temp_objects: u64 =
+            conn.query_row("SELECT COUNT(*) FROM temp.sqlite_master", (), |row| {
+                row.get(0)
+            })?;
+        if temp_objects > 0 {
+            return Ok(None);
+        }
+
+        let data = conn.serialize(DatabaseName::Main)?.to_vec();
+        Ok(Some(Self { data, pragmas }))
+    }
+
+    /// Restore the snapshot to the main database of the connection.
+    pub fn restore(&self, conn: &mut rusqlite::Connection) -> Result<(), rusqlite::Error> {
+        // SAFETY: the buffer is allocated by sqlite3_malloc64 with the size of
+        // the data, and is fully initialized before being handed to SQLite.
+        let data = unsafe {
+            let ptr = ffi::sqlite3_malloc64(self.data.len() as u64).cast::<u8>();
+            let ptr = NonNull::new(ptr).ok_or_else(|| {
+                rusqlite::Error::SqliteFailure(ffi::Error::new(ffi::SQLITE_NOMEM), None)
+            })?;
+            std::ptr::copy_nonoverlapping(self.data.as_ptr(), ptr.as_ptr(), self.data.len());
+            OwnedData::from_raw_nonnull(ptr, self.data.len())
+        };
+        conn.deserialize(DatabaseName::Main, data, false)?;
+
+        // The authorizer gives the values without their quotes, so they are
+        // quoted again, which the pragmas taking a number or a keyword
+        // accept too.
+        for (name, value) in &self.pragmas {
+            let value = value.replace('\'', "''");
+            conn.execute_batch(&format!("PRAGMA {name} = '{value}'"))?;
+        }
+
+        Ok(())
+    }
+
+    /// The size of the snapshot, in bytes.
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+}
+
+/// The counters of a [`SnapshotCache`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "hits={} misses={} evictions={} entries={} bytes={}",
+            self.hits, self.misses, self.evictions, self.entries, self.bytes
+        )
+    }
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    snapshots: HashMap<Blake3Hash, Arc<Snapshot>>,
+    /// The keys from the least to the most recently used.
+    recency: VecDeque<Blake3Hash>,
+    bytes: usize,
+}
+
+/// The least-recently-used cache of [`Snapshot`]s, keyed by the hash of the
+/// initial SQL and bounded by the total size of the snapshots.
+#[derive(Debug, Default)]
+pub struct SnapshotCache {
+    max_bytes: usize,
+    entries: Mutex<Entries>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    evictions: AtomicU64,
+}
+
+impl SnapshotCache {
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            max_bytes,
+            ..Default::default()
+        }
+    }
+
+    /// Get the snapshot of the initial SQL and mark it as recently used.
+    pub fn get(&self, key: &Blake3Hash) -> Option<Arc<Snapshot>> {
+        let mut entries = self.entries.lock().unwrap();
+
+        let Some(snapshot) = entries.snapshots.get(key).cloned() else {
+            self.misses.fetch_add(1, Ordering::Relaxed);
+            return None;
+        };
+        entries.recency.retain(|k| k != key);
+        entries.recency.push_back(*key);
+
+        self.hits.fetch_add(1, Ordering::Relaxed);
+        Some(snapshot)
+    }
+
+    /// Store the snapshot of the initial SQL, evicting the least recently
+    /// used ones until it fits.
+    ///
+    /// Snapshots larger than the whole cache are not stored.
+    pub fn insert(&self, key: Blake3Hash, snapshot: Snapshot) {
+        if snapshot.size() > self.max_bytes {
+            return;
+        }
+
+        let mut entries = self.entries.lock().unwrap();
+        if entries.snapshots.contains_key(&key) {
+            return;
+        }
+
+        while entries.bytes + snapshot.size() > self.max_bytes {
+            let Some(evicted) = entries.recency.pop_front() else {
+                break;
+            };
+            if let Some(evicted) = entries.snapshots.remove(&evicted) {
+                entries.bytes -= evicted.size();
+                self.evictions.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+
+        entries.bytes += snapshot.size();
+        entries.recency.push_back(key);
+        entries.snapshots.insert(key, Arc::new(snapshot));
+    }
+
+    pub fn stats(&self) -> Stats {
+        let entries = self.entries.lock().unwrap();
+
+        Stats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            evictions: self.evictions.load(Ordering::Relaxed),
+            entries: entries.snapshots.len(),
+            bytes: entries.bytes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(size: usize) -> Snapshot {
+        Snapshot {
+            data: vec![0; size],
+            pragmas: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_eviction() {
+        let cache = SnapshotCache::new(100);
+        let (a, b, c) = (blake3::hash(b"a"), blake3::hash(b"b"), blake3::hash(b"c"));
+
+        cache.insert(a, snapshot(40));
+        cache.insert(b, snapshot(40));
+        assert!(cache.get(&a).is_some());
+
+        // b is the least recently used one.
+        cache.insert(c, snapshot(40));
+        assert!(cache.get(&b).is_none());
+        assert!(cache.get(&a).is_some());
+        assert!(cache.get(&c).is_some());
+
+        // too large to be cached.
+        cache.insert(b, snapshot(101));
+        assert!(cache.get(&b).is_none());
+
+        assert_eq!(
+            cache.stats(),
+            Stats {
+                hits: 3,
+                misses: 2,
+                evictions: 1,
+                entries: 2,
+                bytes: 80,
+            }
+        );
+        assert_eq!(
+            cache.stats().to_string(),
+            "hits=3 misses=2 evictions=1 entries=2 bytes=80"
+        );
+    }
+
+    #[test]
+    fn test_restore_pragmas() {
+        let conn = rusqlite::Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE test (id INTEGER PRIMARY KEY);")
+            .unwrap();
+        let snapshot = Snapshot::take(
+            &conn,
+            vec![
+                ("encoding".to_string(), "UTF-16le".to_string()),
+                ("cache_size".to_string(), "-1000".to_string()),
+                ("foreign_keys".to_string(), "ON".to_string()),
+            ],
+        )
+        .unwrap()
+        .expect("no temporary objects");
+
+        let mut conn = rusqlite::Connection::open_in_memory().unwrap();
+        snapshot.restore(&mut conn).expect("should restore");
+        let (cache_size, foreign_keys): (i64, bool) = conn
+            .query_row(
+                "SELECT * FROM pragma_cache_size, pragma_foreign_keys",
+                [],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap();
+        assert_eq!((cache_size, foreign_keys), (-1000, true));
+    }
+}
@@ -0,0 +1,275 @@
+//! The statements of a SQL text, with their location in it.
+
+use std::ffi::CString;
+
+use rusqlite::ffi;
+use sql_insight::sqlparser::{
+    dialect::SQLiteDialect,
+    tokenizer::{Location, Token, Tokenizer},
+};
+
+/// A position in a SQL text.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Position {
+    /// The byte offset of the position in the text.
+    pub offset: usize,
+
+    /// The line of the position, starting from 1.
+    pub line: usize,
+
+    /// The column of the position in its line, in characters, starting from
+    /// 1.
+    pub column: usize,
+}
+
+impl Position {
+    /// The position of the byte offset in the SQL.
+    pub fn at(sql: &str, offset: usize) -> Self {
+        let before = &sql[..offset];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        Self {
+            offset,
+            line: before.matches('\n').count() + 1,
+            column: before[line_start..].chars().count() + 1,
+        }
+    }
+
+    /// The position of the tokenizer location in the SQL, if it is in it.
+    pub fn from_location(sql: &str, location: Location) -> Option<Self> {
+        let line_start = sql
+            .split_inclusive('\n')
+            .take(location.line.checked_sub(1)? as usize)
+            .map(str::len)
+            .sum::<usize>();
+
+        sql[line_start..]
+            .char_indices()
+            .nth(location.column.checked_sub(1)? as usize)
+            .map(|(i, _)| Self {
+                offset: line_start + i,
+                line: location.line as usize,
+                column: location.column as usize,
+            })
+    }
+}
+
+/// A statement of a SQL text, and where it is in the text.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StatementLocation {
+    /// The index of the statement in the text, starting from 0.
+    pub index: usize,
+
+    /// The byte offset of the statement in the text.
+    pub offset: usize,
+
+    /// The line of the statement in the text, starting from 1.
+    pub line: usize,
+
+    /// The column of the statement in its line, in characters, starting
+    /// from 1.
+    pub column: usize,
+
+    /// The text of the statement, from its first keyword to its semicolon.
+    pub text: String,
+}
+
+/// Split the SQL into its statements, as SQLite would run them.
+///
+/// The statements end at the semicolons `sqlite3_complete()` accepts, so the
+/// semicolons in literals, comments and trigger bodies do not split them.
+/// The empty statements are skipped, and the text after the last semicolon
+/// is a statement if it is not empty.
+pub fn split(sql: &str) -> Vec<StatementLocation> {
+    let mut statements = Vec::new();
+    let mut start = 0;
+
+    for (end, _) in sql.match_indices(';') {
+        if end >= start && is_complete(&sql[start..=end]) {
+            push(&mut statements, sql, start, end + 1);
+            start = end + 1;
+        }
+    }
+    push(&mut statements, sql, start, sql.len());
+
+    statements
+}
+
+/// Push the statement in `sql[start..end]`, unless it is empty.
+fn push(statements: &mut Vec<StatementLocation>, sql: &str, start: usize, end: usize) {
+    let offset = start + leading_trivia(&sql[start..end]);
+    let text = &sql[offset..end];
+    if text.is_empty() || text == ";" {
+        return;
+    }
+
+    let position = Position::at(sql, offset);
+    statements.push(StatementLocation {
+        index: statements.len(),
+        offset,
+        line: position.line,
+        column: position.column,
+        text: text.to_string(),
+    });
+}
+
+/// The position in the original SQL of the token at `offset` in the
+/// statement at `index` of the SQL run, which was formatted from the
+/// original.
+///
+/// The formatting changes the whitespace and the case of the keywords but
+/// keeps the identifiers and the literals, so the token is found in the
+/// original statement as the same occurrence of the same token. Without
+/// `offset`, or if the token is not found, this is the position of the
+/// statement.
+pub fn locate(original: &str, index: usize, run: &str, offset: Option<usize>) -> Option<Position> {
+    let statement = split(original).into_iter().nth(index)?;
+    let statement_position = Position::at(original, statement.offset);
+
+    let token_offset = offset.and_then(|offset| {
+        let run_tokens = tokens(run);
+        let (i, (_, token)) = run_tokens
+            .iter()
+            .enumerate()
+            .take_while(|(_, (start, _))| *start <= offset)
+            .last()?;
+        let occurrence = run_tokens[..i]
+            .iter()
+            .filter(|(_, t)| same_token(t, token))
+            .count();
+
+        tokens(&statement.text)
+            .into_iter()
+            .filter(|(_, t)| same_token(t, token))
+            .nth(occurrence)
+            .map(|(start, _)| start)
+    });
+
+    Some(match token_offset {
+        Some(start) => Position::at(original, statement.offset + start),
+        None => statement_position,
+    })
+}
+
+/// The tokens of the SQL up to its first semicolon, with their byte offset,
+/// without the whitespace and the comments.
+fn tokens(sql: &str) -> Vec<(usize, Token)> {
+    let Ok(tokens) = Tokenizer::new(&SQLiteDialect {}, sql).tokenize_with_location() else {
+        return Vec::new();
+    };
+
+    tokens
+        .into_iter()
+        .filter(|t| !matches!(t.token, Token::Whitespace(_)))
+        .take_while(|t| t.token != Token::SemiColon)
+        .filter_map(|t| Some((Position::from_location(sql, t.location)?.offset, t.token)))
+        .collect()
+}
+
+/// Whether the tokens are the same but for the case and the quotes of the
+/// words.
+fn same_token(a: &Token, b: &Token) -> bool {
+    match (a, b) {
+        (Token::Word(a), Token::Word(b)) => a.value.eq_ignore_ascii_case(&b.value),
+        _ => a == b,
+    }
+}
+
+/// The length of the whitespace and comments at the start of the SQL.
+fn leading_trivia(sql: &str) -> usize {
+    let mut rest = sql;
+    loop {
+        let trimmed = rest.trim_start();
+        rest = if let Some(comment) = trimmed.strip_prefix("--") {
+            comment.find('\n').map_or("", |i| &comment[i..])
+        } else if let Some(comment) = trimmed.strip_prefix("/*") {
+            comment.find("*/").map_or("", |i| &comment[i + 2..])
+        } else {
+            return sql.len() - trimmed.len();
+        };
+    }
+}
+
+/// Whether the SQL ends with a complete statement.
+fn is_complete(sql: &str) -> bool {
+    // A NUL byte ends the SQL for SQLite, so the statement is cut there and
+    // fails when it runs.
+    let Ok(sql) = CString::new(sql) else {
+        return true;
+    };
+
+    // SAFETY: `sql` is a valid NUL-terminated string.
+    unsafe { ffi::sqlite3_complete(sql.as_ptr()) != 0 }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split() {
+        let sql = "CREATE TABLE t (v TEXT);\n\n  -- the values; with a semicolon\n  INSERT INTO t VALUES ('a;b'), ('€');;\nCREATE TRIGGER r AFTER INSERT ON t BEGIN\n  DELETE FROM t;\nEND; /* done */ SELECT 1";
+
+        let statements = split(sql);
+        let located = statements
+            .iter()
+            .map(|s| (s.index, s.line, s.column, s.text.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            located,
+            vec![
+                (0, 1, 1, "CREATE TABLE t (v TEXT);"),
+                (1, 4, 3, "INSERT INTO t VALUES ('a;b'), ('€');"),
+                (
+                    2,
+                    5,
+                    1,
+                    "CREATE TRIGGER r AFTER INSERT ON t BEGIN\n  DELETE FROM t;\nEND;"
+                ),
+                (3, 7, 17, "SELECT 1"),
+            ]
+        );
+        for statement in &statements {
+            assert!(sql[statement.offset..].starts_with(&statement.text));
+        }
+    }
+
+    #[test]
+    fn test_locate() {
+        let original =
+            "select 1;\n\nselect a,\n  A from  Students -- the students\n  where name = 'x'";
+        let run = " SELECT a, a FROM Students WHERE name = 'x'";
+
+        let at = |needle: &str| run.find(needle);
+        assert_eq!(
+            locate(original, 1, run, at("Students")),
+            Some(Position::at(original, original.find("Students").unwrap()))
+        );
+        assert_eq!(
+            locate(original, 1, run, at("a FROM")),
+            Some(Position {
+                offset: 23,
+                line: 4,
+                column: 3
+            })
+        );
+        assert_eq!(
+            locate(original, 1, run, None),
+            Some(Position {
+                offset: 11,
+                line: 3,
+                column: 1
+            })
+        );
+        assert_eq!(
+            locate(original, 1, run, at("'x'").map(|i| i + 1)),
+            locate(original, 1, run, at("'x'"))
+        );
+        assert_eq!(locate(original, 2, run, None), None);
+    }
+
+    #[test]
+    fn test_split_empty() {
+        assert_eq!(split(""), vec![]);
+        assert_eq!(split(" ; -- nothing\n"), vec![]);
+    }
+}
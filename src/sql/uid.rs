@@ -1,4 +1,4 @@
-use super::{Query, QueryResponse};
+use super::{executor::ResolvedQuery, Fixture, Query, QueryResponse};
 pub use blake3::Hash;
 use std::io::Write;
 
@@ -18,19 +18,102 @@ impl UidGetter for Query {
         let mut hasher = blake3::Hasher::new();
         hasher.update(self.initial_sql.as_bytes());
         hasher.update("\x00".as_bytes());
+        hash_fixtures(&mut hasher, &self.fixtures);
+        hasher.update("\x00".as_bytes());
         hasher.update(self.query.as_bytes());
+        hasher.update("\x00".as_bytes());
+        hasher.update(self.blob_rendering.as_str().as_bytes());
+        hasher.update("\x00".as_bytes());
+        write!(hasher, "{}:{}", self.seed, self.clock_ms).unwrap();
+        hasher.update("\x00".as_bytes());
+        hasher.update(self.function_profile.as_str().as_bytes());
+        hasher.update("\x00".as_bytes());
+        write!(hasher, "{:?}", self.parameters).unwrap();
+        hasher.update("\x00".as_bytes());
+        write!(hasher, "{:?}", self.capture).unwrap();
+        hasher.update("\x00".as_bytes());
+        write!(hasher, "{:?}", self.read_only).unwrap();
+        hasher.update("\x00".as_bytes());
+        hasher.update(self.sqlite_profile.as_bytes());
+        hasher.update("\x00".as_bytes());
+        write!(hasher, "{:?}", self.dialect).unwrap();
         hasher.finalize()
     }
 }
 
+impl UidGetter for ResolvedQuery {
+    /// Get the UID of the formatted query, with the settings of its SQLite
+    /// profile rather than only its name, as they are configured by the
+    /// server.
+    fn get_uid(&self) -> Hash {
+        let mut hasher = blake3::Hasher::new();
+        hasher.update(self.query.get_uid().as_bytes());
+        hasher.update("\x00".as_bytes());
+        write!(hasher, "{:?}", self.profile).unwrap();
+        hasher.finalize()
+    }
+}
+
+/// Hash the fixtures with the length of their data, which may contain any
+/// byte.
+pub(crate) fn hash_fixtures(hasher: &mut blake3::Hasher, fixtures: &[Fixture]) {
+    for fixture in fixtures {
+        hasher.update("\x03".as_bytes());
+        hasher.update(fixture.table.as_bytes());
+        hasher.update("\x00".as_bytes());
+        write!(hasher, "{:?}:{}", fixture.format, fixture.data.len()).unwrap();
+        hasher.update("\x00".as_bytes());
+        hasher.update(&fixture.data);
+    }
+}
+
 impl UidGetter for QueryResponse {
     /// Get the UID of this query (for caching).
     fn get_uid(&self) -> Hash {
+        // The blobs keep their bytes in the cells, so how they are rendered
+        // as text is not part of the output. Neither are the
+        // non-deterministic inputs, which are only reported, as the same
+        // rows may come from a frozen clock or from literals.
         let mut hasher = blake3::Hasher::new();
 
-        write!(hasher, "{:?}", self.header).unwrap();
-        hasher.update("\x00".as_bytes());
-        write!(hasher, "{:?}", self.rows).unwrap();
+        // The captured tables are the output of the queries writing to the
+        // database, whatever their statements return.
+        let result_sets = if self.captured {
+            &[][..]
+        } else {
+            &self.result_sets[..]
+        };
+        for result_set in result_sets {
+            // The debug representation of the cells includes their storage
+            // class, so `1`, `1.0` and `'1'` hash differently. The columns
+            // are left out, as the same rows may come from other tables.
+            write!(hasher, "{:?}", result_set.header).unwrap();
+            hasher.update("\x00".as_bytes());
+            write!(hasher, "{:?}", result_set.rows).unwrap();
+            hasher.update("\x00".as_bytes());
+            // The change counters are left out too, as the same rows may
+            // come from different changes, or from the initial SQL.
+            write!(
+                hasher,
+                "{:?}:{:?}",
+                result_set.truncated, result_set.total_rows
+            )
+            .unwrap();
+            hasher.update("\x01".as_bytes());
+        }
+
+        // The captured tables are hashed like the result sets, after their
+        // name.
+        for (table, contents) in &self.state {
+            hasher.update("\x02".as_bytes());
+            hasher.update(table.as_bytes());
+            hasher.update("\x00".as_bytes());
+            write!(hasher, "{:?}", contents.header).unwrap();
+            hasher.update("\x00".as_bytes());
+            write!(hasher, "{:?}", contents.rows).unwrap();
+            hasher.update("\x00".as_bytes());
+            write!(hasher, "{:?}:{:?}", contents.truncated, contents.total_rows).unwrap();
+        }
 
         hasher.finalize()
     }
@@ -39,6 +122,11 @@ impl UidGetter for QueryResponse {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::sql::{
+        executor::{Config, Executor},
+        BlobRendering, Cell, ColumnMetadata, Dialect, FunctionProfile, Nondeterminism, Parameters,
+        ResultSet, StateCapture, Statistics,
+    };
 
     #[test]
     fn test_hash_query() {
@@ -46,21 +134,25 @@ mod tests {
             initial_sql: "CREATE TABLE test (id INTEGER PRIMARY KEY, name TEXT NOT NULL)"
                 .to_string(),
             query: "SELECT * FROM test".to_string(),
+            ..Default::default()
         };
         let query_a2 = Query {
             initial_sql: "CREATE TABLE test (id INTEGER PRIMARY KEY, name TEXT NOT NULL)"
                 .to_string(),
             query: "SELECT * FROM test".to_string(),
+            ..Default::default()
         };
         let query_b = Query {
             initial_sql: "CREATE TABLE test (id INTEGER PRIMARY KEY, name TEXT NOT NULL)"
                 .to_string(),
             query: "SELECT * FROM test WHERE id = 1".to_string(),
+            ..Default::default()
         };
         let query_b2 = Query {
             initial_sql: "CREATE TABLE test (id INTEGER PRIMARY KEY, name TEXT NOT NULL)"
                 .to_string(),
             query: "select * from test where id = 1".to_string(),
+            ..Default::default()
         }
         .format()
         .expect("should formattable");
@@ -68,6 +160,7 @@ mod tests {
             initial_sql: "CREATE TABLE test (id INTEGER PRIMARY KEY, name TEXT NOT NULL)"
                 .to_string(),
             query: "SELECT * FROM test WHERE id = 2".to_string(),
+            ..Default::default()
         };
 
         assert_eq!(query_a1.get_uid(), query_a2.get_uid());
@@ -75,25 +168,319 @@ mod tests {
         assert_ne!(query_a1.get_uid(), query_c.get_uid());
         assert_ne!(query_b.get_uid(), query_c.get_uid());
         assert_eq!(query_b.get_uid(), query_b2.get_uid());
+
+        let query_b_hex = Query {
+            blob_rendering: BlobRendering::Hex,
+            ..query_b.clone()
+        };
+        assert_ne!(query_b.get_uid(), query_b_hex.get_uid());
+
+        let query_b_seeded = Query {
+            seed: 1,
+            ..query_b.clone()
+        };
+        let query_b_clock = Query {
+            clock_ms: 1,
+            ..query_b.clone()
+        };
+        assert_ne!(query_b.get_uid(), query_b_seeded.get_uid());
+        assert_ne!(query_b.get_uid(), query_b_clock.get_uid());
+        assert_ne!(query_b_seeded.get_uid(), query_b_clock.get_uid());
+
+        let query_b_sqlite = Query {
+            function_profile: FunctionProfile::Sqlite,
+            ..query_b.clone()
+        };
+        assert_ne!(query_b.get_uid(), query_b_sqlite.get_uid());
+
+        let parameterized = |value: Cell| Query {
+            query: "SELECT * FROM test WHERE id = ?".to_string(),
+            parameters: Parameters {
+                positional: vec![value],
+                ..Default::default()
+            },
+            ..query_b.clone()
+        };
+        assert_eq!(
+            parameterized(Cell::Integer(1)).get_uid(),
+            parameterized(Cell::Integer(1)).get_uid()
+        );
+        assert_ne!(
+            parameterized(Cell::Integer(1)).get_uid(),
+            parameterized(Cell::Integer(2)).get_uid()
+        );
+        assert_ne!(
+            parameterized(Cell::Integer(1)).get_uid(),
+            parameterized(Cell::Text("1".to_string())).get_uid()
+        );
+
+        let query_b_captured = Query {
+            capture: StateCapture {
+                written_tables: true,
+                ..Default::default()
+            },
+            ..query_b.clone()
+        };
+        assert_ne!(query_b.get_uid(), query_b_captured.get_uid());
+
+        let query_b_read_only = Query {
+            read_only: true,
+            ..query_b.clone()
+        };
+        assert_ne!(query_b.get_uid(), query_b_read_only.get_uid());
+
+        let query_b_profile = Query {
+            sqlite_profile: "strict".to_string(),
+            ..query_b.clone()
+        };
+        assert_ne!(query_b.get_uid(), query_b_profile.get_uid());
+
+        let query_b_postgresql = Query {
+            dialect: Dialect::Postgresql,
+            ..query_b.clone()
+        };
+        assert_ne!(query_b.get_uid(), query_b_postgresql.get_uid());
+
+        let with_fixture = |table: &str, data: &[u8]| Query {
+            fixtures: vec![Fixture {
+                table: table.to_string(),
+                data: data.to_vec(),
+                ..Default::default()
+            }],
+            ..query_b.clone()
+        };
+        assert_eq!(
+            with_fixture("test", b"id\n1\n").get_uid(),
+            with_fixture("test", b"id\n1\n").get_uid()
+        );
+        assert_ne!(
+            query_b.get_uid(),
+            with_fixture("test", b"id\n1\n").get_uid()
+        );
+        assert_ne!(
+            with_fixture("test", b"id\n1\n").get_uid(),
+            with_fixture("test", b"id\n2\n").get_uid()
+        );
+        assert_ne!(
+            with_fixture("test", b"id\n1\n").get_uid(),
+            with_fixture("other", b"id\n1\n").get_uid()
+        );
+        // The initial SQL is still written in the dialect once formatted.
+        assert_ne!(
+            query_b.get_uid(),
+            query_b_postgresql
+                .format()
+                .expect("should translate")
+                .get_uid()
+        );
+    }
+
+    #[test]
+    fn test_hash_resolved_query() {
+        let query = Query {
+            query: "SELECT 1".to_string(),
+            sqlite_profile: "strict".to_string(),
+            ..Default::default()
+        };
+        let resolve = |profiles: &str| {
+            Executor::new(Config {
+                sqlite_profiles: profiles.parse().expect("valid profiles"),
+                workers: 1,
+                ..Default::default()
+            })
+            .resolve(query.clone())
+            .expect("should resolve")
+            .get_uid()
+        };
+
+        assert_eq!(
+            resolve(r#"{"strict": {"strict_tables": true}}"#),
+            resolve(r#"{"strict": {"strict_tables": true}, "other": {}}"#)
+        );
+        assert_ne!(
+            resolve(r#"{"strict": {"strict_tables": true}}"#),
+            resolve(r#"{"strict": {"strict_tables": false}}"#),
+            "the settings of the profile should be part of the UID"
+        );
     }
 
     #[test]
     fn test_hash_result() {
         let response_a1 = QueryResponse {
-            header: vec!["id".to_string(), "name".to_string()],
-            rows: vec![vec![Some("1".to_string()), Some("Alice".to_string())]],
+            result_sets: vec![ResultSet {
+                header: vec!["id".to_string(), "name".to_string()],
+                rows: vec![vec![Cell::Integer(1), Cell::Text("Alice".to_string())]],
+                truncated: false,
+                total_rows: 1,
+                affected_rows: 0,
+                ..Default::default()
+            }],
+            ..Default::default()
         };
         let response_a2 = QueryResponse {
-            header: vec!["id".to_string(), "name".to_string()],
-            rows: vec![vec![Some("1".to_string()), Some("Alice".to_string())]],
+            result_sets: vec![ResultSet {
+                header: vec!["id".to_string(), "name".to_string()],
+                rows: vec![vec![Cell::Integer(1), Cell::Text("Alice".to_string())]],
+                truncated: false,
+                total_rows: 1,
+                affected_rows: 0,
+                ..Default::default()
+            }],
+            ..Default::default()
         };
         let response_b = QueryResponse {
-            header: vec!["id".to_string(), "name".to_string()],
-            rows: vec![vec![Some("2".to_string()), Some("Bob".to_string())]],
+            result_sets: vec![ResultSet {
+                header: vec!["id".to_string(), "name".to_string()],
+                rows: vec![vec![Cell::Integer(2), Cell::Text("Bob".to_string())]],
+                truncated: false,
+                total_rows: 1,
+                affected_rows: 0,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let response_c = QueryResponse {
+            result_sets: vec![ResultSet {
+                header: vec!["id".to_string(), "name".to_string()],
+                rows: vec![vec![Cell::Integer(1), Cell::Text("Alice".to_string())]],
+                truncated: true,
+                total_rows: 2,
+                affected_rows: 0,
+                ..Default::default()
+            }],
+            ..Default::default()
         };
 
         assert_eq!(response_a1.get_uid(), response_a2.get_uid());
         assert_ne!(response_a1.get_uid(), response_b.get_uid());
         assert_ne!(response_a2.get_uid(), response_b.get_uid());
+        assert_ne!(response_a1.get_uid(), response_c.get_uid());
+
+        let response_d = QueryResponse {
+            result_sets: vec![response_a1.result_sets[0].clone(); 2],
+            ..Default::default()
+        };
+        assert_ne!(response_a1.get_uid(), response_d.get_uid());
+
+        let response_a_slower = QueryResponse {
+            statistics: Statistics {
+                wall_time: std::time::Duration::from_millis(1),
+                vm_steps: 1,
+                ..Default::default()
+            },
+            ..response_a1.clone()
+        };
+        assert_eq!(
+            response_a1.get_uid(),
+            response_a_slower.get_uid(),
+            "the statistics should not be part of the output"
+        );
+
+        let response_a_base64 = QueryResponse {
+            blob_rendering: BlobRendering::Base64,
+            ..response_a1.clone()
+        };
+        assert_eq!(
+            response_a1.get_uid(),
+            response_a_base64.get_uid(),
+            "the blob rendering should not be part of the output"
+        );
+
+        let response_a_clock = QueryResponse {
+            nondeterminism: Nondeterminism {
+                clock: true,
+                ..Default::default()
+            },
+            ..response_a1.clone()
+        };
+        assert_eq!(
+            response_a1.get_uid(),
+            response_a_clock.get_uid(),
+            "the nondeterminism should not be part of the output"
+        );
+
+        let captured = |name: &str| QueryResponse {
+            state: [(
+                "test".to_string(),
+                ResultSet {
+                    header: vec!["name".to_string()],
+                    rows: vec![vec![Cell::Text(name.to_string())]],
+                    total_rows: 1,
+                    ..Default::default()
+                },
+            )]
+            .into(),
+            captured: true,
+            ..Default::default()
+        };
+        assert_eq!(captured("Alice").get_uid(), captured("Alice").get_uid());
+        assert_eq!(
+            captured("Alice").get_uid(),
+            QueryResponse {
+                result_sets: vec![ResultSet::default(); 2],
+                ..captured("Alice")
+            }
+            .get_uid(),
+            "only the captured tables should be part of the output"
+        );
+        assert_ne!(captured("Alice").get_uid(), captured("Bob").get_uid());
+        assert_ne!(
+            captured("Alice").get_uid(),
+            QueryResponse::default().get_uid()
+        );
+
+        let from_table = |table: &str| QueryResponse {
+            result_sets: vec![ResultSet {
+                columns: vec![ColumnMetadata {
+                    decl_type: Some("INTEGER".to_string()),
+                    origin_table: Some(table.to_string()),
+                    origin_column: Some("id".to_string()),
+                    is_expression: false,
+                }],
+                ..response_a1.result_sets[0].clone()
+            }],
+            ..Default::default()
+        };
+        assert_eq!(
+            from_table("students").get_uid(),
+            from_table("teachers").get_uid(),
+            "the origin of the columns should not be part of the output"
+        );
+
+        let with_counters = |affected_rows, changes, last_insert_rowid| QueryResponse {
+            result_sets: vec![ResultSet {
+                affected_rows,
+                changes,
+                total_changes: changes,
+                last_insert_rowid,
+                ..response_a1.result_sets[0].clone()
+            }],
+            ..Default::default()
+        };
+        assert_eq!(
+            with_counters(0, 0, 0).get_uid(),
+            with_counters(2, 2, 5).get_uid(),
+            "the change counters should not be part of the output"
+        );
+
+        let typed_response = |cell: Cell| QueryResponse {
+            result_sets: vec![ResultSet {
+                header: vec!["value".to_string()],
+                rows: vec![vec![cell]],
+                truncated: false,
+                total_rows: 1,
+                affected_rows: 0,
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let integer = typed_response(Cell::Integer(1)).get_uid();
+        let real = typed_response(Cell::Real(1.0)).get_uid();
+        let text = typed_response(Cell::Text("1".to_string())).get_uid();
+        let blob = typed_response(Cell::Blob(b"1".to_vec())).get_uid();
+        assert_ne!(integer, real);
+        assert_ne!(integer, text);
+        assert_ne!(real, text);
+        assert_ne!(text, blob);
     }
 }